@@ -1,13 +1,38 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::{Parser, Subcommand};
+use serde::Deserialize;
 
-use disco_client::RaftClient;
+use disco_client::{
+  compare, txn_op, ClientOptions, Compare, EventType, Expected, NamespaceResponse, Quota,
+  RaftClient, RangeRequest, ReadConsistency, TlsConfig, TxnOp, TxnRequest,
+};
+use disco_daemon::protobuf::{DeleteRequest, GetRequest, SetRequest};
 
 #[derive(Parser, Clone, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Opt {
-  #[clap(long)]
-  // Network address to connect with
-  pub addr: String,
+  /// Network addresses of the cluster nodes to connect with, separated by commas
+  #[clap(long, value_delimiter = ',', required = true)]
+  pub addr: Vec<String>,
+
+  /// Namespace of the keys: system, controller or user/<name>; user/default if omitted
+  #[clap(long, short, default_value = "")]
+  pub namespace: String,
+
+  /// Connect over TLS, checking the nodes against the CA certificates in this PEM file
+  #[clap(long, env = "DISCO_CA")]
+  pub ca: Option<PathBuf>,
+
+  /// PEM file of the client certificate, for nodes that require one
+  #[clap(long, env = "DISCO_CERT", requires_all = ["ca", "key"])]
+  pub cert: Option<PathBuf>,
+
+  /// PEM file of the private key of the client certificate
+  #[clap(long, env = "DISCO_KEY", requires = "cert")]
+  pub key: Option<PathBuf>,
 
   #[clap(subcommand)]
   pub command: Command,
@@ -19,16 +44,317 @@ pub enum Command {
   Get {
     /// Key to look up
     key: String,
+    /// Also print the revisions and version of the key
+    #[clap(long)]
+    metadata: bool,
+    /// Read consistency: linearizable, lease or stale
+    #[clap(long, default_value = "linearizable", value_parser = parse_consistency)]
+    consistency: ReadConsistency,
+    /// Write the value to this file instead of printing it
+    #[clap(long)]
+    output: Option<PathBuf>,
   },
   /// Set a value for a key
   Set {
     /// Key to set
     key: String,
     /// Value to store
+    #[clap(required_unless_present = "file", conflicts_with = "file")]
+    value: Option<String>,
+    /// Store the content of this file as the value
+    #[clap(long)]
+    file: Option<PathBuf>,
+    /// Hint of how to interpret the value, e.g. a MIME type
+    #[clap(long, default_value = "")]
+    content_type: String,
+    /// Attach the key to this lease, so that it is deleted when the lease expires
+    #[clap(long, default_value_t = 0)]
+    lease: u64,
+  },
+  /// Delete a key
+  Delete {
+    /// Key to delete
+    key: String,
+  },
+  /// Write or delete a key only if it is in the expected state
+  Cas {
+    /// Key to write
+    key: String,
+    /// New value; the key is deleted if omitted
+    new_value: Option<String>,
+    /// Only write if the key holds this value
+    #[clap(long)]
+    expected_value: Option<String>,
+    /// Only write if the key was last modified at this revision
+    #[clap(long)]
+    expected_revision: Option<u64>,
+    /// Only write if the key does not exist
+    #[clap(long)]
+    absent: bool,
+  },
+  /// Apply a transaction read as JSON from stdin, e.g.
+  /// {"compare": [{"key": "a", "absent": true}],
+  ///  "success": [{"set": {"key": "a", "value": "1"}}, {"get": {"key": "b"}}],
+  ///  "failure": [{"delete": {"key": "a"}}]}
+  /// Guards and operations may name their own "namespace"; they use --namespace otherwise.
+  Txn,
+  /// List keys in lexical order
+  List {
+    /// Only list the keys with this prefix
+    #[clap(default_value = "")]
+    prefix: String,
+    /// First key to list
+    #[clap(long, default_value = "")]
+    start: String,
+    /// List the keys before this one
+    #[clap(long, default_value = "")]
+    end: String,
+    /// Maximum number of keys to list
+    #[clap(long, default_value_t = 0)]
+    limit: u32,
+    /// Continue after a previous page
+    #[clap(long, default_value = "")]
+    page_token: String,
+    /// Only list the keys, without their values
+    #[clap(long)]
+    keys_only: bool,
+    /// Only print the number of keys
+    #[clap(long)]
+    count_only: bool,
+    /// Read consistency: linearizable, lease or stale
+    #[clap(long, default_value = "linearizable", value_parser = parse_consistency)]
+    consistency: ReadConsistency,
+  },
+  /// Print the changes made to a key, or to every key with a prefix, until interrupted
+  Watch {
+    /// Key to watch
+    key: String,
+    /// Watch every key starting with KEY
+    #[clap(long)]
+    prefix: bool,
+    /// Print the changes made since this revision first
+    #[clap(long)]
+    start_revision: Option<u64>,
+  },
+  /// Manage leases
+  Lease {
+    #[clap(subcommand)]
+    command: LeaseCommand,
+  },
+  /// Show or change the quota of the namespace
+  Quota {
+    #[clap(subcommand)]
+    command: QuotaCommand,
+  },
+  /// Change the members of the cluster
+  Node {
+    #[clap(subcommand)]
+    command: NodeCommand,
+  },
+  /// Move the leadership of the cluster
+  Leader {
+    #[clap(subcommand)]
+    command: LeaderCommand,
+  },
+  /// Show the metrics of the node
+  Metrics,
+  /// Build a snapshot of the node's state machine
+  Snapshot,
+  /// Purge the node's log up to an index that is included in a snapshot
+  Purge {
+    /// Last log index to purge
+    upto: u64,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum LeaseCommand {
+  /// Grant a lease and print its id
+  Grant {
+    /// Seconds after which the lease expires unless it is kept alive
+    ttl: u64,
+  },
+  /// Keep a lease alive until interrupted
+  KeepAlive {
+    /// Id of the lease
+    id: u64,
+  },
+  /// Revoke a lease, deleting the keys attached to it
+  Revoke {
+    /// Id of the lease
+    id: u64,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum QuotaCommand {
+  /// Print the usage and the quota of the namespace
+  Get,
+  /// Replace the quota of the namespace
+  Set {
+    /// Maximum number of keys, 0 for no limit
+    #[clap(long, default_value_t = 0)]
+    max_keys: u64,
+    /// Maximum total size of the keys and values in bytes, 0 for no limit
+    #[clap(long, default_value_t = 0)]
+    max_bytes: u64,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum NodeCommand {
+  /// Remove a node from the cluster, demoting it to learner first if it is a voter
+  Remove {
+    /// Id of the node
+    id: u64,
+  },
+  /// Make the node keep out of leadership and stop its controller, e.g. before patching its host
+  Drain {
+    /// Let the node stand for election again
+    #[clap(long)]
+    undo: bool,
+  },
+}
+
+#[derive(Subcommand, Clone, Debug)]
+pub enum LeaderCommand {
+  /// Hand the leadership to another voter
+  Transfer {
+    /// Id of the voter to hand the leadership to; the most up-to-date one if omitted
+    #[clap(long)]
+    to: Option<u64>,
+  },
+}
+
+/// A transaction, as read by `disco txn`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TxnJson {
+  #[serde(default)]
+  compare: Vec<CompareJson>,
+  #[serde(default)]
+  success: Vec<OpJson>,
+  #[serde(default)]
+  failure: Vec<OpJson>,
+}
+
+/// A guard, with exactly one of `value`, `revision` or `absent`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CompareJson {
+  key: String,
+  #[serde(default)]
+  namespace: String,
+  value: Option<String>,
+  revision: Option<u64>,
+  #[serde(default)]
+  absent: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum OpJson {
+  Set {
+    key: String,
     value: String,
+    #[serde(default)]
+    lease: u64,
+    #[serde(default)]
+    namespace: String,
+  },
+  Delete {
+    key: String,
+    #[serde(default)]
+    namespace: String,
+  },
+  Get {
+    key: String,
+    #[serde(default)]
+    namespace: String,
   },
 }
 
+impl OpJson {
+  fn describe(&self) -> String {
+    match self {
+      OpJson::Set { key, .. } => format!("set {}", key),
+      OpJson::Delete { key, .. } => format!("delete {}", key),
+      OpJson::Get { key, .. } => format!("get {}", key),
+    }
+  }
+
+  fn into_op(self) -> TxnOp {
+    let op = match self {
+      OpJson::Set {
+        key,
+        value,
+        lease,
+        namespace,
+      } => txn_op::Op::Set(SetRequest {
+        key,
+        value: value.into_bytes(),
+        lease,
+        namespace,
+        content_type: String::new(),
+      }),
+      OpJson::Delete { key, namespace } => txn_op::Op::Delete(DeleteRequest { key, namespace }),
+      OpJson::Get { key, namespace } => txn_op::Op::Get(GetRequest {
+        key,
+        consistency: ReadConsistency::Linearizable.into(),
+        namespace,
+      }),
+    };
+    TxnOp { op: Some(op) }
+  }
+}
+
+impl TryFrom<CompareJson> for Compare {
+  type Error = String;
+
+  fn try_from(guard: CompareJson) -> Result<Self, String> {
+    let expected = match (guard.value, guard.revision, guard.absent) {
+      (Some(value), None, false) => compare::Expected::Value(value.into_bytes()),
+      (None, Some(revision), false) => compare::Expected::Revision(revision),
+      (None, None, true) => compare::Expected::Absent(()),
+      _ => {
+        return Err(format!(
+          "exactly one of value, revision or absent is required for the guard on {}",
+          guard.key
+        ))
+      }
+    };
+    Ok(Compare {
+      key: guard.key,
+      expected: Some(expected),
+      namespace: guard.namespace,
+    })
+  }
+}
+
+/// Formats a value for printing, as a string if it is UTF-8.
+fn display_value(value: &[u8]) -> String {
+  match std::str::from_utf8(value) {
+    Ok(value) => format!("{:?}", value),
+    Err(_) => format!("<{} bytes>", value.len()),
+  }
+}
+
+fn print_namespace(namespace: &NamespaceResponse) {
+  let quota = namespace.quota.unwrap_or_default();
+  let limit = |x: u64| match x {
+    0 => "unlimited".to_string(),
+    x => x.to_string(),
+  };
+  println!("Namespace: {}", namespace.namespace);
+  println!("Keys: {} / {}", namespace.keys, limit(quota.max_keys));
+  println!("Bytes: {} / {}", namespace.bytes, limit(quota.max_bytes));
+}
+
+fn parse_consistency(s: &str) -> Result<ReadConsistency, String> {
+  ReadConsistency::from_str_name(&s.to_uppercase())
+    .ok_or_else(|| format!("unknown read consistency: {}", s))
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
   // Initialize tracing first, before any logging happens
@@ -40,16 +366,237 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   let options = Opt::parse();
 
-  let client = RaftClient::new(options.addr).await?;
+  let tls = match options.ca {
+    Some(ca) => Some(TlsConfig::client(ca, options.cert.zip(options.key))?),
+    None => None,
+  };
+  let client_options = ClientOptions {
+    namespace: options.namespace,
+    tls,
+    ..Default::default()
+  };
+  let client = RaftClient::connect(options.addr, client_options).await?;
 
   match options.command {
-    Command::Get { key } => {
-      let result = client.get_value(key).await?;
-      println!("Value: {:?}", result);
+    Command::Get {
+      key,
+      metadata,
+      consistency,
+      output,
+    } => {
+      let result = client.get(key, consistency).await?;
+      match (&result.value, output) {
+        (Some(value), Some(output)) => {
+          std::fs::write(&output, value)?;
+          println!("Value written to {}", output.display());
+        }
+        (value, _) => println!(
+          "Value: {}",
+          value.as_deref().map_or("none".to_string(), display_value)
+        ),
+      }
+      if metadata {
+        println!("Content type: {:?}", result.content_type);
+        println!("Create revision: {:?}", result.create_revision);
+        println!("Mod revision: {:?}", result.revision);
+        println!("Version: {:?}", result.version);
+        println!("Store revision: {}", result.store_revision);
+      }
+    }
+    Command::Set {
+      key,
+      value,
+      file,
+      content_type,
+      lease,
+    } => {
+      let value = match file {
+        Some(file) => std::fs::read(file)?,
+        None => value.unwrap_or_default().into_bytes(),
+      };
+      let result = client.put(key, value, content_type, lease).await?;
+      println!(
+        "Set result: {}",
+        result
+          .value
+          .as_deref()
+          .map_or("none".to_string(), display_value)
+      );
+    }
+    Command::Delete { key } => {
+      let result = client.delete_value(key).await?;
+      println!("Deleted value: {:?}", result);
+    }
+    Command::Cas {
+      key,
+      new_value,
+      expected_value,
+      expected_revision,
+      absent,
+    } => {
+      let expected = match (expected_value, expected_revision, absent) {
+        (Some(value), None, false) => Expected::Value(value.into_bytes()),
+        (None, Some(revision), false) => Expected::Revision(revision),
+        (None, None, true) => Expected::Absent(()),
+        _ => {
+          return Err(
+            "exactly one of --expected-value, --expected-revision or --absent is required".into(),
+          )
+        }
+      };
+      let (succeeded, value) = client.compare_and_swap(key, expected, new_value).await?;
+      println!("Succeeded: {}, current value: {:?}", succeeded, value);
+    }
+    Command::Txn => {
+      let mut input = String::new();
+      std::io::stdin().read_to_string(&mut input)?;
+      let txn: TxnJson = serde_json::from_str(&input)?;
+
+      let compare = txn
+        .compare
+        .into_iter()
+        .map(Compare::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+      let success = txn.success.iter().map(OpJson::describe).collect::<Vec<_>>();
+      let failure = txn.failure.iter().map(OpJson::describe).collect::<Vec<_>>();
+      let request = TxnRequest {
+        compare,
+        success: txn.success.into_iter().map(OpJson::into_op).collect(),
+        failure: txn.failure.into_iter().map(OpJson::into_op).collect(),
+      };
+
+      let result = client.txn(request).await?;
+      let succeeded = result.succeeded.unwrap_or(false);
+      println!("Succeeded: {}", succeeded);
+      let applied = if succeeded { success } else { failure };
+      for (op, response) in applied.iter().zip(result.responses) {
+        println!(
+          "{}: {}",
+          op,
+          response
+            .value
+            .as_deref()
+            .map_or("none".to_string(), display_value)
+        );
+      }
+    }
+    Command::List {
+      prefix,
+      start,
+      end,
+      limit,
+      page_token,
+      keys_only,
+      count_only,
+      consistency,
+    } => {
+      let request = RangeRequest {
+        prefix,
+        start,
+        end,
+        limit,
+        page_token,
+        keys_only,
+        count_only,
+        consistency: consistency.into(),
+        namespace: String::new(),
+      };
+      let result = client.range(request).await?;
+      for pair in result.kvs {
+        match pair.kv {
+          Some(kv) => println!("{} = {}", pair.key, display_value(&kv.value)),
+          None => println!("{}", pair.key),
+        }
+      }
+      println!("Count: {}", result.count);
+      if !result.next_page_token.is_empty() {
+        println!("Next page token: {}", result.next_page_token);
+      }
+    }
+    Command::Watch {
+      key,
+      prefix,
+      start_revision,
+    } => {
+      let mut watch = client.watch(key, prefix, start_revision).await?;
+      while let Some(changes) = watch.next().await? {
+        for event in changes.events {
+          match event.r#type() {
+            EventType::Put => {
+              let value = event.kv.map(|x| x.value).unwrap_or_default();
+              let value = display_value(&value);
+              println!("{} PUT {} = {}", changes.revision, event.key, value)
+            }
+            EventType::Delete => println!("{} DELETE {}", changes.revision, event.key),
+          }
+        }
+      }
+    }
+    Command::Lease { command } => match command {
+      LeaseCommand::Grant { ttl } => {
+        let id = client.grant_lease(ttl).await?;
+        println!("Lease: {}", id);
+      }
+      LeaseCommand::KeepAlive { id } => {
+        let mut keep_alive = client.keep_alive().await?;
+        // Renew three times per ttl, so that a late renewal does not let the lease expire.
+        while let Some(ttl) = keep_alive.renew(id).await? {
+          println!("Renewed lease {} for {}s", id, ttl);
+          tokio::time::sleep(Duration::from_millis(ttl * 1000 / 3)).await;
+        }
+        println!("Lease {} not found", id);
+      }
+      LeaseCommand::Revoke { id } => {
+        client.revoke_lease(id).await?;
+        println!("Revoked lease {}", id);
+      }
+    },
+    Command::Quota { command } => match command {
+      QuotaCommand::Get => print_namespace(&client.namespace_usage().await?),
+      QuotaCommand::Set {
+        max_keys,
+        max_bytes,
+      } => {
+        let quota = Quota {
+          max_keys,
+          max_bytes,
+        };
+        print_namespace(&client.set_quota(quota).await?);
+      }
+    },
+    Command::Node { command } => match command {
+      NodeCommand::Remove { id } => {
+        client.remove_node(id).await?;
+        println!("Node {} removed", id);
+      }
+      NodeCommand::Drain { undo } => {
+        let drained = client.drain(!undo).await?;
+        println!("Drained: {}", drained.drained);
+        match drained.current_leader {
+          Some(leader) => println!("Leader: {}", leader),
+          None => println!("Leader: unknown"),
+        }
+      }
+    },
+    Command::Leader { command } => match command {
+      LeaderCommand::Transfer { to } => {
+        let leader = client.transfer_leader(to).await?;
+        println!("Node {} is the leader", leader);
+      }
+    },
+    Command::Metrics => {
+      let metrics = client.metrics().await?;
+      println!("Last snapshot: {:?}", metrics.snapshot);
+      println!("Last purged: {:?}", metrics.purged);
+      println!("{}", metrics.other_metrics);
+    }
+    Command::Snapshot => {
+      client.trigger_snapshot().await?;
+      println!("Snapshot triggered");
     }
-    Command::Set { key, value } => {
-      let result = client.set_value(key, value).await?;
-      println!("Set result: {:?}", result);
+    Command::Purge { upto } => {
+      client.purge_log(upto).await?;
+      println!("Log purge up to {} triggered", upto);
     }
   }
 
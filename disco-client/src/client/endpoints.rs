@@ -0,0 +1,266 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use disco_daemon::protobuf::MetricsResponse;
+use disco_daemon::tls::{self, TlsConfig};
+use tonic::transport::{Channel, Endpoint};
+
+/// Tracks the nodes of the cluster, which one is the leader, and a channel to each of them.
+///
+/// Channels connect lazily, so an unreachable node only costs a failed request.
+pub(super) struct Endpoints {
+  state: Mutex<State>,
+  timeout: Duration,
+  tls: Option<Arc<TlsConfig>>,
+}
+
+struct State {
+  /// The addresses the client was created with, kept to find the cluster again if every known
+  /// member becomes unreachable.
+  seeds: Vec<String>,
+
+  /// The address of each member, from the last membership that was discovered.
+  members: BTreeMap<u64, String>,
+
+  /// The address of the leader, if known.
+  leader: Option<String>,
+
+  channels: HashMap<String, Channel>,
+
+  /// Which address to try next when the leader is unknown.
+  next: usize,
+}
+
+impl State {
+  /// Every known address: the members first, then the seeds that are not members.
+  fn addrs(&self) -> Vec<String> {
+    let mut addrs = self.members.values().cloned().collect::<Vec<_>>();
+    for seed in &self.seeds {
+      if !addrs.contains(seed) {
+        addrs.push(seed.clone());
+      }
+    }
+    addrs
+  }
+}
+
+/// Adds the scheme to addresses taken from the cluster membership, which only hold host and port.
+pub(super) fn normalize(addr: &str) -> String {
+  if addr.contains("://") {
+    addr.to_string()
+  } else {
+    format!("http://{}", addr)
+  }
+}
+
+impl Endpoints {
+  pub fn new(
+    seeds: Vec<String>,
+    timeout: Duration,
+    tls: Option<Arc<TlsConfig>>,
+  ) -> Result<Self, Box<dyn std::error::Error>> {
+    if seeds.is_empty() {
+      return Err("at least one address is required".into());
+    }
+
+    let mut channels = HashMap::new();
+    let seeds = seeds.iter().map(|x| normalize(x)).collect::<Vec<_>>();
+    for seed in &seeds {
+      channels.insert(seed.clone(), Self::connect(seed, timeout, tls.as_ref())?);
+    }
+
+    Ok(Self {
+      state: Mutex::new(State {
+        seeds,
+        members: BTreeMap::new(),
+        leader: None,
+        channels,
+        next: 0,
+      }),
+      timeout,
+      tls,
+    })
+  }
+
+  /// Any node may answer at an address, so the certificate of the node is not checked against a
+  /// node id, only against the CA.
+  fn connect(
+    addr: &str,
+    timeout: Duration,
+    tls: Option<&Arc<TlsConfig>>,
+  ) -> Result<Channel, tonic::transport::Error> {
+    let endpoint = Endpoint::from_shared(addr.to_string())?.timeout(timeout);
+    Ok(tls::connect_lazy(endpoint, tls, None))
+  }
+
+  fn channel(&self, state: &mut State, addr: &str) -> Option<Channel> {
+    if let Some(channel) = state.channels.get(addr) {
+      return Some(channel.clone());
+    }
+    match Self::connect(addr, self.timeout, self.tls.as_ref()) {
+      Ok(channel) => {
+        state.channels.insert(addr.to_string(), channel.clone());
+        Some(channel)
+      }
+      Err(e) => {
+        tracing::warn!("ignoring invalid address {}: {}", addr, e);
+        None
+      }
+    }
+  }
+
+  /// Returns the leader, or the next node to try if the leader is unknown.
+  pub fn leader_or_next(&self) -> (String, Channel) {
+    {
+      let mut state = self.state.lock().unwrap();
+      if let Some(leader) = state.leader.clone() {
+        if let Some(channel) = self.channel(&mut state, &leader) {
+          return (leader, channel);
+        }
+      }
+    }
+    self.next()
+  }
+
+  /// Returns the next node, going round the known nodes.
+  pub fn next(&self) -> (String, Channel) {
+    let mut state = self.state.lock().unwrap();
+    let addrs = state.addrs();
+    for _ in 0..addrs.len() {
+      let addr = addrs[state.next % addrs.len()].clone();
+      state.next = state.next.wrapping_add(1);
+      if let Some(channel) = self.channel(&mut state, &addr) {
+        return (addr, channel);
+      }
+    }
+
+    // Seeds are validated when the client is created, so there is always one.
+    let seed = state.seeds[0].clone();
+    let channel = state.channels[&seed].clone();
+    (seed, channel)
+  }
+
+  /// Returns the first seed, for requests about a specific node rather than the cluster.
+  pub fn primary(&self) -> Channel {
+    let state = self.state.lock().unwrap();
+    state.channels[&state.seeds[0]].clone()
+  }
+
+  /// Every known address, to look for the cluster membership.
+  pub fn addrs(&self) -> Vec<String> {
+    self.state.lock().unwrap().addrs()
+  }
+
+  pub fn channel_to(&self, addr: &str) -> Option<Channel> {
+    let mut state = self.state.lock().unwrap();
+    self.channel(&mut state, addr)
+  }
+
+  pub fn set_leader(&self, addr: Option<String>) {
+    self.state.lock().unwrap().leader = addr.map(|x| normalize(&x));
+  }
+
+  /// Records that a request to `addr` failed, so that the next one goes elsewhere.
+  pub fn failed(&self, addr: &str) {
+    let mut state = self.state.lock().unwrap();
+    if state.leader.as_deref() == Some(addr) {
+      state.leader = None;
+    }
+  }
+
+  /// Updates the members and the leader from the metrics of a node.
+  ///
+  /// Channels to the nodes that left the cluster are dropped, unless they are seeds.
+  pub fn update(&self, metrics: &MetricsResponse) {
+    let Some(membership) = &metrics.membership else {
+      return;
+    };
+
+    let members = membership
+      .nodes
+      .iter()
+      .map(|(id, node)| (*id, normalize(&node.rpc_addr)))
+      .collect::<BTreeMap<_, _>>();
+
+    let mut state = self.state.lock().unwrap();
+    if state.members != members {
+      tracing::debug!("cluster membership changed: {:?}", members);
+      let State {
+        seeds, channels, ..
+      } = &mut *state;
+      channels.retain(|addr, _| members.values().any(|x| x == addr) || seeds.contains(addr));
+      state.members = members;
+    }
+    let leader = metrics
+      .current_leader
+      .and_then(|id| state.members.get(&id).cloned());
+    state.leader = leader;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use disco_daemon::protobuf::{Membership, Node};
+
+  fn metrics(nodes: &[(u64, &str)], leader: Option<u64>) -> MetricsResponse {
+    let nodes = nodes
+      .iter()
+      .map(|(id, addr)| {
+        (
+          *id,
+          Node {
+            node_id: *id,
+            rpc_addr: addr.to_string(),
+          },
+        )
+      })
+      .collect();
+    MetricsResponse {
+      membership: Some(Membership {
+        configs: vec![],
+        nodes,
+      }),
+      current_leader: leader,
+      ..Default::default()
+    }
+  }
+
+  #[tokio::test]
+  async fn test_tracks_membership_and_leader() {
+    let endpoints = Endpoints::new(
+      vec!["127.0.0.1:5051".to_string()],
+      Duration::from_secs(1),
+      None,
+    )
+    .unwrap();
+    assert_eq!(endpoints.leader_or_next().0, "http://127.0.0.1:5051");
+
+    endpoints.update(&metrics(
+      &[(1, "127.0.0.1:5051"), (2, "127.0.0.1:5052")],
+      Some(2),
+    ));
+    assert_eq!(endpoints.leader_or_next().0, "http://127.0.0.1:5052");
+    assert_eq!(
+      endpoints.addrs(),
+      vec!["http://127.0.0.1:5051", "http://127.0.0.1:5052"]
+    );
+
+    // The leader failed: go round the other nodes.
+    endpoints.failed("http://127.0.0.1:5052");
+    let (first, _) = endpoints.leader_or_next();
+    let (second, _) = endpoints.leader_or_next();
+    assert_ne!(first, second);
+
+    // Node 2 left the cluster; the seed is kept.
+    endpoints.update(&metrics(&[(3, "127.0.0.1:5053")], Some(3)));
+    assert_eq!(
+      endpoints.addrs(),
+      vec!["http://127.0.0.1:5053", "http://127.0.0.1:5051"]
+    );
+    assert_eq!(endpoints.leader_or_next().0, "http://127.0.0.1:5053");
+  }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use disco_daemon::protobuf::app_service_client::AppServiceClient;
+use disco_daemon::protobuf::{KeepAliveRequest, LeaseResponse};
+use futures::channel::mpsc;
+use tonic::{transport::Channel, Code, Request, Status, Streaming};
+
+use super::{is_not_leader, leader_addr, Backoff, ClientOptions, Endpoints};
+
+/// A stream keeping leases alive, created by [`super::RaftClient::keep_alive`].
+///
+/// Lease expiry is tracked by the leader, so the stream is opened on the leader. If the leader
+/// changes or becomes unreachable, the stream is opened again on the new one.
+pub struct KeepAlive {
+  endpoints: Arc<Endpoints>,
+  options: ClientOptions,
+  addr: String,
+  sender: mpsc::UnboundedSender<KeepAliveRequest>,
+  stream: Streaming<LeaseResponse>,
+}
+
+impl KeepAlive {
+  pub(super) async fn start(
+    endpoints: Arc<Endpoints>,
+    options: ClientOptions,
+  ) -> Result<Self, Status> {
+    let (addr, channel) = endpoints.leader_or_next();
+    let (sender, stream) = match Self::open(channel).await {
+      Ok(opened) => opened,
+      Err(status) => {
+        endpoints.failed(&addr);
+        return Err(status);
+      }
+    };
+    Ok(Self {
+      endpoints,
+      options,
+      addr,
+      sender,
+      stream,
+    })
+  }
+
+  /// Opens the stream on the node at the other end of `channel`.
+  async fn open(
+    channel: Channel,
+  ) -> Result<
+    (
+      mpsc::UnboundedSender<KeepAliveRequest>,
+      Streaming<LeaseResponse>,
+    ),
+    Status,
+  > {
+    let (sender, receiver) = mpsc::unbounded();
+    let mut client = AppServiceClient::new(channel);
+    let response = client.keep_alive(Request::new(receiver)).await?;
+    Ok((sender, response.into_inner()))
+  }
+
+  /// Renews lease `id` for its whole ttl.
+  ///
+  /// Returns the ttl of the lease, in seconds, or `None` if the lease does not exist anymore.
+  pub async fn renew(&mut self, id: u64) -> Result<Option<u64>, Status> {
+    let mut backoff = Backoff::new(&self.options);
+    let mut attempt = 0;
+    let mut reopen = false;
+
+    loop {
+      let status = match self.send(id, reopen).await {
+        Ok(ttl) => return Ok(ttl),
+        Err(status) => status,
+      };
+
+      // Renewing a lease twice does no harm, so any failure to reach the leader is retried.
+      let retryable = is_not_leader(&status) || status.code() == Code::Unavailable;
+      if !retryable || attempt >= self.options.max_retries {
+        return Err(status);
+      }
+      attempt += 1;
+
+      tracing::debug!("reopening keep-alive stream: {}", status.message());
+      match leader_addr(&status) {
+        // Redirected to the leader: no need to wait.
+        Some(leader) => self.endpoints.set_leader(Some(leader)),
+        None => {
+          self.endpoints.failed(&self.addr);
+          backoff.wait().await;
+        }
+      }
+      reopen = true;
+    }
+  }
+
+  /// Sends a renewal of lease `id` and waits for the response, after opening the stream again on
+  /// the leader, or the next node that can be reached, if `reopen` is set.
+  async fn send(&mut self, id: u64, reopen: bool) -> Result<Option<u64>, Status> {
+    if reopen {
+      let (addr, channel) = self.endpoints.leader_or_next();
+      self.addr = addr;
+      (self.sender, self.stream) = Self::open(channel).await?;
+    }
+
+    let closed = || Status::unavailable("keep-alive stream closed");
+    self
+      .sender
+      .unbounded_send(KeepAliveRequest { id })
+      .map_err(|_| closed())?;
+    match self.stream.message().await? {
+      Some(response) => Ok(Some(response.ttl).filter(|x| *x != 0)),
+      None => Err(closed()),
+    }
+  }
+}
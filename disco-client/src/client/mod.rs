@@ -1,36 +1,285 @@
-use std::time::Duration;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use disco_daemon::grpc::status::{is_not_leader, LEADER_ADDR_METADATA};
 use disco_daemon::protobuf::app_service_client::AppServiceClient;
-use disco_daemon::protobuf::{GetRequest, SetRequest};
-use tonic::{transport::Channel, Request, Status};
+use disco_daemon::protobuf::{
+  CampaignRequest, CompareAndSwapRequest, DeleteRequest, DrainRequest, GetRequest,
+  GrantLeaseRequest, LeaderResponse, LockRequest, LockResponse, MetricsResponse, NamespaceRequest,
+  ObserveRequest, PurgeLogRequest, RangeResponse, RemoveNodeRequest, ResignRequest, Response,
+  RevokeLeaseRequest, SetQuotaRequest, SetRequest, TransferLeaderRequest, UnlockRequest,
+  WatchRequest,
+};
 
+pub use disco_daemon::protobuf::{compare, txn_op, Compare, TxnOp, TxnRequest};
+
+pub use disco_daemon::protobuf::compare_and_swap_request::Expected;
+pub use disco_daemon::protobuf::event::EventType;
+pub use disco_daemon::protobuf::DrainResponse;
+pub use disco_daemon::protobuf::NamespaceResponse;
+pub use disco_daemon::protobuf::Quota;
+pub use disco_daemon::protobuf::RangeRequest;
+pub use disco_daemon::protobuf::ReadConsistency;
+pub use disco_daemon::tls::TlsConfig;
+use tokio::time::Instant;
+use tonic::{transport::Channel, Code, Request, Status, Streaming};
+
+mod endpoints;
+mod keep_alive;
+mod watch;
+use endpoints::Endpoints;
+pub use keep_alive::KeepAlive;
+pub use watch::Watch;
+
+/// Settings of a [`RaftClient`].
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+  /// Timeout of each request.
+  pub timeout: Duration,
+
+  /// How many times a failed request is retried.
+  pub max_retries: u32,
+
+  /// How long to wait before the first retry. The delay doubles with each retry.
+  pub initial_backoff: Duration,
+
+  /// The longest delay between two retries.
+  pub max_backoff: Duration,
+
+  /// How long [`RaftClient::lock`] and [`RaftClient::campaign`] wait in all before they fail.
+  pub wait_timeout: Duration,
+
+  /// The namespace of the keys read and written; the default user namespace if empty.
+  pub namespace: String,
+
+  /// Connects over TLS if set. The certificates are read once; call [`TlsConfig::watch`] to pick
+  /// up rotated ones in a long-lived client.
+  pub tls: Option<Arc<TlsConfig>>,
+}
+
+impl Default for ClientOptions {
+  fn default() -> Self {
+    Self {
+      timeout: Duration::from_secs(5),
+      max_retries: 5,
+      initial_backoff: Duration::from_millis(50),
+      max_backoff: Duration::from_secs(2),
+      wait_timeout: Duration::from_secs(60),
+      namespace: String::new(),
+      tls: None,
+    }
+  }
+}
+
+/// Which failures a request can be retried after.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Retry {
+  /// The request can safely be applied more than once: retry whenever the cluster is unavailable.
+  Idempotent,
+
+  /// Only retry when the request was refused because it was not sent to the leader, which
+  /// guarantees that it was not applied.
+  NotLeader,
+}
+
+/// Returns the leader address carried by a "not leader" status, if the node knew it.
+fn leader_addr(status: &Status) -> Option<String> {
+  status
+    .metadata()
+    .get(LEADER_ADDR_METADATA)
+    .and_then(|x| x.to_str().ok())
+    .map(|x| x.to_string())
+}
+
+/// Converts a value read as bytes for the string convenience methods.
+fn lossy_string(value: Vec<u8>) -> String {
+  String::from_utf8(value).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned())
+}
+
+/// Exponential backoff between the retries of a request.
+struct Backoff {
+  delay: Duration,
+  max: Duration,
+}
+
+impl Backoff {
+  fn new(options: &ClientOptions) -> Self {
+    Self {
+      delay: options.initial_backoff,
+      max: options.max_backoff,
+    }
+  }
+
+  /// Waits before the next retry, then doubles the delay up to the longest one.
+  async fn wait(&mut self) {
+    tokio::time::sleep(self.delay).await;
+    self.delay = (self.delay * 2).min(self.max);
+  }
+}
+
+/// Returns whether `status` tells that a request that waits, like acquiring a lock, ran out of
+/// time before it was done.
+fn is_timeout(status: &Status) -> bool {
+  matches!(status.code(), Code::Cancelled | Code::DeadlineExceeded)
+}
+
+/// Returns a new owner for an acquisition of a lock or a leadership, sent with each of its
+/// retries. It only has to differ from the owners of other acquisitions sharing the same lease,
+/// including those of other clients.
+fn new_owner() -> String {
+  static NEXT: AtomicU64 = AtomicU64::new(0);
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_nanos();
+  format!(
+    "{}-{}-{}",
+    std::process::id(),
+    now,
+    NEXT.fetch_add(1, Ordering::Relaxed)
+  )
+}
+
+/// A client of a disco cluster.
+///
+/// The client is given one or more seed addresses. It discovers the other members of the cluster
+/// from their metrics, sends requests to the leader, and follows the leader when it changes.
+/// Requests that fail because a node is unreachable or is not the leader are retried on another
+/// node, with exponential backoff.
 pub struct RaftClient {
-  channel: Channel,
+  endpoints: Arc<Endpoints>,
+  options: ClientOptions,
 }
 
 impl RaftClient {
+  /// Connects to the cluster through the node at `addr`.
   pub async fn new(addr: String) -> Result<Self, Box<dyn std::error::Error>> {
-    let channel = Channel::from_shared(addr.clone())?
-      .timeout(Duration::from_secs(5))
-      .connect()
-      .await?;
+    Self::connect(vec![addr], ClientOptions::default()).await
+  }
 
-    Ok(Self { channel })
+  /// Connects to the cluster through any of the nodes at `seeds`.
+  ///
+  /// Fails if none of the seeds can be reached.
+  pub async fn connect(
+    seeds: Vec<String>,
+    options: ClientOptions,
+  ) -> Result<Self, Box<dyn std::error::Error>> {
+    let client = Self {
+      endpoints: Arc::new(Endpoints::new(seeds, options.timeout, options.tls.clone())?),
+      options,
+    };
+    client.discover().await?;
+    Ok(client)
   }
 
-  pub async fn get_value(&self, key: String) -> Result<Option<String>, Status> {
-    // Create a client using the channel
-    let mut client = AppServiceClient::new(self.channel.clone());
+  /// Returns a client of the same cluster that reads and writes the keys of `namespace`.
+  pub fn with_namespace(&self, namespace: String) -> Self {
+    Self {
+      endpoints: self.endpoints.clone(),
+      options: ClientOptions {
+        namespace,
+        ..self.options.clone()
+      },
+    }
+  }
+
+  /// Returns the namespace of the keys this client reads and writes.
+  pub fn namespace(&self) -> &str {
+    &self.options.namespace
+  }
+
+  /// Refreshes the cluster membership and the leader from the first node that answers.
+  pub async fn discover(&self) -> Result<(), Status> {
+    let mut last_error = Status::unavailable("no node to connect to");
+    for addr in self.endpoints.addrs() {
+      let Some(channel) = self.endpoints.channel_to(&addr) else {
+        continue;
+      };
+      let mut client = AppServiceClient::new(channel);
+      match client.metrics(Request::new(())).await {
+        Ok(response) => {
+          self.endpoints.update(&response.into_inner());
+          return Ok(());
+        }
+        Err(status) => {
+          tracing::debug!("failed to get metrics from {}: {}", addr, status);
+          last_error = status;
+        }
+      }
+    }
+    Err(last_error)
+  }
+
+  /// Sends a request with `call` to the leader, retrying according to `retry`.
+  async fn call<T, F, Fut>(&self, retry: Retry, mut call: F) -> Result<T, Status>
+  where
+    F: FnMut(AppServiceClient<Channel>) -> Fut,
+    Fut: Future<Output = Result<tonic::Response<T>, Status>>,
+  {
+    let mut backoff = Backoff::new(&self.options);
+    let mut attempt = 0;
+
+    loop {
+      let (addr, channel) = self.endpoints.leader_or_next();
+      let status = match call(AppServiceClient::new(channel)).await {
+        Ok(response) => return Ok(response.into_inner()),
+        Err(status) => status,
+      };
 
-    // Create the GetRequest message
-    let request = Request::new(GetRequest { key });
+      let retryable = is_not_leader(&status)
+        || (retry == Retry::Idempotent && status.code() == Code::Unavailable);
+      if !retryable || attempt >= self.options.max_retries {
+        return Err(status);
+      }
+      attempt += 1;
 
-    // Make the RPC call
-    let response = client.get(request).await?;
-    let result = response.into_inner();
+      if let Some(leader) = leader_addr(&status) {
+        // Redirected to the leader: no need to wait.
+        tracing::debug!("{} is not the leader, retrying on {}", addr, leader);
+        self.endpoints.set_leader(Some(leader));
+        continue;
+      }
 
-    // Return the response inner data
-    Ok(result.value)
+      tracing::debug!("request to {} failed, retrying: {}", addr, status);
+      self.endpoints.failed(&addr);
+      if let Err(e) = self.discover().await {
+        tracing::debug!("failed to refresh the cluster membership: {}", e);
+      }
+
+      backoff.wait().await;
+    }
+  }
+
+  /// Reads `key` as a string. Values that are not UTF-8 are converted lossily; use [`get`] to
+  /// read them as bytes.
+  ///
+  /// [`get`]: RaftClient::get
+  pub async fn get_value(&self, key: String) -> Result<Option<String>, Status> {
+    let result = self.get(key, ReadConsistency::Linearizable).await?;
+    Ok(result.value.map(lossy_string))
+  }
+
+  /// Reads `key` along with its content type and revision metadata: the revision that created
+  /// it, the revision that last modified it, and its version. None of them are set if the key
+  /// does not exist.
+  ///
+  /// Unless `consistency` is `Stale`, the read must be sent to the leader. Other nodes answer
+  /// with `FailedPrecondition`, carrying the leader address in the status metadata.
+  pub async fn get(&self, key: String, consistency: ReadConsistency) -> Result<Response, Status> {
+    let request = GetRequest {
+      key,
+      consistency: consistency.into(),
+      namespace: self.options.namespace.clone(),
+    };
+
+    self
+      .call(Retry::Idempotent, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.get(request).await }
+      })
+      .await
   }
 
   pub async fn set_value(
@@ -38,17 +287,419 @@ impl RaftClient {
     key: String,
     value: String,
   ) -> Result<Option<String>, tonic::Status> {
-    // Create a client using the channel
-    let mut client = AppServiceClient::new(self.channel.clone());
+    self.set_with_lease(key, value, 0).await
+  }
 
-    // Create the SetRequest message
-    let request = Request::new(SetRequest { key, value });
+  /// Writes `key` and attaches it to `lease`, so that it is deleted when the lease expires or is
+  /// revoked. A `lease` of 0 writes the key without a lease.
+  ///
+  /// Fails with `NotFound` if the lease does not exist, or `ResourceExhausted` if the namespace
+  /// is full.
+  pub async fn set_with_lease(
+    &self,
+    key: String,
+    value: String,
+    lease: u64,
+  ) -> Result<Option<String>, tonic::Status> {
+    let result = self
+      .put(key, value.into_bytes(), String::new(), lease)
+      .await?;
+    Ok(result.value.map(lossy_string))
+  }
+
+  /// Writes `key` with a binary `value`, and `content_type` as a hint of how to interpret it,
+  /// attached to `lease` unless it is 0.
+  ///
+  /// Returns the key after the write. Fails with `InvalidArgument` if the key or the value is
+  /// larger than the cluster accepts.
+  pub async fn put(
+    &self,
+    key: String,
+    value: Vec<u8>,
+    content_type: String,
+    lease: u64,
+  ) -> Result<Response, Status> {
+    let request = SetRequest {
+      key,
+      value,
+      lease,
+      namespace: self.options.namespace.clone(),
+      content_type,
+    };
+
+    // Writing again bumps the revision and the version of the key, so a write that may have
+    // been applied is not retried.
+    self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.set(request).await }
+      })
+      .await
+  }
+
+  /// Deletes `key`, returning its previous value as a string if it existed.
+  pub async fn delete_value(&self, key: String) -> Result<Option<String>, Status> {
+    let request = DeleteRequest {
+      key,
+      namespace: self.options.namespace.clone(),
+    };
+
+    // A retry after a delete that was applied would return no previous value, so only the
+    // requests refused by a node that is not the leader are retried.
+    let result = self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.delete(request).await }
+      })
+      .await?;
+
+    // Return the previous value, if the key existed
+    Ok(result.value.map(lossy_string))
+  }
 
-    // Make the RPC call
-    let response = client.set(request).await?;
-    let result = response.into_inner();
+  /// Writes `new_value` to `key`, or deletes it if `new_value` is `None`, only if the key is
+  /// in the `expected` state.
+  ///
+  /// Returns whether the write was applied, along with the current value of the key. Values
+  /// that are not UTF-8 are converted lossily.
+  pub async fn compare_and_swap(
+    &self,
+    key: String,
+    expected: Expected,
+    new_value: Option<String>,
+  ) -> Result<(bool, Option<String>), Status> {
+    let request = CompareAndSwapRequest {
+      key,
+      expected: Some(expected),
+      new_value: new_value.map(String::into_bytes),
+      lease: 0,
+      namespace: self.options.namespace.clone(),
+      content_type: String::new(),
+    };
+
+    // Applying it twice could report a failure for a swap that succeeded.
+    let result = self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.compare_and_swap(request).await }
+      })
+      .await?;
+
+    Ok((
+      result.succeeded.unwrap_or(false),
+      result.value.map(lossy_string),
+    ))
+  }
+
+  /// Applies a transaction: if every guard in `request.compare` holds, the `success` operations
+  /// are applied, otherwise the `failure` ones, atomically.
+  ///
+  /// Returns whether the guards held, and the result of each applied operation in `responses`.
+  /// Fails with `NotFound` if a write names a lease that does not exist, or `ResourceExhausted`
+  /// if the writes would make a namespace exceed its quota.
+  ///
+  /// Guards and operations that do not name a namespace use the one of this client.
+  pub async fn txn(&self, mut request: TxnRequest) -> Result<Response, Status> {
+    for guard in &mut request.compare {
+      self.default_namespace(&mut guard.namespace);
+    }
+    for op in request.success.iter_mut().chain(&mut request.failure) {
+      match &mut op.op {
+        Some(txn_op::Op::Set(set)) => self.default_namespace(&mut set.namespace),
+        Some(txn_op::Op::Delete(delete)) => self.default_namespace(&mut delete.namespace),
+        Some(txn_op::Op::Get(get)) => self.default_namespace(&mut get.namespace),
+        None => {}
+      }
+    }
+
+    // The guards of a retry could see the changes of a failed attempt that was applied.
+    self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.txn(request).await }
+      })
+      .await
+  }
+
+  /// Grants a lease that expires unless it is kept alive at least once every `ttl` seconds.
+  ///
+  /// Returns the id of the lease.
+  pub async fn grant_lease(&self, ttl: u64) -> Result<u64, Status> {
+    let request = GrantLeaseRequest { ttl };
+
+    // Retrying after a failed attempt that was applied would leak a lease.
+    let result = self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.grant_lease(request).await }
+      })
+      .await?;
+
+    Ok(result.id)
+  }
+
+  /// Opens a stream to keep leases alive with [`KeepAlive::renew`].
+  pub async fn keep_alive(&self) -> Result<KeepAlive, Status> {
+    KeepAlive::start(self.endpoints.clone(), self.options.clone()).await
+  }
+
+  /// Revokes a lease, deleting the keys attached to it.
+  ///
+  /// Fails with `NotFound` if the lease does not exist.
+  pub async fn revoke_lease(&self, id: u64) -> Result<(), Status> {
+    let request = RevokeLeaseRequest { id };
+
+    // A retry after a revoke that was applied would fail with `NotFound`.
+    self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.revoke_lease(request).await }
+      })
+      .await
+  }
+
+  /// Waits until the named lock is acquired, and holds it until it is unlocked or `lease`
+  /// expires.
+  ///
+  /// Fails with the last `DeadlineExceeded` or `Cancelled` status if the lock is not acquired
+  /// within the `wait_timeout` of the options.
+  ///
+  /// Returns the key holding the lock and the fencing token of the holder. The token grows each
+  /// time the lock changes hands: pass it to the resources the lock protects, so they can refuse
+  /// requests from a previous holder.
+  pub async fn lock(&self, name: String, lease: u64) -> Result<LockResponse, Status> {
+    let request = LockRequest {
+      name,
+      lease,
+      owner: new_owner(),
+    };
+
+    // Acquiring a lock already held by the same owner returns it, so the request is retried
+    // whenever it fails, including when it times out while waiting, until `wait_timeout`.
+    let deadline = Instant::now() + self.options.wait_timeout;
+    loop {
+      let result = self
+        .call(Retry::Idempotent, |mut client| {
+          let request = Request::new(request.clone());
+          async move { client.lock(request).await }
+        })
+        .await;
+      match result {
+        Err(status) if is_timeout(&status) && Instant::now() < deadline => continue,
+        result => return result,
+      }
+    }
+  }
+
+  /// Releases a lock held with `fencing_token`.
+  ///
+  /// Fails with `NotFound` if the lock is not held with this token.
+  pub async fn unlock(&self, name: String, fencing_token: u64) -> Result<(), Status> {
+    let request = UnlockRequest {
+      name,
+      fencing_token,
+    };
+
+    // A retry after an unlock that was applied would fail with `NotFound`.
+    self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.unlock(request).await }
+      })
+      .await
+  }
+
+  /// Waits until elected leader of the named election, and stays the leader until resigning or
+  /// until `lease` expires. `value` is published to observers while this client is the leader.
+  ///
+  /// Returns the leadership, with the fencing token of the new leader. Fails like
+  /// [`RaftClient::lock`] if not elected within the `wait_timeout` of the options.
+  pub async fn campaign(
+    &self,
+    name: String,
+    lease: u64,
+    value: String,
+  ) -> Result<LeaderResponse, Status> {
+    let request = CampaignRequest {
+      name,
+      lease,
+      value,
+      owner: new_owner(),
+    };
+
+    // Like a lock, campaigning again with the same owner returns the current leadership.
+    let deadline = Instant::now() + self.options.wait_timeout;
+    loop {
+      let result = self
+        .call(Retry::Idempotent, |mut client| {
+          let request = Request::new(request.clone());
+          async move { client.campaign(request).await }
+        })
+        .await;
+      match result {
+        Err(status) if is_timeout(&status) && Instant::now() < deadline => continue,
+        result => return result,
+      }
+    }
+  }
+
+  /// Gives up the leadership won with `fencing_token`.
+  ///
+  /// Fails with `NotFound` if the leadership is not held with this token.
+  pub async fn resign(&self, name: String, fencing_token: u64) -> Result<(), Status> {
+    let request = ResignRequest {
+      name,
+      fencing_token,
+    };
+
+    self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.resign(request).await }
+      })
+      .await
+  }
+
+  /// Streams the leader of the named election, starting with the current one if there is one,
+  /// then each new leader.
+  pub async fn observe(&self, name: String) -> Result<Streaming<LeaderResponse>, Status> {
+    let request = ObserveRequest { name };
+
+    self
+      .call(Retry::Idempotent, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.observe(request).await }
+      })
+      .await
+  }
+
+  /// Lists a range of keys, in lexical order. Only one page is returned when `request.limit` is
+  /// set; pass the returned `next_page_token` back as `page_token` to get the next one.
+  ///
+  /// The keys of the namespace of this client are listed, unless the request names another.
+  pub async fn range(&self, mut request: RangeRequest) -> Result<RangeResponse, Status> {
+    self.default_namespace(&mut request.namespace);
+
+    self
+      .call(Retry::Idempotent, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.range(request).await }
+      })
+      .await
+  }
+
+  /// Watches the changes made to `key`, or to every key starting with `key` if `prefix` is set.
+  ///
+  /// If `start_revision` is set, the changes made since that revision are received first.
+  pub async fn watch(
+    &self,
+    key: String,
+    prefix: bool,
+    start_revision: Option<u64>,
+  ) -> Result<Watch, Status> {
+    let request = WatchRequest {
+      key,
+      prefix,
+      start_revision,
+      namespace: self.options.namespace.clone(),
+    };
+    Watch::start(self.endpoints.clone(), request).await
+  }
+
+  /// Replaces the quota of the namespace of this client. Limits of 0 are unlimited.
+  ///
+  /// Returns the usage and the new quota of the namespace.
+  pub async fn set_quota(&self, quota: Quota) -> Result<NamespaceResponse, Status> {
+    let request = SetQuotaRequest {
+      namespace: self.options.namespace.clone(),
+      quota: Some(quota),
+    };
+
+    // Setting the same quota again leaves the store in the same state.
+    self
+      .call(Retry::Idempotent, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.set_quota(request).await }
+      })
+      .await
+  }
+
+  /// Returns the usage and the quota of the namespace of this client.
+  pub async fn namespace_usage(&self) -> Result<NamespaceResponse, Status> {
+    let request = NamespaceRequest {
+      namespace: self.options.namespace.clone(),
+      consistency: ReadConsistency::Linearizable.into(),
+    };
+
+    self
+      .call(Retry::Idempotent, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.get_namespace(request).await }
+      })
+      .await
+  }
+
+  /// Fills in the namespace of this client if `namespace` is empty.
+  fn default_namespace(&self, namespace: &mut String) {
+    if namespace.is_empty() {
+      namespace.clone_from(&self.options.namespace);
+    }
+  }
+
+  /// Removes a node from the cluster. A voter is demoted to learner before it is removed.
+  pub async fn remove_node(&self, node_id: u64) -> Result<(), Status> {
+    let request = RemoveNodeRequest { node_id };
+
+    // Once removed, the node is not found anymore: only retry when the leader was not reached.
+    self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.remove_node(request).await }
+      })
+      .await?;
+    Ok(())
+  }
+
+  /// Hands the leadership to `target`, or to the most up-to-date other voter, and returns the
+  /// new leader once it has taken over.
+  pub async fn transfer_leader(&self, target: Option<u64>) -> Result<u64, Status> {
+    let request = TransferLeaderRequest { target };
+
+    let response = self
+      .call(Retry::NotLeader, |mut client| {
+        let request = Request::new(request.clone());
+        async move { client.transfer_leader(request).await }
+      })
+      .await?;
+    Ok(response.leader_id)
+  }
+
+  /// Drains the first seed node, so that it refuses to become leader and stops its controller,
+  /// or undrains it. A drained leader hands its leadership over first.
+  pub async fn drain(&self, drain: bool) -> Result<DrainResponse, Status> {
+    let mut client = AppServiceClient::new(self.endpoints.primary());
+    let response = client.drain(Request::new(DrainRequest { drain })).await?;
+    Ok(response.into_inner())
+  }
+
+  /// Returns the metrics of the first seed node.
+  pub async fn metrics(&self) -> Result<MetricsResponse, Status> {
+    let mut client = AppServiceClient::new(self.endpoints.primary());
+    let response = client.metrics(Request::new(())).await?;
+    Ok(response.into_inner())
+  }
+
+  /// Makes the first seed node build a snapshot.
+  pub async fn trigger_snapshot(&self) -> Result<(), Status> {
+    let mut client = AppServiceClient::new(self.endpoints.primary());
+    client.trigger_snapshot(Request::new(())).await?;
+    Ok(())
+  }
 
-    // Return the response inner data (success flag)
-    Ok(result.value)
+  /// Makes the first seed node purge its log up to `upto`.
+  pub async fn purge_log(&self, upto: u64) -> Result<(), Status> {
+    let mut client = AppServiceClient::new(self.endpoints.primary());
+    client.purge_log(Request::new(PurgeLogRequest { upto })).await?;
+    Ok(())
   }
 }
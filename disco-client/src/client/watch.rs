@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use disco_daemon::protobuf::app_service_client::AppServiceClient;
+use disco_daemon::protobuf::{WatchRequest, WatchResponse};
+use tonic::{Code, Request, Status, Streaming};
+
+use super::Endpoints;
+
+/// A stream of the changes made to watched keys, created by [`super::RaftClient::watch`].
+///
+/// If the server drops the stream because this watcher fell behind, or the node becomes
+/// unreachable, the watch is restarted from the revision after the last one received, on another
+/// node if needed, so no change is missed. Before anything has been received, that revision is
+/// only known if the watch was started from one.
+pub struct Watch {
+  endpoints: Arc<Endpoints>,
+  request: WatchRequest,
+  addr: String,
+  stream: Streaming<WatchResponse>,
+}
+
+impl Watch {
+  pub(super) async fn start(
+    endpoints: Arc<Endpoints>,
+    request: WatchRequest,
+  ) -> Result<Self, Status> {
+    let (addr, stream) = Self::open(&endpoints, request.clone()).await?;
+    Ok(Self {
+      endpoints,
+      request,
+      addr,
+      stream,
+    })
+  }
+
+  /// Opens the stream on the leader, or the next node that can be reached.
+  async fn open(
+    endpoints: &Endpoints,
+    request: WatchRequest,
+  ) -> Result<(String, Streaming<WatchResponse>), Status> {
+    let (addr, channel) = endpoints.leader_or_next();
+    let mut client = AppServiceClient::new(channel);
+    match client.watch(Request::new(request)).await {
+      Ok(response) => Ok((addr, response.into_inner())),
+      Err(status) => {
+        endpoints.failed(&addr);
+        Err(status)
+      }
+    }
+  }
+
+  /// Waits for the next revision that changed the watched keys.
+  ///
+  /// Returns `None` if the server closed the stream.
+  pub async fn next(&mut self) -> Result<Option<WatchResponse>, Status> {
+    loop {
+      match self.stream.message().await {
+        Ok(Some(changes)) => {
+          self.request.start_revision = Some(changes.revision + 1);
+          return Ok(Some(changes));
+        }
+        Ok(None) => return Ok(None),
+        Err(status)
+          if matches!(status.code(), Code::Aborted | Code::Unavailable)
+            && self.request.start_revision.is_some() =>
+        {
+          tracing::debug!("restarting watch: {}", status.message());
+          if status.code() == Code::Unavailable {
+            self.endpoints.failed(&self.addr);
+          }
+          (self.addr, self.stream) = Self::open(&self.endpoints, self.request.clone()).await?;
+        }
+        Err(status) => return Err(status),
+      }
+    }
+  }
+}
@@ -1,3 +1,19 @@
 mod client;
 
+pub use client::compare;
+pub use client::txn_op;
+pub use client::ClientOptions;
+pub use client::Compare;
+pub use client::DrainResponse;
+pub use client::EventType;
+pub use client::Expected;
+pub use client::KeepAlive;
+pub use client::NamespaceResponse;
+pub use client::Quota;
 pub use client::RaftClient;
+pub use client::RangeRequest;
+pub use client::ReadConsistency;
+pub use client::TlsConfig;
+pub use client::TxnOp;
+pub use client::TxnRequest;
+pub use client::Watch;
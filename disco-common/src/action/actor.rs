@@ -1,4 +1,9 @@
+use std::sync::Arc;
+
 use tokio::sync::oneshot;
+use tokio::sync::watch;
+
+use crate::coordination::Coordinator;
 
 pub use oneshot::Sender;
 
@@ -24,7 +29,58 @@ pub struct CommandResult {
   pub status: i32,
 }
 
+/// What an actor runs with: the term of the leadership of the controller that runs it, and a
+/// signal telling it to stop.
+///
+/// The controller only runs on the leader. Once the node is no longer the leader, or is the
+/// leader of a later term, the actors of the controller are cancelled, and whatever they write
+/// with the term of their context is refused, even if it reaches the cluster after a new
+/// controller has started on another node.
+#[derive(Clone)]
+pub struct ActorContext {
+  term: u64,
+  cancelled: watch::Receiver<bool>,
+  coordinator: Arc<dyn Coordinator>,
+}
+
+impl ActorContext {
+  pub fn new(
+    term: u64,
+    cancelled: watch::Receiver<bool>,
+    coordinator: Arc<dyn Coordinator>,
+  ) -> Self {
+    Self {
+      term,
+      cancelled,
+      coordinator,
+    }
+  }
+
+  /// The fencing term to present when writing results back to the cluster.
+  pub fn term(&self) -> u64 {
+    self.term
+  }
+
+  /// Whether the actor should stop: its result is discarded.
+  pub fn is_cancelled(&self) -> bool {
+    *self.cancelled.borrow()
+  }
+
+  /// Waits until the actor should stop.
+  pub async fn cancelled(&mut self) {
+    let _ = self.cancelled.wait_for(|x| *x).await;
+  }
+
+  /// The cluster to write results back to, with [`Coordinator::put`] and the term of the context.
+  pub fn coordinator(&self) -> &Arc<dyn Coordinator> {
+    &self.coordinator
+  }
+}
+
 /// Base trait for all actor types
+///
+/// `process` runs on a thread where it may block. Long-running actors should check
+/// [`ActorContext::is_cancelled`] as they go.
 pub trait Actor: Send + 'static {
-  fn process(self: Box<Self>, respond_to: oneshot::Sender<ActorResponse>);
+  fn process(self: Box<Self>, context: ActorContext, respond_to: oneshot::Sender<ActorResponse>);
 }
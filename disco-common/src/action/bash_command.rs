@@ -1,7 +1,16 @@
-use super::actor::{Actor, ActorResponse, CommandResult, Sender};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::actor::{Actor, ActorContext, ActorResponse, CommandResult, Sender};
+
+/// How often a running command checks whether it was cancelled.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Run a bash command and capture its output
 ///
+/// The command is killed if the actor is cancelled.
 pub struct BashCommand {
   command: String,
 }
@@ -10,24 +19,58 @@ impl BashCommand {
   pub fn new(command: String) -> Box<Self> {
     Box::new(Self { command })
   }
-}
 
-impl Actor for BashCommand {
-  fn process(self: Box<Self>, respond_to: Sender<ActorResponse>) {
-    // Execute the command
-    let output = std::process::Command::new("bash")
+  fn run(&self, context: &ActorContext) -> std::io::Result<CommandResult> {
+    let mut child = Command::new("bash")
       .arg("-c")
       .arg(&self.command)
-      .output()
-      .expect("failed to execute process");
-
-    // Create the result
-    let result = CommandResult {
-      stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-      stderr: String::from_utf8_lossy(&output.stderr).to_string(),
-      status: output.status.code().unwrap_or(-1),
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()?;
+
+    // Read the output as it comes, so that the command does not block on a full pipe
+    let stdout = read_to_string(child.stdout.take());
+    let stderr = read_to_string(child.stderr.take());
+
+    let status = loop {
+      if let Some(status) = child.try_wait()? {
+        break status;
+      }
+      if context.is_cancelled() {
+        child.kill()?;
+        break child.wait()?;
+      }
+      std::thread::sleep(POLL_INTERVAL);
     };
 
+    Ok(CommandResult {
+      stdout: stdout.join().unwrap_or_default(),
+      stderr: stderr.join().unwrap_or_default(),
+      status: status.code().unwrap_or(-1),
+    })
+  }
+}
+
+/// Reads `pipe` to the end on another thread.
+fn read_to_string(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<String> {
+  std::thread::spawn(move || {
+    let mut output = Vec::new();
+    if let Some(mut pipe) = pipe {
+      let _ = pipe.read_to_end(&mut output);
+    }
+    String::from_utf8_lossy(&output).to_string()
+  })
+}
+
+impl Actor for BashCommand {
+  fn process(self: Box<Self>, context: ActorContext, respond_to: Sender<ActorResponse>) {
+    // Execute the command
+    let result = self.run(&context).unwrap_or_else(|e| CommandResult {
+      stdout: String::new(),
+      stderr: format!("failed to execute process: {}", e),
+      status: -1,
+    });
+
     // Send the result
     let _ = respond_to.send(ActorResponse::CommandResult(result));
   }
@@ -2,5 +2,5 @@ mod actor;
 
 mod bash_command;
 
-pub use actor::{Actor, ActorResponse};
+pub use actor::{Actor, ActorContext, ActorResponse};
 pub use bash_command::BashCommand;
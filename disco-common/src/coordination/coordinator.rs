@@ -0,0 +1,202 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use rhai::EvalAltResult;
+
+pub type CoordinationError = Box<dyn Error + Send + Sync>;
+
+/// Distributed locks and leader elections, made available to scripts by the node running them.
+///
+/// Locks and leaderships are attached to a lease, and are lost if the lease expires. Acquiring
+/// one returns a fencing token, which grows each time it changes hands: pass it along to the
+/// resources the lock protects so they can refuse requests from a previous holder.
+///
+/// Calls block until the cluster answers.
+pub trait Coordinator: Send + Sync {
+  /// Grants a lease that expires unless it is kept alive every `ttl` seconds, and returns its id.
+  fn grant_lease(&self, ttl: u64) -> Result<u64, CoordinationError>;
+
+  /// Renews a lease for its whole ttl.
+  fn keep_alive(&self, lease: u64) -> Result<(), CoordinationError>;
+
+  /// Revokes a lease, releasing everything attached to it.
+  fn revoke_lease(&self, lease: u64) -> Result<(), CoordinationError>;
+
+  /// Waits until the named lock is acquired, and returns the fencing token.
+  fn lock(&self, name: &str, lease: u64) -> Result<u64, CoordinationError>;
+
+  /// Releases a lock held with `fencing_token`.
+  fn unlock(&self, name: &str, fencing_token: u64) -> Result<(), CoordinationError>;
+
+  /// Waits until elected leader of the named election, publishing `value` while the leader, and
+  /// returns the fencing token.
+  fn campaign(&self, name: &str, lease: u64, value: &str) -> Result<u64, CoordinationError>;
+
+  /// Gives up the leadership won with `fencing_token`.
+  fn resign(&self, name: &str, fencing_token: u64) -> Result<(), CoordinationError>;
+
+  /// Returns the value published by the current leader of the named election, if there is one.
+  fn leader(&self, name: &str) -> Result<Option<String>, CoordinationError>;
+
+  /// Writes `value` to `key` of the controller namespace. The write is refused unless the
+  /// leadership of `term` is still current.
+  fn put(&self, key: &str, value: &str, term: u64) -> Result<(), CoordinationError>;
+}
+
+/// Rhai integers are signed; ids, ttls and tokens are not.
+fn unsigned(x: i64) -> Result<u64, Box<EvalAltResult>> {
+  u64::try_from(x).map_err(|_| format!("expected a positive number, got {x}").into())
+}
+
+fn signed(x: u64) -> i64 {
+  x as i64
+}
+
+fn script_error(e: CoordinationError) -> Box<EvalAltResult> {
+  e.to_string().into()
+}
+
+/// Registers the functions of `coordinator` in `engine`:
+///
+/// - `grant_lease(ttl)`, `keep_alive(lease)`, `revoke_lease(lease)`
+/// - `lock(name, lease)`, `unlock(name, token)`
+/// - `campaign(name, lease, value)`, `resign(name, token)`, `leader(name)`
+pub fn register_coordinator(engine: &mut rhai::Engine, coordinator: Arc<dyn Coordinator>) {
+  let c = coordinator.clone();
+  engine.register_fn("grant_lease", move |ttl: i64| {
+    c.grant_lease(unsigned(ttl)?)
+      .map(signed)
+      .map_err(script_error)
+  });
+
+  let c = coordinator.clone();
+  engine.register_fn("keep_alive", move |lease: i64| {
+    c.keep_alive(unsigned(lease)?).map_err(script_error)
+  });
+
+  let c = coordinator.clone();
+  engine.register_fn("revoke_lease", move |lease: i64| {
+    c.revoke_lease(unsigned(lease)?).map_err(script_error)
+  });
+
+  let c = coordinator.clone();
+  engine.register_fn("lock", move |name: &str, lease: i64| {
+    c.lock(name, unsigned(lease)?)
+      .map(signed)
+      .map_err(script_error)
+  });
+
+  let c = coordinator.clone();
+  engine.register_fn("unlock", move |name: &str, token: i64| {
+    c.unlock(name, unsigned(token)?).map_err(script_error)
+  });
+
+  let c = coordinator.clone();
+  engine.register_fn("campaign", move |name: &str, lease: i64, value: &str| {
+    c.campaign(name, unsigned(lease)?, value)
+      .map(signed)
+      .map_err(script_error)
+  });
+
+  let c = coordinator.clone();
+  engine.register_fn("resign", move |name: &str, token: i64| {
+    c.resign(name, unsigned(token)?).map_err(script_error)
+  });
+
+  let c = coordinator;
+  engine.register_fn(
+    "leader",
+    move |name: &str| -> Result<rhai::Dynamic, Box<EvalAltResult>> {
+      match c.leader(name).map_err(script_error)? {
+        Some(value) => Ok(value.into()),
+        None => Ok(rhai::Dynamic::UNIT),
+      }
+    },
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  /// Holds every lock in memory, for a single process.
+  #[derive(Default)]
+  struct LocalCoordinator {
+    locks: Mutex<Vec<(String, u64)>>,
+  }
+
+  impl Coordinator for LocalCoordinator {
+    fn grant_lease(&self, _ttl: u64) -> Result<u64, CoordinationError> {
+      Ok(1)
+    }
+
+    fn keep_alive(&self, _lease: u64) -> Result<(), CoordinationError> {
+      Ok(())
+    }
+
+    fn revoke_lease(&self, _lease: u64) -> Result<(), CoordinationError> {
+      Ok(())
+    }
+
+    fn lock(&self, name: &str, _lease: u64) -> Result<u64, CoordinationError> {
+      let mut locks = self.locks.lock().unwrap();
+      if locks.iter().any(|(x, _)| x == name) {
+        return Err("already locked".into());
+      }
+      let token = locks.len() as u64 + 10;
+      locks.push((name.to_string(), token));
+      Ok(token)
+    }
+
+    fn unlock(&self, name: &str, fencing_token: u64) -> Result<(), CoordinationError> {
+      let mut locks = self.locks.lock().unwrap();
+      let before = locks.len();
+      locks.retain(|(x, token)| !(x == name && *token == fencing_token));
+      if locks.len() == before {
+        return Err("not held".into());
+      }
+      Ok(())
+    }
+
+    fn campaign(&self, name: &str, lease: u64, _value: &str) -> Result<u64, CoordinationError> {
+      self.lock(name, lease)
+    }
+
+    fn resign(&self, name: &str, fencing_token: u64) -> Result<(), CoordinationError> {
+      self.unlock(name, fencing_token)
+    }
+
+    fn leader(&self, _name: &str) -> Result<Option<String>, CoordinationError> {
+      Ok(None)
+    }
+
+    fn put(&self, _key: &str, _value: &str, _term: u64) -> Result<(), CoordinationError> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_register_coordinator() {
+    let mut engine = rhai::Engine::new();
+    register_coordinator(&mut engine, Arc::new(LocalCoordinator::default()));
+
+    let token = engine
+      .eval::<i64>(
+        r#"
+          let lease = grant_lease(10);
+          let token = lock("deploy", lease);
+          unlock("deploy", token);
+          token
+        "#,
+      )
+      .unwrap();
+    assert_eq!(token, 10);
+
+    assert!(engine.eval::<()>(r#"unlock("deploy", 10)"#).is_err());
+    assert!(engine.eval::<()>(r#"grant_lease(-1)"#).is_err());
+    assert!(engine
+      .eval::<bool>(r#"type_of(leader("controller")) == "()""#)
+      .unwrap());
+  }
+}
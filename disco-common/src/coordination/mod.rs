@@ -0,0 +1,5 @@
+mod coordinator;
+
+pub use coordinator::register_coordinator;
+pub use coordinator::CoordinationError;
+pub use coordinator::Coordinator;
@@ -1,6 +1,8 @@
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use crate::builder::cluster_module;
+use crate::coordination::{register_coordinator, Coordinator};
 
 use rhai;
 use rhai::{exported_module, EvalAltResult, Position};
@@ -13,10 +15,25 @@ pub struct Engine {
 
 impl Engine {
   pub fn new<S: Into<String>>(filename: S) -> Result<Self, Box<dyn std::error::Error>> {
-    let rhai_engine = Self::configure_rhai_engine();
+    Self::load(filename.into(), None)
+  }
+
+  /// Runs the script with the locks and elections of `coordinator` available to it.
+  pub fn with_coordinator<S: Into<String>>(
+    filename: S,
+    coordinator: Arc<dyn Coordinator>,
+  ) -> Result<Self, Box<dyn std::error::Error>> {
+    Self::load(filename.into(), Some(coordinator))
+  }
+
+  fn load(
+    filename: String,
+    coordinator: Option<Arc<dyn Coordinator>>,
+  ) -> Result<Self, Box<dyn std::error::Error>> {
+    let rhai_engine = Self::configure_rhai_engine(coordinator);
 
     // Load the script file
-    let (script_path, script_contents) = Self::load_script(&filename.into())?;
+    let (script_path, script_contents) = Self::load_script(&filename)?;
 
     let expanded_filename = script_path.to_string_lossy();
 
@@ -103,12 +120,16 @@ impl Engine {
     }
   }
 
-  fn configure_rhai_engine() -> rhai::Engine {
+  fn configure_rhai_engine(coordinator: Option<Arc<dyn Coordinator>>) -> rhai::Engine {
     let mut engine = rhai::Engine::new();
     let module = exported_module!(cluster_module);
     // Register custom functions
     engine.register_global_module(module.into());
 
+    if let Some(coordinator) = coordinator {
+      register_coordinator(&mut engine, coordinator);
+    }
+
     // You can add more configuration here as needed
 
     engine
@@ -2,5 +2,6 @@
 
 pub mod action;
 pub mod builder;
+pub mod coordination;
 pub mod engine;
 pub mod provider;
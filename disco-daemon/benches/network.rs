@@ -0,0 +1,155 @@
+//! Compares the Raft network layer, which keeps a channel to each node, with connecting a new
+//! channel for each RPC as it used to.
+//!
+//! Run with `cargo bench --bench network`. For each way, it sends vote RPCs to a stub node on
+//! localhost and prints the mean latency of an RPC, and the CPU time the process (client and
+//! stub node together) spends on it.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use openraft::network::v2::RaftNetworkV2;
+use openraft::network::RPCOption;
+use openraft::RaftNetworkFactory;
+use tokio::net::TcpListener;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Channel;
+use tonic::transport::Server;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+use tonic::Streaming;
+
+use disco_daemon::network::Network;
+use disco_daemon::protobuf;
+use disco_daemon::protobuf::raft_service_client::RaftServiceClient;
+use disco_daemon::protobuf::raft_service_server::RaftService;
+use disco_daemon::protobuf::raft_service_server::RaftServiceServer;
+use disco_daemon::raft_types::VoteRequest;
+
+const RPCS: u32 = 2000;
+
+/// A node that refuses every vote, without any Raft behind it.
+struct StubNode;
+
+#[tonic::async_trait]
+impl RaftService for StubNode {
+  async fn vote(
+    &self,
+    request: Request<protobuf::VoteRequest>,
+  ) -> Result<Response<protobuf::VoteResponse>, Status> {
+    Ok(Response::new(protobuf::VoteResponse {
+      vote: request.into_inner().vote,
+      vote_granted: false,
+      last_log_id: None,
+    }))
+  }
+
+  async fn append_entries(
+    &self,
+    _request: Request<protobuf::AppendEntriesRequest>,
+  ) -> Result<Response<protobuf::AppendEntriesResponse>, Status> {
+    Err(Status::unimplemented("Not used by the benchmark"))
+  }
+
+  async fn snapshot(
+    &self,
+    _request: Request<Streaming<protobuf::SnapshotRequest>>,
+  ) -> Result<Response<protobuf::SnapshotResponse>, Status> {
+    Err(Status::unimplemented("Not used by the benchmark"))
+  }
+}
+
+/// Returns the CPU time the process has used so far, in user and system mode.
+fn cpu_time() -> Duration {
+  let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+  let usage = unsafe {
+    libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+    usage.assume_init()
+  };
+  let time = |x: libc::timeval| Duration::new(x.tv_sec as u64, x.tv_usec as u32 * 1000);
+  time(usage.ru_utime) + time(usage.ru_stime)
+}
+
+/// Runs `rpc` `RPCS` times and prints the mean wall time and CPU time it took.
+async fn measure<F, Fut>(name: &str, mut rpc: F)
+where
+  F: FnMut() -> Fut,
+  Fut: std::future::Future<Output = ()>,
+{
+  // Warm up, so that the pooled channel is connected before measuring.
+  rpc().await;
+
+  let start = Instant::now();
+  let start_cpu = cpu_time();
+  for _ in 0..RPCS {
+    rpc().await;
+  }
+  let elapsed = start.elapsed();
+  let cpu = cpu_time() - start_cpu;
+
+  println!(
+    "{:<20} latency {:>9.1?}/rpc    cpu {:>9.1?}/rpc",
+    name,
+    elapsed / RPCS,
+    cpu / RPCS
+  );
+}
+
+fn vote_request() -> VoteRequest {
+  let vote = protobuf::Vote {
+    leader_id: Some(protobuf::LeaderId {
+      term: 1,
+      node_id: 1,
+    }),
+    committed: false,
+  };
+  VoteRequest::new(vote, None)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+  let listener = TcpListener::bind("127.0.0.1:0").await?;
+  let addr = listener.local_addr()?;
+  let incoming = TcpIncoming::from_listener(listener, true, None)?;
+  tokio::spawn(
+    Server::builder()
+      .add_service(RaftServiceServer::new(StubNode))
+      .serve_with_incoming(incoming),
+  );
+
+  let node = protobuf::Node {
+    node_id: 2,
+    rpc_addr: addr.to_string(),
+  };
+
+  measure("connect per rpc", || async {
+    let channel = Channel::builder(format!("http://{}", addr).parse().unwrap())
+      .connect()
+      .await
+      .unwrap();
+    RaftServiceClient::new(channel)
+      .vote(protobuf::VoteRequest::from(vote_request()))
+      .await
+      .unwrap();
+  })
+  .await;
+
+  // A new connection is made for each RPC, as for the votes of each election, but all of them
+  // share the channel of the network.
+  let network = Network::new(1, None, 4 * 1024 * 1024);
+  measure("pooled channel", || {
+    let mut network = network.clone();
+    let node = node.clone();
+    async move {
+      let mut connection = network.new_client(node.node_id, &node).await;
+      connection
+        .vote(vote_request(), RPCOption::new(Duration::from_secs(1)))
+        .await
+        .unwrap();
+    }
+  })
+  .await;
+
+  Ok(())
+}
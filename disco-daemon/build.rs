@@ -13,6 +13,21 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     .btree_map(["."])
     .type_attribute("disco.Node", "#[derive(Eq)]")
     .type_attribute("disco.SetRequest", "#[derive(Eq)]")
+    .type_attribute("disco.DeleteRequest", "#[derive(Eq)]")
+    .type_attribute("disco.CompareAndSwapRequest", "#[derive(Eq)]")
+    .type_attribute("disco.CompareAndSwapRequest.expected", "#[derive(Eq)]")
+    .type_attribute("disco.GrantLeaseRequest", "#[derive(Eq)]")
+    .type_attribute("disco.RevokeLeaseRequest", "#[derive(Eq)]")
+    .type_attribute("disco.GetRequest", "#[derive(Eq)]")
+    .type_attribute("disco.Compare", "#[derive(Eq)]")
+    .type_attribute("disco.Compare.expected", "#[derive(Eq)]")
+    .type_attribute("disco.TxnOp", "#[derive(Eq)]")
+    .type_attribute("disco.TxnOp.op", "#[derive(Eq)]")
+    .type_attribute("disco.TxnRequest", "#[derive(Eq)]")
+    .type_attribute("disco.Quota", "#[derive(Eq)]")
+    .type_attribute("disco.SetQuotaRequest", "#[derive(Eq)]")
+    .type_attribute("disco.Command", "#[derive(Eq)]")
+    .type_attribute("disco.Command.op", "#[derive(Eq)]")
     .type_attribute("disco.Response", "#[derive(Eq)]")
     .type_attribute("disco.LeaderId", "#[derive(Eq)]")
     .type_attribute("disco.Vote", "#[derive(Eq)]")
@@ -1,5 +1,7 @@
 #![allow(clippy::uninlined_format_args)]
 
+use std::path::PathBuf;
+
 use clap::Parser;
 
 use disco_daemon::node::Node;
@@ -14,6 +16,14 @@ pub struct Opt {
   #[clap(long)]
   /// Network address to bind the server to (e.g., "127.0.0.1:50051")
   pub addr: String,
+
+  #[clap(long, env = "DISCO_DATA_DIR", default_value = "data")]
+  /// Directory where the Raft log and state are persisted
+  pub data_dir: PathBuf,
+
+  #[clap(long)]
+  /// Address of any member of a running cluster to join, as a learner first and then as a voter
+  pub join: Option<String>,
 }
 
 #[tokio::main]
@@ -30,7 +40,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
   let settings = Settings::new()?;
 
-  let service = Node::new(options.id, options.addr, settings).await;
+  let service = Node::new(options.id, options.addr, options.data_dir, settings).await?;
+  if let Some(seed) = options.join {
+    service.join(seed);
+  }
   service.run().await?;
 
   Ok(())
@@ -1,40 +1,76 @@
 use std::sync::Arc;
 use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio::sync::{
   mpsc::{channel, Receiver, Sender},
   OwnedSemaphorePermit, Semaphore,
 };
 use tokio::task::JoinHandle;
 use tracing::info;
+use tracing::warn;
 
-use disco_common::action::{Actor, ActorResponse, BashCommand};
+use disco_common::action::{Actor, ActorContext, ActorResponse, BashCommand};
+use disco_common::coordination::Coordinator;
 use disco_common::engine::Engine;
 
+/// The script run by the controller when it starts.
+const INIT_SCRIPT: &str = "test-deployment/init.rhai";
+
+/// Runs actors on the leader, for the leadership of a single term.
+///
+/// Stopping the controller cancels the actors it runs, and the init script; they are given the
+/// term as the fencing term of their writes.
 pub struct Controller {
+  term: u64,
   sender: Sender<Box<dyn Actor>>,
+  cancel: watch::Sender<bool>,
   task_handle: JoinHandle<()>,
-  engine: Engine,
+
+  // ends once the init script completes, or once the controller is stopped
+  init_handle: JoinHandle<()>,
 }
 
 impl Controller {
-  pub fn new(max_concurrent_tasks: usize) -> Controller {
+  /// Starts a controller, and runs the init script in the background.
+  ///
+  /// `coordinator` must give up the requests it waits on once `cancel` is set, so that a script
+  /// waiting for a lock stops with the controller.
+  pub fn new(
+    max_concurrent_tasks: usize,
+    coordinator: Arc<dyn Coordinator>,
+    term: u64,
+    cancel: watch::Sender<bool>,
+  ) -> Controller {
     let (sender, receiver) = channel::<Box<dyn Actor>>(100);
     let semaphore = Arc::new(Semaphore::new(max_concurrent_tasks));
+    let context = ActorContext::new(term, cancel.subscribe(), coordinator);
+    let init_handle = tokio::spawn(run_init_script(context.clone()));
 
     let task_handle = {
       let semaphore = semaphore.clone();
-      tokio::spawn(process_receiver(receiver, semaphore))
+      tokio::spawn(process_receiver(receiver, semaphore, context))
     };
 
     Controller {
+      term,
       sender,
+      cancel,
       task_handle,
-      engine: Engine::new("test-deployment/init.rhai").unwrap(),
+      init_handle,
     }
   }
 
+  /// The term of the leadership this controller runs for.
+  pub fn term(&self) -> u64 {
+    self.term
+  }
+
+  /// Cancels the init script and the running actors, drops the queued ones, and waits until no
+  /// more are started.
   pub async fn stop(self) -> Result<(), tokio::task::JoinError> {
+    self.cancel.send_replace(true);
     drop(self.sender);
+    self.init_handle.await?;
     self.task_handle.await
   }
 
@@ -53,22 +89,68 @@ impl Controller {
   }
 }
 
-async fn process_receiver(mut receiver: Receiver<Box<dyn Actor>>, semaphore: Arc<Semaphore>) {
+/// Runs the init script on a blocking thread, where it may wait on the coordinator, until it
+/// completes or the controller is stopped.
+async fn run_init_script(mut context: ActorContext) {
+  let term = context.term();
+  let coordinator = context.coordinator().clone();
+  let script = tokio::task::spawn_blocking(move || {
+    // The errors of the script itself are reported by the engine
+    Engine::with_coordinator(INIT_SCRIPT, coordinator)
+      .map(|_| ())
+      .map_err(|e| e.to_string())
+  });
+
+  tokio::select! {
+    res = script => match res {
+      Ok(Ok(())) => info!("Ran the init script for term {}", term),
+      Ok(Err(e)) => warn!("Failed to run the init script {}: {}", INIT_SCRIPT, e),
+      Err(e) => warn!("The init script {} failed: {}", INIT_SCRIPT, e),
+    },
+    // The script stops at its next request to the coordinator
+    _ = context.cancelled() => info!("Cancelled the init script of term {}", term),
+  }
+}
+
+async fn process_receiver(
+  mut receiver: Receiver<Box<dyn Actor>>,
+  semaphore: Arc<Semaphore>,
+  context: ActorContext,
+) {
   while let Some(actor) = receiver.recv().await {
     let permit = semaphore.clone().acquire_owned().await.unwrap();
-    tokio::spawn(process_actor(actor, permit));
+    if context.is_cancelled() {
+      break;
+    }
+    tokio::spawn(process_actor(actor, context.clone(), permit));
   }
 }
 
 // Standalone function to run an actor
-pub async fn run_actor(actor: Box<dyn Actor>) -> Result<ActorResponse, oneshot::error::RecvError> {
+pub async fn run_actor(
+  actor: Box<dyn Actor>,
+  context: ActorContext,
+) -> Result<ActorResponse, oneshot::error::RecvError> {
   let (tx, rx) = oneshot::channel();
-  actor.process(tx);
+  // Actors block, e.g. while a command runs
+  tokio::task::spawn_blocking(move || actor.process(context, tx));
   rx.await
 }
 
-pub async fn process_actor(actor: Box<dyn Actor>, _permit: OwnedSemaphorePermit) {
-  if let Ok(result) = run_actor(actor).await {
+pub async fn process_actor(
+  actor: Box<dyn Actor>,
+  context: ActorContext,
+  _permit: OwnedSemaphorePermit,
+) {
+  let cancelled = context.clone();
+  if let Ok(result) = run_actor(actor, context).await {
+    if cancelled.is_cancelled() {
+      info!(
+        "Discarding the result of an actor of term {}",
+        cancelled.term()
+      );
+      return;
+    }
     match &result {
       ActorResponse::CommandResult(cmd) => {
         info!(
@@ -0,0 +1,222 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use disco_common::coordination::{CoordinationError, Coordinator};
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+use crate::lease::LeaseManager;
+use crate::lock;
+use crate::lock::Locks;
+use crate::protobuf;
+use crate::protobuf::Rejection;
+use crate::raft_types::Raft;
+use crate::store::namespace;
+
+/// Gives the controller scripts access to the locks and elections of the cluster.
+///
+/// The controller runs on the leader, so requests are served locally rather than through the
+/// gRPC API. Scripts and actors run synchronously on the blocking threads of the runtime, so each
+/// call blocks its thread until it completes; it must not be made from an async task.
+///
+/// A coordinator is made for the leadership of one term, and its writes present that term as
+/// their fencing term: they are refused once the node is no longer the leader of that term. Once
+/// the controller of the term is stopped, the requests waiting on the coordinator fail, as do the
+/// later ones.
+pub struct NodeCoordinator {
+  raft: Raft,
+  lease_manager: Arc<LeaseManager>,
+  locks: Arc<Locks>,
+  term: u64,
+
+  /// Set when the controller of the term is stopped.
+  cancelled: watch::Receiver<bool>,
+
+  /// The runtime the coordinator was made on, which completes the requests of the scripts.
+  handle: Handle,
+}
+
+impl NodeCoordinator {
+  /// Makes a coordinator for the leadership of `term`, which is cancelled by `cancelled`. Must be
+  /// called from within the runtime.
+  pub fn new(
+    raft: Raft,
+    lease_manager: Arc<LeaseManager>,
+    locks: Arc<Locks>,
+    term: u64,
+    cancelled: watch::Receiver<bool>,
+  ) -> Self {
+    Self {
+      raft,
+      lease_manager,
+      locks,
+      term,
+      cancelled,
+      handle: Handle::current(),
+    }
+  }
+
+  /// Writes `cmd` fenced to `term`, failing if the state machine rejects it.
+  fn write(
+    &self,
+    cmd: protobuf::Command,
+    term: u64,
+  ) -> Result<protobuf::Response, CoordinationError> {
+    let res = self.block_on(self.raft.client_write(cmd.fenced(term)))?;
+    match res.data.rejection() {
+      Rejection::None => Ok(res.data),
+      Rejection::Fenced => Err(format!("term {} is over", term).into()),
+      rejection => Err(format!("write rejected: {:?}", rejection).into()),
+    }
+  }
+
+  /// Waits for `future`, unless the coordinator is cancelled first.
+  fn block_on<T, E>(
+    &self,
+    future: impl Future<Output = Result<T, E>>,
+  ) -> Result<T, CoordinationError>
+  where
+    E: Into<CoordinationError>,
+  {
+    let mut cancelled = self.cancelled.clone();
+    self.handle.block_on(async move {
+      tokio::select! {
+        biased;
+        // Also when the controller is gone
+        _ = cancelled.wait_for(|x| *x) => Err(self.term_over()),
+        res = future => res.map_err(Into::into),
+      }
+    })
+  }
+
+  fn term_over(&self) -> CoordinationError {
+    format!("term {} is over", self.term).into()
+  }
+}
+
+impl Coordinator for NodeCoordinator {
+  fn grant_lease(&self, ttl: u64) -> Result<u64, CoordinationError> {
+    if ttl == 0 {
+      return Err("the ttl must be at least 1 second".into());
+    }
+    let res = self.write(protobuf::GrantLeaseRequest { ttl }.into(), self.term)?;
+    Ok(res.lease.unwrap_or_default())
+  }
+
+  fn keep_alive(&self, lease: u64) -> Result<(), CoordinationError> {
+    if *self.cancelled.borrow() || self.raft.metrics().borrow().current_term != self.term {
+      return Err(self.term_over());
+    }
+    match self.lease_manager.keep_alive(lease)? {
+      Some(_) => Ok(()),
+      None => Err(format!("lease not found: {}", lease).into()),
+    }
+  }
+
+  fn revoke_lease(&self, lease: u64) -> Result<(), CoordinationError> {
+    let res = self.write(protobuf::RevokeLeaseRequest { id: lease }.into(), self.term)?;
+    if res.succeeded != Some(true) {
+      return Err(format!("lease not found: {}", lease).into());
+    }
+    Ok(())
+  }
+
+  // The script waits for the lock itself rather than retrying, so the acquisition has no owner:
+  // a lock already held with the same lease is contended like any other.
+  fn lock(&self, name: &str, lease: u64) -> Result<u64, CoordinationError> {
+    let key = lock::lock_key(name);
+    let holder = self.block_on(self.locks.acquire(&key, "", lease, "", self.term))?;
+    Ok(holder.fencing_token)
+  }
+
+  fn unlock(&self, name: &str, fencing_token: u64) -> Result<(), CoordinationError> {
+    self.block_on(
+      self
+        .locks
+        .release(&lock::lock_key(name), fencing_token, self.term),
+    )?;
+    Ok(())
+  }
+
+  fn campaign(&self, name: &str, lease: u64, value: &str) -> Result<u64, CoordinationError> {
+    let key = lock::election_key(name);
+    let holder = self.block_on(self.locks.acquire(&key, value, lease, "", self.term))?;
+    Ok(holder.fencing_token)
+  }
+
+  fn resign(&self, name: &str, fencing_token: u64) -> Result<(), CoordinationError> {
+    self.block_on(
+      self
+        .locks
+        .release(&lock::election_key(name), fencing_token, self.term),
+    )?;
+    Ok(())
+  }
+
+  fn leader(&self, name: &str) -> Result<Option<String>, CoordinationError> {
+    let holder = self.locks.holder(&lock::election_key(name));
+    Ok(holder.map(|x| x.value))
+  }
+
+  fn put(&self, key: &str, value: &str, term: u64) -> Result<(), CoordinationError> {
+    let req = protobuf::SetRequest {
+      key: key.to_string(),
+      value: value.as_bytes().to_vec(),
+      lease: 0,
+      namespace: namespace::CONTROLLER.to_string(),
+      content_type: String::new(),
+    };
+    self.write(req.into(), term)?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::testing;
+  use crate::testing::TestNode;
+
+  fn coordinator(node: &TestNode, cancelled: watch::Receiver<bool>) -> Arc<NodeCoordinator> {
+    let term = node.raft.metrics().borrow().current_term;
+    Arc::new(NodeCoordinator::new(
+      node.raft.clone(),
+      node.lease_manager.clone(),
+      node.locks.clone(),
+      term,
+      cancelled,
+    ))
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_cancel_waiting_lock() {
+    let nodes = testing::start_cluster(1).await;
+    let (cancel, cancelled) = watch::channel(false);
+    let c = coordinator(&nodes[0], cancelled);
+
+    // The script takes the lock, then waits for it with another lease
+    let script = tokio::task::spawn_blocking({
+      let c = c.clone();
+      move || {
+        let lease = c.grant_lease(60)?;
+        c.lock("deploy", lease)?;
+        let lease = c.grant_lease(60)?;
+        c.lock("deploy", lease)
+      }
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!script.is_finished());
+
+    cancel.send_replace(true);
+    let res = tokio::time::timeout(testing::TIMEOUT, script)
+      .await
+      .unwrap();
+    assert!(res.unwrap().is_err());
+
+    // The requests made once it is cancelled fail at once
+    let res = tokio::task::spawn_blocking(move || c.grant_lease(60)).await;
+    assert!(res.unwrap().is_err());
+  }
+}
@@ -1,3 +1,5 @@
 mod controller;
+mod coordinator;
 
 pub use controller::*;
+pub use coordinator::NodeCoordinator;
@@ -1,21 +1,48 @@
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::pin::Pin;
 use std::sync::Arc;
 
+use futures::stream;
+use futures::Stream;
+use openraft::raft::ReadPolicy;
+use openraft::ChangeMembers;
 use tonic::Request;
 use tonic::Response;
 use tonic::Status;
+use tonic::Streaming;
 use tracing::debug;
 
+use crate::grpc::forward;
+use crate::grpc::limits::RequestLimits;
+use crate::grpc::status::check_is_leader_status;
+use crate::grpc::status::not_leader;
+use crate::lease::LeaseManager;
+use crate::lock;
+use crate::lock::Holder;
+use crate::lock::LockError;
+use crate::lock::Locks;
+use crate::membership;
+use crate::membership::Drain;
+use crate::membership::MembershipError;
+use crate::membership::TRANSFER_TIMEOUT;
 use crate::protobuf;
+use crate::protobuf::txn_op;
+use crate::protobuf::ReadConsistency;
+use crate::protobuf::Rejection;
 use crate::raft_types::*;
+use crate::store::namespace;
+use crate::store::KeyFilter;
 use crate::store::StateMachineStore;
+use crate::store::WatchError;
+use crate::tls::TlsConfig;
 
 /// External API service implementation providing key-value store operations.
 /// This service handles client requests for getting and setting values in the distributed store.
 ///
 /// # Responsibilities
 /// - Handle key-value get operations
-/// - Handle key-value set operations
+/// - Handle key-value set and delete operations
 /// - Ensure consistency through Raft consensus
 ///
 /// # Protocol Safety
@@ -27,6 +54,16 @@ pub struct AppServiceImpl {
   /// The state machine store for direct reads
   /// The state machine's key-value store for direct reads
   state_machine_store: Arc<StateMachineStore>,
+  /// Tracks the expiry of leases while this node is the leader
+  lease_manager: Arc<LeaseManager>,
+  /// Distributed locks and elections
+  locks: Arc<Locks>,
+  /// Size limits of the writes accepted
+  limits: RequestLimits,
+  /// TLS to reach the leader with when forwarding requests, if enabled
+  tls: Option<Arc<TlsConfig>>,
+  /// The drain mode of this node
+  drain: Arc<Drain>,
 }
 
 impl AppServiceImpl {
@@ -35,49 +72,679 @@ impl AppServiceImpl {
   /// # Arguments
   /// * `raft` - The Raft node instance this service will use
   /// * `state_machine_store` - The state machine store for reading data
-  pub fn new(raft: Raft, state_machine_store: Arc<StateMachineStore>) -> Self {
+  /// * `lease_manager` - The lease manager renewing leases on keep-alives
+  /// * `locks` - The distributed locks and elections
+  /// * `limits` - The size limits of the writes accepted
+  /// * `tls` - The TLS configuration of the node, if enabled
+  /// * `drain` - The drain mode of the node
+  pub fn new(
+    raft: Raft,
+    state_machine_store: Arc<StateMachineStore>,
+    lease_manager: Arc<LeaseManager>,
+    locks: Arc<Locks>,
+    limits: RequestLimits,
+    tls: Option<Arc<TlsConfig>>,
+    drain: Arc<Drain>,
+  ) -> Self {
     AppServiceImpl {
       raft,
       state_machine_store,
+      lease_manager,
+      locks,
+      limits,
+      tls,
+      drain,
     }
   }
+
+  /// Waits until the local state machine can serve a read with the requested consistency
+  ///
+  /// # Returns
+  /// * `Ok(())` - The state machine is up to date enough
+  /// * `Err(Status)` - `FailedPrecondition` with the leader address if this node is not the
+  ///   leader, or `Unavailable` if leadership could not be confirmed
+  async fn ensure_readable(&self, consistency: ReadConsistency) -> Result<(), Status> {
+    let policy = match consistency {
+      ReadConsistency::Linearizable => ReadPolicy::ReadIndex,
+      ReadConsistency::Lease => ReadPolicy::LeaseRead,
+      ReadConsistency::Stale => return Ok(()),
+    };
+
+    self
+      .raft
+      .ensure_linearizable(policy)
+      .await
+      .map_err(check_is_leader_status)?;
+    Ok(())
+  }
+}
+
+/// Checks that `namespace` exists and, for a write, that clients may write to it.
+fn check_namespace(namespace: &str, write: bool) -> Result<(), Status> {
+  namespace::validate(namespace).map_err(Status::invalid_argument)?;
+  if write && namespace::is_reserved(namespace) {
+    return Err(Status::permission_denied(format!(
+      "Namespace {} is reserved",
+      namespace
+    )));
+  }
+  Ok(())
+}
+
+/// Converts the rejection of a write by the state machine into a status.
+fn check_rejection(res: &protobuf::Response) -> Result<(), Status> {
+  match res.rejection() {
+    Rejection::None => Ok(()),
+    Rejection::LeaseNotFound => Err(Status::not_found("Lease not found")),
+    Rejection::QuotaExceeded => Err(Status::resource_exhausted("Namespace quota exceeded")),
+    Rejection::Fenced => Err(Status::aborted("Fencing term is not the current term")),
+  }
+}
+
+/// Converts a watch failure into the status ending the stream.
+fn watch_status(e: WatchError) -> Status {
+  match e {
+    WatchError::Compacted { .. } => Status::out_of_range(e.to_string()),
+    WatchError::Lagged => Status::aborted(e.to_string()),
+    WatchError::Closed => Status::unavailable(e.to_string()),
+  }
+}
+
+/// Converts a lock failure, other than this node not being the leader, into a status.
+fn lock_status(e: LockError) -> Status {
+  match e {
+    LockError::ForwardToLeader(to) => not_leader(&to),
+    LockError::LeaseNotFound(_) | LockError::NotHeld => Status::not_found(e.to_string()),
+    LockError::Fenced(_) => Status::aborted(e.to_string()),
+    LockError::Write(_) => Status::internal(e.to_string()),
+  }
+}
+
+/// Converts the failure to change the membership of the cluster.
+fn membership_status(e: MembershipError) -> Status {
+  match e {
+    MembershipError::ForwardToLeader(to) => not_leader(&to),
+    MembershipError::NotMember(_) | MembershipError::NotVoter(_) => {
+      Status::not_found(e.to_string())
+    }
+    MembershipError::LastVoter(_) | MembershipError::NoTransferTarget => {
+      Status::invalid_argument(e.to_string())
+    }
+    MembershipError::TransferTimeout(_) => Status::deadline_exceeded(e.to_string()),
+    MembershipError::Demoted(_) => Status::unavailable(e.to_string()),
+    MembershipError::Write(_) => Status::internal(e.to_string()),
+  }
+}
+
+/// Describes the leader of election `name`.
+fn leader_response(name: &str, holder: Holder) -> protobuf::LeaderResponse {
+  protobuf::LeaderResponse {
+    name: name.to_string(),
+    value: holder.value,
+    fencing_token: holder.fencing_token,
+    lease: holder.lease,
+  }
 }
 
 #[tonic::async_trait]
 impl protobuf::app_service_server::AppService for AppServiceImpl {
+  type KeepAliveStream =
+    Pin<Box<dyn Stream<Item = Result<protobuf::LeaseResponse, Status>> + Send>>;
+  type ObserveStream = Pin<Box<dyn Stream<Item = Result<protobuf::LeaderResponse, Status>> + Send>>;
+  type WatchStream = Pin<Box<dyn Stream<Item = Result<protobuf::WatchResponse, Status>> + Send>>;
+
   /// Sets a value for a given key in the distributed store
   ///
   /// # Arguments
-  /// * `request` - Contains the key and value to set
+  /// * `request` - Contains the namespace, the key and value to set, and optionally a lease to
+  ///   attach the key to
   ///
   /// # Returns
   /// * `Ok(Response)` - Success response after the value is set
-  /// * `Err(Status)` - Error status if the set operation fails
+  /// * `Err(Status)` - Error status if the set operation fails, `NotFound` if the lease does not
+  ///   exist, `ResourceExhausted` if the namespace is full, `PermissionDenied` if the namespace
+  ///   is reserved, `InvalidArgument` if the key or the value is too large
   async fn set(
     &self,
     request: Request<protobuf::SetRequest>,
   ) -> Result<Response<protobuf::Response>, Status> {
+    let hops = forward::hops(&request);
     let req = request.into_inner();
     debug!("Processing set request for key: {}", req.key.clone());
 
-    let res = self
-      .raft
-      .client_write(req.clone())
-      .await
-      .map_err(|e| Status::internal(format!("Failed to write to store: {}", e)))?;
+    check_namespace(&req.namespace, true)?;
+    self.limits.check_set(&req)?;
+
+    let key = req.key.clone();
+    let res = match self.raft.client_write(req.clone().into()).await {
+      Ok(res) => res,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.set(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    check_rejection(&res.data)?;
+
+    debug!("Successfully set value for key: {}", key);
+    Ok(Response::new(res.data))
+  }
+
+  /// Deletes a key from the distributed store
+  ///
+  /// # Arguments
+  /// * `request` - Contains the namespace and the key to delete
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Response containing the previous value, if the key existed
+  /// * `Err(Status)` - Error status if the delete operation fails, `PermissionDenied` if the
+  ///   namespace is reserved
+  async fn delete(
+    &self,
+    request: Request<protobuf::DeleteRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing delete request for key: {}", req.key);
 
-    debug!("Successfully set value for key: {}", req.key);
+    check_namespace(&req.namespace, true)?;
+    self.limits.check_key(&req.key)?;
+
+    let key = req.key.clone();
+    let res = match self.raft.client_write(req.clone().into()).await {
+      Ok(res) => res,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.delete(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    debug!("Successfully deleted key: {}", key);
     Ok(Response::new(res.data))
   }
 
+  /// Conditionally writes or deletes a key in the distributed store
+  ///
+  /// # Arguments
+  /// * `request` - Contains the key, the expected current state and the new value
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Whether the write was applied, and the current value of the key
+  /// * `Err(Status)` - Error status if the operation could not be committed, `NotFound` if the
+  ///   lease does not exist, `ResourceExhausted` if the namespace is full, `PermissionDenied` if
+  ///   the namespace is reserved, `InvalidArgument` if the key or a value is too large
+  async fn compare_and_swap(
+    &self,
+    request: Request<protobuf::CompareAndSwapRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing compare-and-swap request for key: {}", req.key);
+
+    if req.expected.is_none() {
+      return Err(Status::invalid_argument("An expected state is required"));
+    }
+    check_namespace(&req.namespace, true)?;
+    self.limits.check_key(&req.key)?;
+    if let Some(protobuf::compare_and_swap_request::Expected::Value(value)) = &req.expected {
+      self.limits.check_value(&req.key, value)?;
+    }
+    if let Some(value) = &req.new_value {
+      self.limits.check_value(&req.key, value)?;
+    }
+    self.limits.check_entry(&req)?;
+
+    let res = match self.raft.client_write(req.clone().into()).await {
+      Ok(res) => res,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.compare_and_swap(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    check_rejection(&res.data)?;
+    Ok(Response::new(res.data))
+  }
+
+  /// Applies a transaction: operations applied atomically depending on guards
+  ///
+  /// Every guard in `compare` is checked against the current state of the store. If they all
+  /// hold, the `success` operations are applied, otherwise the `failure` ones, as a single
+  /// change: no reader or watcher can observe some of them without the others.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the guards and the operations of both branches
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Whether the guards held, and the result of each applied operation
+  /// * `Err(Status)` - `NotFound` if a write names a lease that does not exist, or
+  ///   `ResourceExhausted` if the writes would make a namespace exceed its quota, in which case
+  ///   nothing is applied; `PermissionDenied` if a write names a reserved namespace,
+  ///   `InvalidArgument` if a key, a value or the whole transaction is too large
+  async fn txn(
+    &self,
+    request: Request<protobuf::TxnRequest>,
+  ) -> Result<Response<protobuf::Response>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!(
+      "Processing transaction with {} guards, {} success and {} failure operations",
+      req.compare.len(),
+      req.success.len(),
+      req.failure.len()
+    );
+
+    if req.compare.iter().any(|x| x.expected.is_none()) {
+      return Err(Status::invalid_argument(
+        "An expected state is required for every guard",
+      ));
+    }
+    for guard in &req.compare {
+      check_namespace(&guard.namespace, false)?;
+    }
+    for op in req.success.iter().chain(&req.failure) {
+      match &op.op {
+        Some(txn_op::Op::Set(set)) => check_namespace(&set.namespace, true)?,
+        Some(txn_op::Op::Delete(delete)) => check_namespace(&delete.namespace, true)?,
+        Some(txn_op::Op::Get(get)) => check_namespace(&get.namespace, false)?,
+        None => {}
+      }
+    }
+    self.limits.check_txn(&req)?;
+
+    let res = match self.raft.client_write(req.clone().into()).await {
+      Ok(res) => res,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.txn(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    check_rejection(&res.data)?;
+    Ok(Response::new(res.data))
+  }
+
+  /// Grants a lease, which keys can be attached to
+  ///
+  /// The lease expires unless it is kept alive at least once every `ttl` seconds; the keys
+  /// attached to it are then deleted.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the ttl of the lease, in seconds
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The id and the ttl of the lease
+  /// * `Err(Status)` - `InvalidArgument` if the ttl is zero
+  async fn grant_lease(
+    &self,
+    request: Request<protobuf::GrantLeaseRequest>,
+  ) -> Result<Response<protobuf::LeaseResponse>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing grant lease request with ttl: {}", req.ttl);
+
+    if req.ttl == 0 {
+      return Err(Status::invalid_argument(
+        "The ttl must be at least 1 second",
+      ));
+    }
+
+    let res = match self.raft.client_write(req.clone().into()).await {
+      Ok(res) => res,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.grant_lease(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    let id = res.data.lease.unwrap_or_default();
+    debug!("Granted lease {}", id);
+    Ok(Response::new(protobuf::LeaseResponse { id, ttl: req.ttl }))
+  }
+
+  /// Keeps leases alive
+  ///
+  /// Each lease id received renews that lease for its whole ttl. Expiry is tracked by the
+  /// leader, so the stream must be opened on the leader; it ends with `FailedPrecondition` and
+  /// the leader address when this node is not, or no longer, the leader.
+  ///
+  /// # Arguments
+  /// * `request` - A stream of the ids of the leases to keep alive
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - A stream answering each id with the ttl of the lease, or 0 if the lease
+  ///   does not exist
+  async fn keep_alive(
+    &self,
+    request: Request<Streaming<protobuf::KeepAliveRequest>>,
+  ) -> Result<Response<Self::KeepAliveStream>, Status> {
+    let requests = request.into_inner();
+    let lease_manager = self.lease_manager.clone();
+    debug!("Processing keep alive stream");
+
+    // The stream ends after the first error.
+    let responses = stream::unfold(Some(requests), move |requests| {
+      let lease_manager = lease_manager.clone();
+      async move {
+        let mut requests = requests?;
+        let req = match requests.message().await {
+          Ok(Some(req)) => req,
+          Ok(None) => return None,
+          Err(status) => return Some((Err(status), None)),
+        };
+
+        match lease_manager.keep_alive(req.id) {
+          Ok(ttl) => {
+            let ttl = ttl.unwrap_or_default();
+            let res = protobuf::LeaseResponse { id: req.id, ttl };
+            Some((Ok(res), Some(requests)))
+          }
+          Err(to) => Some((Err(not_leader(&to)), None)),
+        }
+      }
+    });
+
+    Ok(Response::new(Box::pin(responses)))
+  }
+
+  /// Revokes a lease, deleting the keys attached to it
+  ///
+  /// # Arguments
+  /// * `request` - Contains the id of the lease
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Success response after the lease is revoked
+  /// * `Err(Status)` - `NotFound` if the lease does not exist
+  async fn revoke_lease(
+    &self,
+    request: Request<protobuf::RevokeLeaseRequest>,
+  ) -> Result<Response<()>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing revoke lease request for lease: {}", req.id);
+
+    let res = match self.raft.client_write(req.clone().into()).await {
+      Ok(res) => res,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.revoke_lease(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    if res.data.succeeded != Some(true) {
+      return Err(Status::not_found(format!("Lease not found: {}", req.id)));
+    }
+
+    debug!("Revoked lease {}", req.id);
+    Ok(Response::new(()))
+  }
+
+  /// Acquires a named lock, waiting until it is free. A retry of the same acquisition, with the
+  /// same owner and lease, returns the lock it already holds.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the lock, the lease of the holder and the owner of the
+  ///   acquisition
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The key holding the lock and the fencing token of the holder
+  /// * `Err(Status)` - `InvalidArgument` if the name or the lease is missing or the name is too
+  ///   long, `NotFound` if the lease does not exist
+  async fn lock(
+    &self,
+    request: Request<protobuf::LockRequest>,
+  ) -> Result<Response<protobuf::LockResponse>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing lock request for lock: {}", req.name);
+
+    if req.name.is_empty() || req.lease == 0 {
+      return Err(Status::invalid_argument("A name and a lease are required"));
+    }
+
+    let key = lock::lock_key(&req.name);
+    self.limits.check_key(&key)?;
+    let holder = match self.locks.acquire(&key, "", req.lease, &req.owner, 0).await {
+      Ok(holder) => holder,
+      Err(LockError::ForwardToLeader(to)) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.lock(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(lock_status(e)),
+    };
+
+    debug!("Acquired lock {} at {}", req.name, holder.fencing_token);
+    Ok(Response::new(protobuf::LockResponse {
+      key,
+      fencing_token: holder.fencing_token,
+    }))
+  }
+
+  /// Releases a lock
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the lock and the fencing token of the holder
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Success response after the lock is released
+  /// * `Err(Status)` - `NotFound` if the lock is not held with this fencing token
+  async fn unlock(
+    &self,
+    request: Request<protobuf::UnlockRequest>,
+  ) -> Result<Response<()>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing unlock request for lock: {}", req.name);
+
+    let key = lock::lock_key(&req.name);
+    match self.locks.release(&key, req.fencing_token, 0).await {
+      Ok(()) => Ok(Response::new(())),
+      Err(LockError::ForwardToLeader(to)) => {
+        forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.unlock(r).await },
+        )
+        .await
+      }
+      Err(e) => Err(lock_status(e)),
+    }
+  }
+
+  /// Runs for the leadership of a named election, waiting until elected
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the election, the lease of the candidate, the value to
+  ///   publish while it is the leader and the owner of the campaign
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The leadership, with the fencing token of the new leader
+  /// * `Err(Status)` - `InvalidArgument` if the name or the lease is missing or the value is too
+  ///   large, `NotFound` if the lease does not exist
+  async fn campaign(
+    &self,
+    request: Request<protobuf::CampaignRequest>,
+  ) -> Result<Response<protobuf::LeaderResponse>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing campaign request for election: {}", req.name);
+
+    if req.name.is_empty() || req.lease == 0 {
+      return Err(Status::invalid_argument("A name and a lease are required"));
+    }
+
+    let key = lock::election_key(&req.name);
+    self.limits.check_key(&key)?;
+    self.limits.check_value(&key, req.value.as_bytes())?;
+    let holder = match self
+      .locks
+      .acquire(&key, &req.value, req.lease, &req.owner, 0)
+      .await
+    {
+      Ok(holder) => holder,
+      Err(LockError::ForwardToLeader(to)) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.campaign(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(lock_status(e)),
+    };
+
+    debug!("Elected leader of {} at {}", req.name, holder.fencing_token);
+    Ok(Response::new(leader_response(&req.name, holder)))
+  }
+
+  /// Gives up the leadership of an election
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the election and the fencing token of the leader
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - Success response after the leadership is given up
+  /// * `Err(Status)` - `NotFound` if the leadership is not held with this fencing token
+  async fn resign(
+    &self,
+    request: Request<protobuf::ResignRequest>,
+  ) -> Result<Response<()>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!("Processing resign request for election: {}", req.name);
+
+    let key = lock::election_key(&req.name);
+    match self.locks.release(&key, req.fencing_token, 0).await {
+      Ok(()) => Ok(Response::new(())),
+      Err(LockError::ForwardToLeader(to)) => {
+        forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.resign(r).await },
+        )
+        .await
+      }
+      Err(e) => Err(lock_status(e)),
+    }
+  }
+
+  /// Streams the leader of an election, then each new leader
+  ///
+  /// Leaders are read from the state machine of this node as they are applied, like watches.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the name of the election
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - A stream of the leaders, starting with the current one if there is one
+  async fn observe(
+    &self,
+    request: Request<protobuf::ObserveRequest>,
+  ) -> Result<Response<Self::ObserveStream>, Status> {
+    let req = request.into_inner();
+    debug!("Processing observe request for election: {}", req.name);
+
+    let observer = self
+      .locks
+      .observe(&lock::election_key(&req.name))
+      .map_err(watch_status)?;
+
+    // The stream ends after the first error.
+    let name = req.name;
+    let leaders = stream::unfold(Some(observer), move |observer| {
+      let name = name.clone();
+      async move {
+        let mut observer = observer?;
+        match observer.next().await {
+          Ok(holder) => Some((Ok(leader_response(&name, holder)), Some(observer))),
+          Err(e) => {
+            debug!("Ending observe: {}", e);
+            Some((Err(watch_status(e)), None))
+          }
+        }
+      }
+    });
+
+    Ok(Response::new(Box::pin(leaders)))
+  }
+
   /// Gets a value for a given key from the distributed store
   ///
+  /// By default the read is linearizable: it is only served by the leader, once it has
+  /// confirmed its leadership and applied everything committed before the request.
+  ///
   /// # Arguments
-  /// * `request` - Contains the key to retrieve
+  /// * `request` - Contains the namespace, the key to retrieve and the read consistency
   ///
   /// # Returns
-  /// * `Ok(Response)` - Success response containing the value
-  /// * `Err(Status)` - Error status if the get operation fails
+  /// * `Ok(Response)` - Success response containing the value and its revisions, without a value
+  ///   nor revisions if the key does not exist
+  /// * `Err(Status)` - Error status if the get operation fails, `FailedPrecondition` with the
+  ///   leader address if this node can not serve the read
   async fn get(
     &self,
     request: Request<protobuf::GetRequest>,
@@ -85,19 +752,150 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     let req = request.into_inner();
     debug!("Processing get request for key: {}", req.key);
 
-    let sm = self
+    check_namespace(&req.namespace, false)?;
+    self.ensure_readable(req.consistency()).await?;
+
+    let res = self.state_machine_store.get(&req.namespace, &req.key);
+    if res.value.is_none() {
+      debug!("Key not found: {}", req.key);
+    } else {
+      debug!("Successfully retrieved value for key: {}", req.key);
+    }
+    Ok(Response::new(res))
+  }
+
+  /// Lists a range of keys of a namespace from the distributed store, in lexical order
+  ///
+  /// # Arguments
+  /// * `request` - Contains the namespace, the prefix and bounds of the range, and how to list it
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The keys in the range, or their count, and a token for the next page
+  async fn range(
+    &self,
+    request: Request<protobuf::RangeRequest>,
+  ) -> Result<Response<protobuf::RangeResponse>, Status> {
+    let req = request.into_inner();
+    debug!("Processing range request for prefix: {}", req.prefix);
+
+    check_namespace(&req.namespace, false)?;
+    self.ensure_readable(req.consistency()).await?;
+    let res = self.state_machine_store.range(&req);
+    Ok(Response::new(res))
+  }
+
+  /// Streams the changes made to a key, or to every key with a prefix
+  ///
+  /// Changes are read from the state machine of this node as they are applied, so a watch is
+  /// not interrupted by leader changes. If the watcher falls behind, the stream ends with
+  /// `Aborted` and the client should watch again from the revision after the last one received.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the namespace, the key or prefix to watch, and optionally a revision
+  ///   to start from
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - A stream of the changes, one message per revision
+  /// * `Err(Status)` - `OutOfRange` if the start revision is no longer available
+  async fn watch(
+    &self,
+    request: Request<protobuf::WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    let req = request.into_inner();
+    debug!(
+      "Processing watch request for key: {}, prefix: {}",
+      req.key, req.prefix
+    );
+
+    check_namespace(&req.namespace, false)?;
+    let filter = KeyFilter::new(&req.namespace, req.key, req.prefix);
+    let watcher = self
       .state_machine_store
-      .state_machine
-      .lock()
-      .map_err(|e| Status::internal(format!("error getting lock on sm: {}", e)))?;
-    let value = sm
-      .data
-      .get(&req.key)
-      .ok_or_else(|| Status::internal(format!("Key not found: {}", req.key)))?
-      .to_string();
+      .watch(filter, req.start_revision)
+      .map_err(watch_status)?;
+
+    // The stream ends after the first error.
+    let changes = stream::unfold(Some(watcher), |watcher| async move {
+      let mut watcher = watcher?;
+      match watcher.next().await {
+        Ok(changes) => Some((Ok(changes), Some(watcher))),
+        Err(e) => {
+          debug!("Ending watch: {}", e);
+          Some((Err(watch_status(e)), None))
+        }
+      }
+    });
+
+    Ok(Response::new(Box::pin(changes)))
+  }
+
+  /// Replaces the quota of a namespace
+  ///
+  /// A namespace already holding more than the new quota keeps its data, but writes that would
+  /// grow it are refused until it is back under the quota.
+  ///
+  /// # Arguments
+  /// * `request` - Contains the namespace and its quota, 0 meaning unlimited
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The usage and the new quota of the namespace
+  /// * `Err(Status)` - `PermissionDenied` if the namespace is reserved
+  async fn set_quota(
+    &self,
+    request: Request<protobuf::SetQuotaRequest>,
+  ) -> Result<Response<protobuf::NamespaceResponse>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+    debug!(
+      "Processing set quota request for namespace: {}",
+      req.namespace
+    );
+
+    check_namespace(&req.namespace, true)?;
 
-    debug!("Successfully retrieved value for key: {}", req.key);
-    Ok(Response::new(protobuf::Response { value: Some(value) }))
+    match self.raft.client_write(req.clone().into()).await {
+      Ok(_) => {}
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.set_quota(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(Status::internal(format!("Failed to write to store: {}", e))),
+    };
+
+    Ok(Response::new(
+      self.state_machine_store.namespace(&req.namespace),
+    ))
+  }
+
+  /// Describes the usage and the quota of a namespace
+  ///
+  /// # Arguments
+  /// * `request` - Contains the namespace and the read consistency
+  ///
+  /// # Returns
+  /// * `Ok(Response)` - The number of keys and bytes in the namespace, and its quota
+  async fn get_namespace(
+    &self,
+    request: Request<protobuf::NamespaceRequest>,
+  ) -> Result<Response<protobuf::NamespaceResponse>, Status> {
+    let req = request.into_inner();
+    debug!(
+      "Processing get namespace request for namespace: {}",
+      req.namespace
+    );
+
+    check_namespace(&req.namespace, false)?;
+    self.ensure_readable(req.consistency()).await?;
+    Ok(Response::new(
+      self.state_machine_store.namespace(&req.namespace),
+    ))
   }
 
   /// Initializes a new Raft cluster with the specified nodes
@@ -150,10 +948,12 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     &self,
     request: Request<protobuf::AddLearnerRequest>,
   ) -> Result<Response<protobuf::ClientWriteResponse>, Status> {
+    let hops = forward::hops(&request);
     let req = request.into_inner();
 
     let node = req
       .node
+      .clone()
       .ok_or_else(|| Status::internal("Node information is required"))?;
 
     debug!("Adding learner node {}", node.node_id);
@@ -163,11 +963,26 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
       node_id: node.node_id,
     };
 
-    let result = self
-      .raft
-      .add_learner(node.node_id, raft_node, true)
-      .await
-      .map_err(|e| Status::internal(format!("Failed to add learner node: {}", e)))?;
+    let result = match self.raft.add_learner(node.node_id, raft_node, true).await {
+      Ok(result) => result,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.add_learner(r).await },
+        )
+        .await;
+      }
+      Err(e) => {
+        return Err(Status::internal(format!(
+          "Failed to add learner node: {}",
+          e
+        )))
+      }
+    };
 
     debug!("Successfully added learner node {}", node.node_id);
     Ok(Response::new(result.into()))
@@ -185,23 +1000,132 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     &self,
     request: Request<protobuf::ChangeMembershipRequest>,
   ) -> Result<Response<protobuf::ClientWriteResponse>, Status> {
+    let hops = forward::hops(&request);
     let req = request.into_inner();
 
     debug!(
-      "Changing membership. Members: {:?}, Retain: {}",
-      req.members, req.retain
+      "Changing membership. Members: {:?}, Retain: {}, Add: {}",
+      req.members, req.retain, req.add
     );
 
-    let result = self
-      .raft
-      .change_membership(req.members, req.retain)
-      .await
-      .map_err(|e| Status::internal(format!("Failed to change membership: {}", e)))?;
+    let members = req.members.iter().copied().collect::<BTreeSet<_>>();
+    let changes = if req.add {
+      ChangeMembers::AddVoterIds(members)
+    } else {
+      ChangeMembers::ReplaceAllVoters(members)
+    };
+    let result = match self.raft.change_membership(changes, req.retain).await {
+      Ok(result) => result,
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.change_membership(r).await },
+        )
+        .await;
+      }
+      Err(e) => {
+        return Err(Status::internal(format!(
+          "Failed to change membership: {}",
+          e
+        )))
+      }
+    };
 
     debug!("Successfully changed cluster membership");
     Ok(Response::new(result.into()))
   }
 
+  /// Removes a node from the Raft cluster
+  ///
+  /// A voter is demoted to learner first, so that the quorum shrinks before the node stops
+  /// receiving the log.
+  async fn remove_node(
+    &self,
+    request: Request<protobuf::RemoveNodeRequest>,
+  ) -> Result<Response<protobuf::ClientWriteResponse>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+
+    debug!("Removing node {}", req.node_id);
+
+    let result = match membership::remove_node(&self.raft, req.node_id).await {
+      Ok(result) => result,
+      Err(MembershipError::ForwardToLeader(to)) => {
+        return forward::forward_to_leader(
+          self.tls.as_ref(),
+          self.limits.max_message_size(),
+          to,
+          hops,
+          req,
+          |mut c, r| async move { c.remove_node(r).await },
+        )
+        .await;
+      }
+      Err(e) => return Err(membership_status(e)),
+    };
+
+    debug!("Successfully removed node {}", req.node_id);
+    Ok(Response::new(result.into()))
+  }
+
+  /// Hands the leadership to another voter
+  ///
+  /// Waits until the new leader has taken over, so that requests sent afterwards reach it.
+  async fn transfer_leader(
+    &self,
+    request: Request<protobuf::TransferLeaderRequest>,
+  ) -> Result<Response<protobuf::TransferLeaderResponse>, Status> {
+    let hops = forward::hops(&request);
+    let req = request.into_inner();
+
+    debug!("Transferring leadership to {:?}", req.target);
+
+    let leader_id =
+      match membership::transfer_leadership(&self.raft, req.target, TRANSFER_TIMEOUT).await {
+        Ok(leader_id) => leader_id,
+        Err(MembershipError::ForwardToLeader(to)) => {
+          return forward::forward_to_leader(
+            self.tls.as_ref(),
+            self.limits.max_message_size(),
+            to,
+            hops,
+            req,
+            |mut c, r| async move { c.transfer_leader(r).await },
+          )
+          .await;
+        }
+        Err(e) => return Err(membership_status(e)),
+      };
+
+    debug!("Node {} is the new leader", leader_id);
+    Ok(Response::new(protobuf::TransferLeaderResponse {
+      leader_id,
+    }))
+  }
+
+  /// Turns the drain mode of this node on or off
+  ///
+  /// This is served by the node that receives it, whether it is the leader or not.
+  async fn drain(
+    &self,
+    request: Request<protobuf::DrainRequest>,
+  ) -> Result<Response<protobuf::DrainResponse>, Status> {
+    let req = request.into_inner();
+
+    debug!("Setting drain mode to {}", req.drain);
+    self.drain.set(req.drain).await.map_err(membership_status)?;
+
+    let current_leader = self.raft.metrics().borrow().current_leader;
+    Ok(Response::new(protobuf::DrainResponse {
+      drained: self.drain.is_drained(),
+      current_leader,
+    }))
+  }
+
   /// Retrieves metrics about the Raft node
   async fn metrics(
     &self,
@@ -212,7 +1136,228 @@ impl protobuf::app_service_server::AppService for AppServiceImpl {
     let resp = protobuf::MetricsResponse {
       membership: Some(metrics.membership_config.membership().clone().into()),
       other_metrics: metrics.to_string(),
+      snapshot: metrics.snapshot.map(|log_id| log_id.into()),
+      purged: metrics.purged.map(|log_id| log_id.into()),
+      current_leader: metrics.current_leader,
     };
     Ok(Response::new(resp))
   }
+
+  /// Builds a snapshot of the local state machine
+  ///
+  /// The snapshot is built in the background; its progress is visible in the `snapshot`
+  /// field of the metrics.
+  async fn trigger_snapshot(&self, _request: Request<()>) -> Result<Response<()>, Status> {
+    debug!("Triggering snapshot");
+
+    self
+      .raft
+      .trigger()
+      .snapshot()
+      .await
+      .map_err(|e| Status::internal(format!("Failed to trigger snapshot: {}", e)))?;
+
+    Ok(Response::new(()))
+  }
+
+  /// Purges the local log up to the given index
+  ///
+  /// # Arguments
+  /// * `request` - Contains the index up to which logs may be purged
+  ///
+  /// Only logs already included in a snapshot are purged; the progress is visible in the
+  /// `purged` field of the metrics.
+  async fn purge_log(
+    &self,
+    request: Request<protobuf::PurgeLogRequest>,
+  ) -> Result<Response<()>, Status> {
+    let req = request.into_inner();
+    debug!("Triggering log purge up to {}", req.upto);
+
+    self
+      .raft
+      .trigger()
+      .purge_log(req.upto)
+      .await
+      .map_err(|e| Status::internal(format!("Failed to trigger log purge: {}", e)))?;
+
+    Ok(Response::new(()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use protobuf::app_service_server::AppService;
+
+  use super::*;
+  use crate::testing;
+
+  #[tokio::test]
+  async fn test_trigger_snapshot_and_purge_log() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+
+    for i in 0..10 {
+      let req = protobuf::SetRequest {
+        key: format!("key-{}", i),
+        value: b"value".to_vec(),
+        ..Default::default()
+      };
+      node.app.set(Request::new(req)).await.unwrap();
+    }
+    let last_index = node.raft.metrics().borrow().last_log_index.unwrap();
+
+    node.app.trigger_snapshot(Request::new(())).await.unwrap();
+    let metrics = node
+      .raft
+      .wait(Some(testing::TIMEOUT))
+      .metrics(
+        |m| m.snapshot.map(|x| x.index) == Some(last_index),
+        "snapshot built",
+      )
+      .await
+      .unwrap();
+    let res = node
+      .app
+      .metrics(Request::new(()))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(res.snapshot.map(|x| x.index), Some(last_index));
+    assert_eq!(res.purged, None);
+    assert_eq!(metrics.purged, None);
+
+    let req = protobuf::PurgeLogRequest { upto: last_index };
+    node.app.purge_log(Request::new(req)).await.unwrap();
+    node
+      .raft
+      .wait(Some(testing::TIMEOUT))
+      .metrics(
+        |m| m.purged.map(|x| x.index) == Some(last_index),
+        "log purged",
+      )
+      .await
+      .unwrap();
+    let res = node
+      .app
+      .metrics(Request::new(()))
+      .await
+      .unwrap()
+      .into_inner();
+    assert_eq!(res.purged.map(|x| x.index), Some(last_index));
+  }
+
+  #[tokio::test]
+  async fn test_get_missing_key() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+
+    let req = protobuf::SetRequest {
+      key: "foo".to_string(),
+      value: b"bar".to_vec(),
+      ..Default::default()
+    };
+    node.app.set(Request::new(req)).await.unwrap();
+
+    let req = protobuf::GetRequest {
+      key: "missing".to_string(),
+      ..Default::default()
+    };
+    let res = node.app.get(Request::new(req)).await.unwrap().into_inner();
+    assert_eq!(res.value, None);
+    assert_eq!(res.revision, None);
+    assert_eq!(res.create_revision, None);
+    assert_eq!(res.version, None);
+    assert!(res.store_revision > 0);
+
+    let req = protobuf::GetRequest {
+      key: "foo".to_string(),
+      ..Default::default()
+    };
+    let res = node.app.get(Request::new(req)).await.unwrap().into_inner();
+    assert_eq!(res.value, Some(b"bar".to_vec()));
+    assert_eq!(res.version, Some(1));
+  }
+
+  #[tokio::test]
+  async fn test_drain() {
+    let nodes = testing::start_cluster(3).await;
+
+    let req = protobuf::DrainRequest { drain: true };
+    let res = nodes[0].app.drain(Request::new(req)).await.unwrap();
+    let res = res.into_inner();
+    assert!(res.drained);
+    assert!(res.current_leader.is_some_and(|x| x != 1));
+  }
+
+  #[tokio::test]
+  async fn test_remove_leader() {
+    let nodes = testing::start_cluster(3).await;
+
+    // The leader demotes itself, and the new leader removes it
+    let req = protobuf::RemoveNodeRequest { node_id: 1 };
+    nodes[0].app.remove_node(Request::new(req)).await.unwrap();
+
+    let metrics = nodes[1].raft.metrics().borrow().clone();
+    let leader = metrics.current_leader.unwrap();
+    let metrics = nodes[leader as usize - 1].raft.metrics().borrow().clone();
+    let membership = metrics.membership_config.membership();
+    assert_eq!(membership.voter_ids().collect::<Vec<_>>(), vec![2, 3]);
+    assert!(membership.get_node(&1).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_drain_and_transfer_leader_without_other_voter() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+
+    let req = protobuf::TransferLeaderRequest { target: None };
+    let status = node
+      .app
+      .transfer_leader(Request::new(req))
+      .await
+      .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    let req = protobuf::DrainRequest { drain: true };
+    let status = node.app.drain(Request::new(req)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(!node.drain.is_drained());
+  }
+
+  #[tokio::test]
+  async fn test_replicate_values_of_the_largest_size() {
+    let nodes = testing::start_cluster(3).await;
+    let value = vec![b'x'; 1024 * 1024];
+
+    // Written at once, so that the leader sends several of them to a follower in one RPC
+    let writes = (0..8).map(|i| {
+      let req = protobuf::SetRequest {
+        key: format!("key-{}", i),
+        value: value.clone(),
+        ..Default::default()
+      };
+      nodes[0].app.set(Request::new(req))
+    });
+    for res in futures::future::join_all(writes).await {
+      res.unwrap();
+    }
+
+    let last_index = nodes[0].raft.metrics().borrow().last_log_index;
+    for node in &nodes[1..] {
+      node
+        .raft
+        .wait(Some(testing::TIMEOUT))
+        .metrics(
+          |m| m.last_applied.map(|x| x.index) == last_index,
+          "values replicated",
+        )
+        .await
+        .unwrap();
+      for i in 0..8 {
+        let res = node.state_machine_store.get("", &format!("key-{}", i));
+        assert_eq!(res.value.as_ref(), Some(&value));
+      }
+    }
+  }
 }
@@ -0,0 +1,114 @@
+//! Forwarding of writes received by followers to the leader.
+//!
+//! Only the leader can write to the Raft log. When a follower receives a write, it sends the same
+//! RPC to the leader and relays the answer, so clients can talk to any node. Each forward is
+//! counted in the request metadata: leadership may move while a request is in flight, and the hop
+//! limit keeps a request from bouncing between nodes that each believe another one is the leader.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::transport::Endpoint;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
+use tracing::debug;
+
+use crate::grpc::status::not_leader;
+use crate::protobuf::app_service_client::AppServiceClient;
+use crate::raft_types::*;
+use crate::tls;
+use crate::tls::TlsConfig;
+
+/// Metadata key counting how many times a request has been forwarded.
+pub const FORWARD_HOPS_METADATA: &str = "disco-forward-hops";
+
+/// How many times a request may be forwarded before the client is told to find the leader.
+pub const MAX_FORWARD_HOPS: u32 = 2;
+
+/// Returns how many times `request` has already been forwarded.
+pub fn hops<T>(request: &Request<T>) -> u32 {
+  request
+    .metadata()
+    .get(FORWARD_HOPS_METADATA)
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| x.parse().ok())
+    .unwrap_or(0)
+}
+
+/// Sends `req` to the leader named in `forward` with `call`, and returns its answer. The leader
+/// is reached over TLS if `tls` is set, with messages of up to `max_message_size` bytes.
+///
+/// If the leader is unknown or `req` has already been forwarded too many times, a "not leader"
+/// status is returned instead, so that the client can retry later or elsewhere.
+pub async fn forward_to_leader<Req, Res, F, Fut>(
+  tls: Option<&Arc<TlsConfig>>,
+  max_message_size: usize,
+  forward: ForwardToLeader,
+  hops: u32,
+  req: Req,
+  call: F,
+) -> Result<Response<Res>, Status>
+where
+  F: FnOnce(AppServiceClient<Channel>, Request<Req>) -> Fut,
+  Fut: Future<Output = Result<Response<Res>, Status>>,
+{
+  let Some(leader) = forward.leader_node.as_ref() else {
+    return Err(not_leader(&forward));
+  };
+  if hops >= MAX_FORWARD_HOPS {
+    debug!("Not forwarding request, hop limit reached");
+    return Err(not_leader(&forward));
+  }
+
+  debug!("Forwarding request to leader at {}", leader.rpc_addr);
+
+  let endpoint = Endpoint::from_shared(format!("http://{}", leader.rpc_addr))
+    .map_err(|e| Status::internal(format!("Invalid leader address: {}", e)))?;
+  let channel = tls::connect(endpoint, tls, forward.leader_id)
+    .await
+    .map_err(|e| {
+      Status::unavailable(format!(
+        "Failed to connect to leader at {}: {}",
+        leader.rpc_addr, e
+      ))
+    })?;
+
+  let mut request = Request::new(req);
+  request
+    .metadata_mut()
+    .insert(FORWARD_HOPS_METADATA, MetadataValue::from(hops + 1));
+
+  let client = AppServiceClient::new(channel)
+    .max_decoding_message_size(max_message_size)
+    .max_encoding_message_size(max_message_size);
+  call(client, request).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_hops() {
+    let mut request = Request::new(());
+    assert_eq!(hops(&request), 0);
+
+    request
+      .metadata_mut()
+      .insert(FORWARD_HOPS_METADATA, MetadataValue::from(2u32));
+    assert_eq!(hops(&request), 2);
+  }
+
+  #[tokio::test]
+  async fn test_unknown_leader_is_not_leader_error() {
+    let status = forward_to_leader(None, 0, ForwardToLeader::empty(), 0, (), |_, _| async {
+      Ok::<_, Status>(Response::new(()))
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+  }
+}
@@ -0,0 +1,168 @@
+//! Limits on the size of the writes proposed to the Raft log.
+//!
+//! Every write is replicated to every node and kept in the log until a snapshot includes it, so
+//! oversized requests are refused before they are proposed, with an `InvalidArgument` status
+//! naming the limit they exceed.
+//!
+//! The gRPC messages between the nodes carry several entries at once, so their size limit is
+//! derived from the entry limit rather than left to the default of tonic.
+
+use prost::Message;
+use tonic::Status;
+
+use crate::protobuf;
+use crate::protobuf::txn_op;
+use crate::settings::Settings;
+
+/// The most entries the leader sends to a follower in one AppendEntries RPC.
+pub const MAX_PAYLOAD_ENTRIES: u64 = 32;
+
+/// Room for the fields of an entry or a message around the request it carries.
+const MESSAGE_OVERHEAD: usize = 4096;
+
+/// The smallest message size limit, which is the default of tonic and fits a snapshot chunk.
+const MIN_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RequestLimits {
+  /// The maximum size of a key, in bytes.
+  pub max_key_size: usize,
+
+  /// The maximum size of a value, in bytes.
+  pub max_value_size: usize,
+
+  /// The maximum encoded size of a whole request, in bytes.
+  pub max_entry_size: usize,
+}
+
+impl RequestLimits {
+  pub fn new(settings: &Settings) -> Self {
+    Self {
+      max_key_size: settings.max_key_size,
+      max_value_size: settings.max_value_size,
+      max_entry_size: settings.max_entry_size,
+    }
+  }
+
+  /// The largest gRPC message a node sends or accepts: an AppendEntries RPC of
+  /// [`MAX_PAYLOAD_ENTRIES`] entries of the largest size.
+  pub fn max_message_size(&self) -> usize {
+    let entries = (self.max_entry_size + MESSAGE_OVERHEAD) * MAX_PAYLOAD_ENTRIES as usize;
+    (entries + MESSAGE_OVERHEAD).max(MIN_MESSAGE_SIZE)
+  }
+
+  pub fn check_key(&self, key: &str) -> Result<(), Status> {
+    if key.len() > self.max_key_size {
+      return Err(Status::invalid_argument(format!(
+        "Key is {} bytes, the maximum is {}",
+        key.len(),
+        self.max_key_size
+      )));
+    }
+    Ok(())
+  }
+
+  pub fn check_value(&self, key: &str, value: &[u8]) -> Result<(), Status> {
+    if value.len() > self.max_value_size {
+      return Err(Status::invalid_argument(format!(
+        "Value of key {} is {} bytes, the maximum is {}",
+        key,
+        value.len(),
+        self.max_value_size
+      )));
+    }
+    Ok(())
+  }
+
+  /// Checks the size of the log entry `req` is written as.
+  pub fn check_entry(&self, req: &impl Message) -> Result<(), Status> {
+    let size = req.encoded_len();
+    if size > self.max_entry_size {
+      return Err(Status::invalid_argument(format!(
+        "Request is {} bytes, the maximum is {}",
+        size, self.max_entry_size
+      )));
+    }
+    Ok(())
+  }
+
+  pub fn check_set(&self, req: &protobuf::SetRequest) -> Result<(), Status> {
+    self.check_key(&req.key)?;
+    self.check_value(&req.key, &req.value)?;
+    self.check_entry(req)
+  }
+
+  /// Checks every key and value of a transaction, and its size as a whole.
+  pub fn check_txn(&self, req: &protobuf::TxnRequest) -> Result<(), Status> {
+    for guard in &req.compare {
+      self.check_key(&guard.key)?;
+    }
+    for op in req.success.iter().chain(&req.failure) {
+      match &op.op {
+        Some(txn_op::Op::Set(set)) => {
+          self.check_key(&set.key)?;
+          self.check_value(&set.key, &set.value)?;
+        }
+        Some(txn_op::Op::Delete(delete)) => self.check_key(&delete.key)?,
+        Some(txn_op::Op::Get(get)) => self.check_key(&get.key)?,
+        None => {}
+      }
+    }
+    self.check_entry(req)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_max_message_size() {
+    let limits = RequestLimits {
+      max_key_size: 4096,
+      max_value_size: 1024 * 1024,
+      max_entry_size: 3 * 512 * 1024,
+    };
+    assert!(limits.max_message_size() > 3 * 512 * 1024 * MAX_PAYLOAD_ENTRIES as usize);
+
+    let limits = RequestLimits {
+      max_entry_size: 64,
+      ..limits
+    };
+    assert_eq!(limits.max_message_size(), MIN_MESSAGE_SIZE);
+  }
+
+  #[test]
+  fn test_check_txn() {
+    let limits = RequestLimits {
+      max_key_size: 4,
+      max_value_size: 8,
+      max_entry_size: 64,
+    };
+    let set = |key: &str, value: &[u8]| protobuf::TxnOp {
+      op: Some(txn_op::Op::Set(protobuf::SetRequest {
+        key: key.to_string(),
+        value: value.to_vec(),
+        ..Default::default()
+      })),
+    };
+    let txn = |success| protobuf::TxnRequest {
+      success,
+      ..Default::default()
+    };
+
+    assert!(limits.check_txn(&txn(vec![set("a", b"12345678")])).is_ok());
+
+    let status = limits
+      .check_txn(&txn(vec![set("abcde", b"1")]))
+      .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    assert!(limits
+      .check_txn(&txn(vec![set("a", b"123456789")]))
+      .is_err());
+
+    // Each write fits, but not all of them together.
+    let ops = (0..8).map(|_| set("a", b"12345678")).collect();
+    assert!(limits.check_txn(&txn(ops)).is_err());
+  }
+}
@@ -1,2 +1,5 @@
 pub mod app_service;
+pub mod forward;
+pub mod limits;
 pub mod raft_service;
+pub mod status;
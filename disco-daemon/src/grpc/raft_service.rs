@@ -1,3 +1,6 @@
+use std::io::Write;
+use std::sync::Arc;
+
 use futures::StreamExt;
 use openraft::Snapshot;
 use tonic::Request;
@@ -8,6 +11,10 @@ use tracing::debug;
 
 use crate::protobuf;
 use crate::raft_types::*;
+use crate::store::SnapshotFile;
+use crate::store::StateMachineStore;
+use crate::tls;
+use crate::tls::PeerInfo;
 
 /// Internal gRPC service implementation for Raft protocol communications.
 /// This service handles the core Raft consensus protocol operations between cluster nodes.
@@ -23,6 +30,8 @@ use crate::raft_types::*;
 pub struct RaftServiceImpl {
   /// The local Raft node instance that this service operates on
   raft: Raft,
+  /// The state machine store, which provides files to receive snapshots into
+  state_machine_store: Arc<StateMachineStore>,
 }
 
 impl RaftServiceImpl {
@@ -30,9 +39,54 @@ impl RaftServiceImpl {
   ///
   /// # Arguments
   /// * `raft` - The Raft node instance this service will operate on
-  pub fn new(raft: Raft) -> Self {
-    RaftServiceImpl { raft }
+  /// * `state_machine_store` - The state machine store receiving snapshots
+  pub fn new(raft: Raft, state_machine_store: Arc<StateMachineStore>) -> Self {
+    RaftServiceImpl {
+      raft,
+      state_machine_store,
+    }
+  }
+}
+
+/// Checks that the sender of a Raft RPC is the node its vote is from, by the certificate the
+/// sender presented if TLS is enabled. A node can then not act on behalf of another one.
+fn check_sender(peer: Option<&PeerInfo>, vote: Option<&protobuf::Vote>) -> Result<(), Status> {
+  let node_id = vote
+    .and_then(|x| x.leader_id.as_ref())
+    .map(|x| x.node_id)
+    .ok_or_else(|| Status::invalid_argument("Missing `vote`"))?;
+  tls::check_peer(peer, node_id)
+}
+
+/// Writes the data chunks of a snapshot stream to `file`, verifying them against `checksum`.
+async fn receive_snapshot_data(
+  stream: &mut Streaming<protobuf::SnapshotRequest>,
+  file: &mut SnapshotFile,
+  checksum: u32,
+) -> Result<(), Status> {
+  let mut hasher = crc32fast::Hasher::new();
+
+  while let Some(chunk) = stream.next().await {
+    let data = chunk?
+      .into_data_chunk()
+      .ok_or_else(|| Status::invalid_argument("Snapshot chunk must be data"))?;
+    hasher.update(&data);
+    file
+      .write_all(&data)
+      .map_err(|e| Status::internal(format!("Failed to write snapshot chunk: {}", e)))?;
+  }
+
+  let actual = hasher.finalize();
+  if actual != checksum {
+    return Err(Status::data_loss(format!(
+      "Snapshot checksum mismatch: expected {:08x}, got {:08x}",
+      checksum, actual
+    )));
   }
+
+  file
+    .rewind()
+    .map_err(|e| Status::internal(format!("Failed to rewind snapshot file: {}", e)))
 }
 
 #[tonic::async_trait]
@@ -54,6 +108,7 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
     request: Request<protobuf::VoteRequest>,
   ) -> Result<Response<protobuf::VoteResponse>, Status> {
     debug!("Processing vote request");
+    check_sender(request.extensions().get(), request.get_ref().vote.as_ref())?;
 
     let vote_resp = self
       .raft
@@ -82,6 +137,7 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
     request: Request<protobuf::AppendEntriesRequest>,
   ) -> Result<Response<protobuf::AppendEntriesResponse>, Status> {
     debug!("Processing append entries request");
+    check_sender(request.extensions().get(), request.get_ref().vote.as_ref())?;
 
     let append_resp = self
       .raft
@@ -95,6 +151,10 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
 
   /// Handles snapshot installation requests for state transfer using streaming.
   ///
+  /// Data chunks are written to a file as they arrive, so the snapshot is never buffered in
+  /// memory as a whole. The data is checked against the checksum sent in the metadata chunk
+  /// before the snapshot is installed.
+  ///
   /// # Arguments
   /// * `request` - Stream of snapshot chunks with metadata
   ///
@@ -106,6 +166,7 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
     request: Request<Streaming<protobuf::SnapshotRequest>>,
   ) -> Result<Response<protobuf::SnapshotResponse>, Status> {
     debug!("Processing streaming snapshot installation request");
+    let peer = request.extensions().get::<PeerInfo>().cloned();
     let mut stream = request.into_inner();
 
     // Get the first chunk which contains metadata
@@ -116,38 +177,47 @@ impl protobuf::raft_service_server::RaftService for RaftServiceImpl {
 
     let vote;
     let snapshot_meta;
+    let checksum;
     {
       let meta = first_chunk
         .into_meta()
         .ok_or_else(|| Status::invalid_argument("First snapshot chunk must be metadata"))?;
 
       debug!("Received snapshot metadata chunk: {:?}", meta);
+      check_sender(peer.as_ref(), meta.vote.as_ref())?;
 
-      vote = meta.vote.unwrap();
+      vote = meta
+        .vote
+        .ok_or_else(|| Status::invalid_argument("Missing `vote` in snapshot metadata"))?;
+      checksum = meta.checksum;
 
       snapshot_meta = SnapshotMeta {
         last_log_id: meta.last_log_id.map(|log_id| log_id.into()),
         last_membership: StoredMembership::new(
           meta.last_membership_log_id.map(|x| x.into()),
-          meta.last_membership.unwrap().into(),
+          meta
+            .last_membership
+            .ok_or_else(|| {
+              Status::invalid_argument("Missing `last_membership` in snapshot metadata")
+            })?
+            .into(),
         ),
         snapshot_id: meta.snapshot_id,
       };
     }
 
-    // Collect snapshot data
-    let mut snapshot_data_bytes = Vec::new();
+    // Stream the snapshot data to disk. The file is removed if it is dropped before being
+    // installed.
+    let mut snapshot_file = self
+      .state_machine_store
+      .begin_receiving()
+      .map_err(|e| Status::internal(format!("Failed to create snapshot file: {}", e)))?;
 
-    while let Some(chunk) = stream.next().await {
-      let data = chunk?
-        .into_data_chunk()
-        .ok_or_else(|| Status::invalid_argument("Snapshot chunk must be data"))?;
-      snapshot_data_bytes.extend_from_slice(&data);
-    }
+    receive_snapshot_data(&mut stream, &mut snapshot_file, checksum).await?;
 
     let snapshot = Snapshot {
       meta: snapshot_meta,
-      snapshot: snapshot_data_bytes,
+      snapshot: snapshot_file,
     };
 
     // Install the full snapshot
@@ -0,0 +1,96 @@
+//! Conversions of Raft errors into gRPC statuses.
+
+use tonic::metadata::MetadataValue;
+use tonic::Code;
+use tonic::Status;
+
+use crate::raft_types::*;
+
+/// Metadata key holding the id of the current leader in a "not leader" status.
+pub const LEADER_ID_METADATA: &str = "disco-leader-id";
+
+/// Metadata key holding the rpc address of the current leader in a "not leader" status.
+pub const LEADER_ADDR_METADATA: &str = "disco-leader-addr";
+
+/// The start of the message of a "not leader" status.
+const NOT_LEADER_MESSAGE: &str = "not leader";
+
+/// Builds the status returned when a request has to be served by the leader and this node is not
+/// the leader.
+///
+/// The status code is `FailedPrecondition`. The leader id and address, if known, are attached
+/// as [`LEADER_ID_METADATA`] and [`LEADER_ADDR_METADATA`], so that clients can retry there.
+pub fn not_leader(forward: &ForwardToLeader) -> Status {
+  let addr = forward.leader_node.as_ref().map(|x| x.rpc_addr.as_str());
+
+  let message = match (forward.leader_id, addr) {
+    (Some(id), Some(addr)) => format!(
+      "{}, the leader is node {} at {}",
+      NOT_LEADER_MESSAGE, id, addr
+    ),
+    (Some(id), None) => format!("{}, the leader is node {}", NOT_LEADER_MESSAGE, id),
+    _ => format!("{}, the leader is unknown", NOT_LEADER_MESSAGE),
+  };
+
+  let mut status = Status::failed_precondition(message);
+  if let Some(id) = forward.leader_id {
+    status
+      .metadata_mut()
+      .insert(LEADER_ID_METADATA, MetadataValue::from(id));
+  }
+  if let Some(value) = addr.and_then(|x| MetadataValue::try_from(x).ok()) {
+    status.metadata_mut().insert(LEADER_ADDR_METADATA, value);
+  }
+  status
+}
+
+/// Returns whether `status` was built by [`not_leader`], which guarantees that the request was
+/// not applied.
+///
+/// Other `FailedPrecondition` statuses, which do not carry the leader or the "not leader"
+/// message, are not.
+pub fn is_not_leader(status: &Status) -> bool {
+  status.code() == Code::FailedPrecondition
+    && (status.metadata().contains_key(LEADER_ID_METADATA)
+      || status.metadata().contains_key(LEADER_ADDR_METADATA)
+      || status.message().starts_with(NOT_LEADER_MESSAGE))
+}
+
+/// Converts the failure to confirm leadership for a read.
+pub fn check_is_leader_status(e: RaftError<CheckIsLeaderError>) -> Status {
+  match e {
+    RaftError::APIError(CheckIsLeaderError::ForwardToLeader(forward)) => not_leader(&forward),
+    RaftError::APIError(CheckIsLeaderError::QuorumNotEnough(e)) => {
+      Status::unavailable(format!("Failed to confirm leadership: {}", e))
+    }
+    RaftError::Fatal(e) => Status::internal(format!("Raft fatal error: {}", e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::protobuf;
+
+  #[test]
+  fn test_is_not_leader() {
+    let node = protobuf::Node {
+      node_id: 2,
+      rpc_addr: "127.0.0.1:5002".to_string(),
+    };
+    let status = not_leader(&ForwardToLeader::new(2, node));
+    assert!(is_not_leader(&status));
+    assert_eq!(
+      status.metadata().get(LEADER_ADDR_METADATA).unwrap(),
+      "127.0.0.1:5002"
+    );
+
+    // Without a leader to retry on, the message still tells the request was not applied.
+    assert!(is_not_leader(&not_leader(&ForwardToLeader::empty())));
+
+    assert!(!is_not_leader(&Status::failed_precondition(
+      "membership change in progress"
+    )));
+    assert!(!is_not_leader(&Status::unavailable("not leader")));
+  }
+}
@@ -0,0 +1,302 @@
+//! Provide `LeaseManager`, which expires leases on the leader.
+//!
+//! Leases are part of the state machine, but when they expire is not: it depends on wall-clock
+//! time and keep-alives, which are different on every node. Only the leader keeps a deadline for
+//! each lease, in memory. Once the deadline of a lease has passed, the leader revokes the lease
+//! through the Raft log, so that every node deletes the keys attached to it at the same point of
+//! the log.
+//!
+//! A new leader does not know the deadlines set by the previous one, so it gives every lease its
+//! whole ttl again. A lease may outlive its ttl across a leader change, but never expires early.
+//! Once past its deadline, a lease can no longer be kept alive, and the revoke is fenced with the
+//! term of the leader that set the deadline.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use openraft::ServerState;
+use tokio::time::Instant;
+use tracing::info;
+use tracing::warn;
+
+use crate::protobuf;
+use crate::protobuf::Rejection;
+use crate::raft_types::*;
+use crate::store::StateMachineStore;
+
+pub struct LeaseManager {
+  raft: Raft,
+  state_machine_store: Arc<StateMachineStore>,
+
+  /// How often to look for expired leases.
+  check_interval: Duration,
+
+  deadlines: Mutex<Deadlines>,
+}
+
+#[derive(Default)]
+struct Deadlines {
+  /// The term in which this node set the deadlines as the leader.
+  term: Option<u64>,
+
+  /// When each lease expires.
+  leases: HashMap<u64, Instant>,
+
+  /// The leases being revoked, past their deadline.
+  revoking: HashSet<u64>,
+}
+
+impl LeaseManager {
+  pub fn new(
+    raft: Raft,
+    state_machine_store: Arc<StateMachineStore>,
+    check_interval: Duration,
+  ) -> Self {
+    Self {
+      raft,
+      state_machine_store,
+      check_interval,
+      deadlines: Mutex::new(Deadlines::default()),
+    }
+  }
+
+  /// Revokes the expired leases while this node is the leader, until the process exits.
+  pub async fn run(self: Arc<Self>) {
+    info!("Monitoring lease expiry");
+
+    let mut interval = tokio::time::interval(self.check_interval);
+    loop {
+      interval.tick().await;
+
+      let Some((term, expired)) = self.expired() else {
+        continue;
+      };
+      for id in expired {
+        self.revoke(id, term).await;
+      }
+    }
+  }
+
+  /// Revokes lease `id`, which expired during the leadership of `term`. The write is refused if
+  /// the term is over, since the leader of a later term keeps deadlines of its own.
+  async fn revoke(&self, id: u64, term: u64) {
+    info!("Lease {} expired, revoking it", id);
+    let revoke = protobuf::Command::from(protobuf::RevokeLeaseRequest { id }).fenced(term);
+    let res = match self.raft.client_write(revoke).await {
+      Ok(res) if res.data.rejection() == Rejection::Fenced => Err(format!("term {} is over", term)),
+      Ok(_) => Ok(()),
+      Err(e) => Err(e.to_string()),
+    };
+
+    if let Err(e) = res {
+      // Revoked again at the next check, unless the term is over
+      warn!("Failed to revoke lease {}: {}", id, e);
+    }
+    let mut deadlines = self.deadlines.lock().unwrap();
+    if deadlines.term == Some(term) {
+      deadlines.revoking.remove(&id);
+    }
+  }
+
+  /// Renews lease `id` for its whole ttl.
+  ///
+  /// # Returns
+  /// * `Ok(Some(ttl))` - The lease has been renewed for `ttl` seconds
+  /// * `Ok(None)` - The lease does not exist, or is past its deadline
+  /// * `Err(ForwardToLeader)` - This node is not the leader
+  pub fn keep_alive(&self, id: u64) -> Result<Option<u64>, ForwardToLeader> {
+    let term = self.leader_term()?;
+
+    let Some(ttl) = self.state_machine_store.lease_ttl(id) else {
+      return Ok(None);
+    };
+
+    let mut deadlines = self.deadlines.lock().unwrap();
+    deadlines.start_term(term);
+
+    // Checked under the same lock as the expiry, so a lease is never renewed once it is revoked
+    let now = Instant::now();
+    if deadlines.leases.get(&id).is_some_and(|x| *x <= now) {
+      return Ok(None);
+    }
+    deadlines.leases.insert(id, now + Duration::from_secs(ttl));
+    Ok(Some(ttl))
+  }
+
+  /// Returns the current term if this node is the leader.
+  fn leader_term(&self) -> Result<u64, ForwardToLeader> {
+    let metrics = self.raft.metrics();
+    let metrics = metrics.borrow();
+
+    if metrics.state == ServerState::Leader {
+      return Ok(metrics.current_term);
+    }
+
+    let leader = metrics.current_leader.and_then(|id| {
+      let node = metrics.membership_config.membership().get_node(&id)?;
+      Some(ForwardToLeader::new(id, node.clone()))
+    });
+    Err(leader.unwrap_or_else(ForwardToLeader::empty))
+  }
+
+  /// Updates the deadlines from the leases in the state machine, and returns the current term and
+  /// the leases that have expired, which are then being revoked. Returns nothing unless this node
+  /// is the leader.
+  fn expired(&self) -> Option<(u64, Vec<u64>)> {
+    let mut deadlines = self.deadlines.lock().unwrap();
+
+    let Ok(term) = self.leader_term() else {
+      *deadlines = Deadlines::default();
+      return None;
+    };
+    deadlines.start_term(term);
+
+    let leases = self.state_machine_store.leases();
+    let now = Instant::now();
+
+    // Forget the revoked leases and start the clock of the new ones.
+    deadlines.leases.retain(|id, _| leases.contains_key(id));
+    for (id, ttl) in leases {
+      deadlines
+        .leases
+        .entry(id)
+        .or_insert_with(|| now + Duration::from_secs(ttl));
+    }
+
+    // Kept past their deadline until they are revoked, so they can not be renewed meanwhile
+    let expired = deadlines
+      .leases
+      .iter()
+      .filter(|(id, deadline)| **deadline <= now && !deadlines.revoking.contains(id))
+      .map(|(id, _)| *id)
+      .collect::<Vec<_>>();
+
+    deadlines.revoking.extend(&expired);
+    Some((term, expired))
+  }
+}
+
+impl Deadlines {
+  /// Drops the deadlines set during another term: another leader may have been renewing the
+  /// leases in the meantime.
+  fn start_term(&mut self, term: u64) {
+    if self.term != Some(term) {
+      self.term = Some(term);
+      self.leases.clear();
+      self.revoking.clear();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing;
+
+  async fn grant(raft: &Raft, ttl: u64) -> u64 {
+    let req = protobuf::GrantLeaseRequest { ttl };
+    raft
+      .client_write(req.into())
+      .await
+      .unwrap()
+      .data
+      .lease
+      .unwrap()
+  }
+
+  /// The leases found expired by `manager`, which must be on the leader.
+  fn expired(manager: &LeaseManager) -> Vec<u64> {
+    manager.expired().unwrap().1
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_lease_expires_unless_kept_alive() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+    let manager = &node.lease_manager;
+
+    let id = grant(&node.raft, 5).await;
+    assert!(expired(manager).is_empty());
+
+    // Renewed before its deadline, the lease gets its whole ttl again.
+    tokio::time::advance(Duration::from_secs(4)).await;
+    assert_eq!(manager.keep_alive(id).unwrap(), Some(5));
+    tokio::time::advance(Duration::from_secs(4)).await;
+    assert!(expired(manager).is_empty());
+
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert_eq!(expired(manager), vec![id]);
+
+    // Not reported twice while it is being revoked.
+    assert!(expired(manager).is_empty());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_expired_lease_is_revoked() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+
+    let id = grant(&node.raft, 2).await;
+    let req = protobuf::SetRequest {
+      key: "foo".to_string(),
+      value: b"bar".to_vec(),
+      lease: id,
+      ..Default::default()
+    };
+    node.raft.client_write(req.into()).await.unwrap();
+
+    tokio::spawn(node.lease_manager.clone().run());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(node.state_machine_store.lease_ttl(id), Some(2));
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(node.state_machine_store.lease_ttl(id), None);
+    assert_eq!(node.state_machine_store.get("", "foo").value, None);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_keep_alive_during_expiry() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+    let manager = &node.lease_manager;
+
+    let id = grant(&node.raft, 2).await;
+    assert!(expired(manager).is_empty());
+
+    // Past its deadline, the lease is not renewed, before or while it is being revoked
+    tokio::time::advance(Duration::from_secs(2)).await;
+    assert_eq!(manager.keep_alive(id).unwrap(), None);
+    let (term, leases) = manager.expired().unwrap();
+    assert_eq!(leases, vec![id]);
+    assert_eq!(manager.keep_alive(id).unwrap(), None);
+
+    // The revoke is refused once the term it expired in is over
+    manager.revoke(id, term + 1).await;
+    assert_eq!(node.state_machine_store.lease_ttl(id), Some(2));
+
+    manager.revoke(id, term).await;
+    assert_eq!(node.state_machine_store.lease_ttl(id), None);
+    assert!(expired(manager).is_empty());
+    assert_eq!(manager.keep_alive(id).unwrap(), None);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_revoked_lease_is_forgotten() {
+    let nodes = testing::start_cluster(1).await;
+    let node = &nodes[0];
+    let manager = &node.lease_manager;
+
+    let id = grant(&node.raft, 2).await;
+    assert!(expired(manager).is_empty());
+
+    let req = protobuf::RevokeLeaseRequest { id };
+    node.raft.client_write(req.into()).await.unwrap();
+
+    tokio::time::advance(Duration::from_secs(3)).await;
+    assert!(expired(manager).is_empty());
+    assert_eq!(manager.keep_alive(id).unwrap(), None);
+  }
+}
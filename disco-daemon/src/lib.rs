@@ -1,10 +1,14 @@
 pub mod controller;
 pub mod grpc;
+pub mod lease;
+pub mod lock;
+pub mod membership;
 pub mod network;
 pub mod node;
 pub mod raft_types;
 pub mod settings;
 pub mod store;
+pub mod tls;
 
 pub mod protobuf {
   tonic::include_proto!("disco");
@@ -15,13 +19,13 @@ mod pb_impl;
 openraft::declare_raft_types!(
     /// Declare the type configuration for example K/V store.
     pub TypeConfig:
-        D = protobuf::SetRequest,
+        D = protobuf::Command,
         R = protobuf::Response,
         LeaderId = protobuf::LeaderId,
         Vote = protobuf::Vote,
         Entry = protobuf::Entry,
         Node = protobuf::Node,
-        SnapshotData = Vec<u8>,
+        SnapshotData = store::SnapshotFile,
 );
 
 pub type NodeId = u64;
@@ -30,3 +34,5 @@ pub type StateMachineStore = store::StateMachineStore;
 
 #[cfg(test)]
 mod test;
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,358 @@
+//! Provide `Locks`, distributed locks and leader elections built on the key-value store.
+//!
+//! A lock, or the leadership of an election, is held by whoever creates its key, with a
+//! compare-and-swap that only succeeds if the key is absent. The key is attached to the lease of
+//! the holder, so it is deleted when the holder releases it or when the lease expires; the
+//! waiters then try again. Waiters are not queued: any of them may get the key next.
+//!
+//! The content type of the key records the owner of the acquisition that created it, so an
+//! acquisition that is retried finds the key it already holds, while other acquisitions sharing
+//! the same lease wait for it like any other.
+//!
+//! The keys live in the reserved `system` namespace, so clients can only change them through the
+//! lock and election requests.
+//!
+//! The revision at which the key was created is the fencing token of the holder. It grows each
+//! time the key changes hands, so a resource can refuse requests carrying an older token than one
+//! it has already seen, even from a holder that does not know yet that its lease expired.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::protobuf as pb;
+use crate::protobuf::compare_and_swap_request::Expected;
+use crate::protobuf::event::EventType;
+use crate::protobuf::Rejection;
+use crate::raft_types::*;
+use crate::store::namespace;
+use crate::store::KeyFilter;
+use crate::store::StateMachineStore;
+use crate::store::WatchError;
+use crate::store::Watcher;
+
+/// Prefix of the keys holding locks.
+pub const LOCK_PREFIX: &str = "/disco/lock/";
+
+/// Prefix of the keys holding the leadership of elections.
+pub const ELECTION_PREFIX: &str = "/disco/election/";
+
+pub fn lock_key(name: &str) -> String {
+  format!("{}{}", LOCK_PREFIX, name)
+}
+
+pub fn election_key(name: &str) -> String {
+  format!("{}{}", ELECTION_PREFIX, name)
+}
+
+#[derive(Debug)]
+pub enum LockError {
+  /// This node is not the leader.
+  ForwardToLeader(ForwardToLeader),
+
+  /// The lease of the holder does not exist.
+  LeaseNotFound(u64),
+
+  /// The key is not held with the given fencing token.
+  NotHeld,
+
+  /// The write presented a fencing term that is no longer the current term.
+  Fenced(u64),
+
+  /// The write could not be committed.
+  Write(String),
+}
+
+impl fmt::Display for LockError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LockError::ForwardToLeader(to) => write!(f, "not the leader: {}", to),
+      LockError::LeaseNotFound(lease) => write!(f, "lease not found: {}", lease),
+      LockError::NotHeld => write!(f, "not held with this fencing token"),
+      LockError::Fenced(term) => write!(f, "term {} is over", term),
+      LockError::Write(e) => write!(f, "failed to write to store: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for LockError {}
+
+/// The holder of a lock, or the leader of an election.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Holder {
+  pub value: String,
+  pub fencing_token: u64,
+  pub lease: u64,
+  pub owner: String,
+}
+
+impl Holder {
+  /// Returns the holder described by the response of a read or a write of the key, if the key
+  /// exists.
+  fn from_response(res: pb::Response) -> Option<Self> {
+    Some(Self {
+      value: String::from_utf8_lossy(&res.value?).into_owned(),
+      fencing_token: res.create_revision?,
+      lease: res.lease.unwrap_or_default(),
+      owner: res.content_type,
+    })
+  }
+
+  fn from_kv(kv: &pb::KeyValue) -> Self {
+    Self {
+      value: String::from_utf8_lossy(&kv.value).into_owned(),
+      fencing_token: kv.create_revision,
+      lease: kv.lease,
+      owner: kv.content_type.clone(),
+    }
+  }
+}
+
+pub struct Locks {
+  raft: Raft,
+  state_machine_store: Arc<StateMachineStore>,
+}
+
+impl Locks {
+  pub fn new(raft: Raft, state_machine_store: Arc<StateMachineStore>) -> Self {
+    Self {
+      raft,
+      state_machine_store,
+    }
+  }
+
+  /// Waits until `key` is created with `value`, attached to `lease`, by the acquisition `owner`.
+  ///
+  /// Acquiring a key already held by the same owner with the same lease returns the current
+  /// holder, so a request can be retried safely. An empty owner never matches: the acquisition
+  /// waits for the key even if it is held with the same lease. The writes are refused unless they
+  /// are made in `fencing_term`, if it is not 0.
+  pub async fn acquire(
+    &self,
+    key: &str,
+    value: &str,
+    lease: u64,
+    owner: &str,
+    fencing_term: u64,
+  ) -> Result<Holder, LockError> {
+    if lease == 0 {
+      return Err(LockError::LeaseNotFound(lease));
+    }
+
+    loop {
+      let res = self
+        .compare_and_swap(
+          pb::CompareAndSwapRequest {
+            key: key.to_string(),
+            expected: Some(Expected::Absent(())),
+            new_value: Some(value.as_bytes().to_vec()),
+            lease,
+            namespace: namespace::SYSTEM.to_string(),
+            content_type: owner.to_string(),
+          },
+          fencing_term,
+        )
+        .await?;
+
+      let acquired = res.succeeded == Some(true);
+      let store_revision = res.store_revision;
+      let Some(holder) = Holder::from_response(res) else {
+        // The key is absent, so it was the lease that did not exist.
+        return Err(LockError::LeaseNotFound(lease));
+      };
+      if acquired || (!owner.is_empty() && holder.owner == owner && holder.lease == lease) {
+        return Ok(holder);
+      }
+
+      tracing::debug!("{} is held, waiting for it to be released", key);
+      self.wait_for_delete(key, store_revision).await;
+    }
+  }
+
+  /// Deletes `key` if it is held with `fencing_token`. The write is refused unless it is made in
+  /// `fencing_term`, if it is not 0.
+  pub async fn release(
+    &self,
+    key: &str,
+    fencing_token: u64,
+    fencing_term: u64,
+  ) -> Result<(), LockError> {
+    let res = self
+      .compare_and_swap(
+        pb::CompareAndSwapRequest {
+          key: key.to_string(),
+          expected: Some(Expected::Revision(fencing_token)),
+          new_value: None,
+          lease: 0,
+          namespace: namespace::SYSTEM.to_string(),
+          content_type: String::new(),
+        },
+        fencing_term,
+      )
+      .await?;
+
+    if res.succeeded != Some(true) {
+      return Err(LockError::NotHeld);
+    }
+    Ok(())
+  }
+
+  /// Follows the holders of `key`, from the local state machine.
+  pub fn observe(&self, key: &str) -> Result<Observer, WatchError> {
+    let res = self.state_machine_store.get(namespace::SYSTEM, key);
+    let filter = KeyFilter::new(namespace::SYSTEM, key.to_string(), false);
+    let watcher = self
+      .state_machine_store
+      .watch(filter, Some(res.store_revision + 1))?;
+
+    Ok(Observer {
+      current: Holder::from_response(res),
+      watcher,
+    })
+  }
+
+  /// Returns the current holder of `key` in the local state machine.
+  pub fn holder(&self, key: &str) -> Option<Holder> {
+    Holder::from_response(self.state_machine_store.get(namespace::SYSTEM, key))
+  }
+
+  async fn compare_and_swap(
+    &self,
+    req: pb::CompareAndSwapRequest,
+    fencing_term: u64,
+  ) -> Result<pb::Response, LockError> {
+    let cmd = pb::Command::from(req).fenced(fencing_term);
+    match self.raft.client_write(cmd).await {
+      Ok(res) if res.data.rejection() == Rejection::Fenced => Err(LockError::Fenced(fencing_term)),
+      Ok(res) => Ok(res.data),
+      Err(RaftError::APIError(ClientWriteError::ForwardToLeader(to))) => {
+        Err(LockError::ForwardToLeader(to))
+      }
+      Err(e) => Err(LockError::Write(e.to_string())),
+    }
+  }
+
+  /// Returns once `key` has been deleted after `revision`, or once the deletion can no longer be
+  /// watched for, in which case the caller finds out by trying again.
+  async fn wait_for_delete(&self, key: &str, revision: u64) {
+    let filter = KeyFilter::new(namespace::SYSTEM, key.to_string(), false);
+    let Ok(mut watcher) = self.state_machine_store.watch(filter, Some(revision + 1)) else {
+      return;
+    };
+
+    while let Ok(changes) = watcher.next().await {
+      if changes
+        .events
+        .iter()
+        .any(|x| x.r#type() == EventType::Delete)
+      {
+        return;
+      }
+    }
+  }
+}
+
+/// Follows the holders of a key, created by [`Locks::observe`].
+pub struct Observer {
+  current: Option<Holder>,
+  watcher: Watcher,
+}
+
+impl Observer {
+  /// Waits for the next holder of the key. The holder at the time the observer was created, if
+  /// any, is returned first.
+  pub async fn next(&mut self) -> Result<Holder, WatchError> {
+    if let Some(current) = self.current.take() {
+      return Ok(current);
+    }
+
+    loop {
+      let changes = self.watcher.next().await?;
+      let put = changes
+        .events
+        .iter()
+        .rev()
+        .find(|x| x.r#type() == EventType::Put);
+      if let Some(kv) = put.and_then(|x| x.kv.as_ref()) {
+        return Ok(Holder::from_kv(kv));
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::testing;
+
+  async fn grant(raft: &Raft, ttl: u64) -> u64 {
+    let req = pb::GrantLeaseRequest { ttl };
+    raft
+      .client_write(req.into())
+      .await
+      .unwrap()
+      .data
+      .lease
+      .unwrap()
+  }
+
+  #[test]
+  fn test_holder_from_response() {
+    assert_eq!(Holder::from_response(pb::Response::default()), None);
+
+    let res = pb::Response {
+      value: Some(b"node-1".to_vec()),
+      revision: Some(7),
+      create_revision: Some(7),
+      lease: Some(3),
+      ..Default::default()
+    };
+    assert_eq!(
+      Holder::from_response(res),
+      Some(Holder {
+        value: "node-1".to_string(),
+        fencing_token: 7,
+        lease: 3,
+        owner: String::new(),
+      })
+    );
+  }
+
+  #[tokio::test]
+  async fn test_acquire_shared_lease() {
+    let nodes = testing::start_cluster(1).await;
+    let locks = nodes[0].locks.clone();
+    let lease = grant(&nodes[0].raft, 60).await;
+    let key = lock_key("deploy");
+
+    let holder = locks.acquire(&key, "", lease, "job-1", 0).await.unwrap();
+    assert_eq!(holder.owner, "job-1");
+
+    // A retry of the same acquisition gets back the lock it holds
+    let retried = locks.acquire(&key, "", lease, "job-1", 0).await.unwrap();
+    assert_eq!(retried, holder);
+
+    // Other acquisitions sharing the lease wait for it, whether they have an owner or not
+    for owner in ["job-2", ""] {
+      let acquire = locks.acquire(&key, "", lease, owner, 0);
+      let res = tokio::time::timeout(Duration::from_millis(500), acquire).await;
+      assert!(res.is_err(), "{:?} acquired a held lock", owner);
+    }
+
+    let waiting = tokio::spawn({
+      let locks = locks.clone();
+      let key = key.clone();
+      async move { locks.acquire(&key, "", lease, "job-2", 0).await }
+    });
+    locks.release(&key, holder.fencing_token, 0).await.unwrap();
+
+    let next = tokio::time::timeout(testing::TIMEOUT, waiting)
+      .await
+      .unwrap()
+      .unwrap()
+      .unwrap();
+    assert_eq!(next.owner, "job-2");
+    assert!(next.fencing_token > holder.fencing_token);
+  }
+}
@@ -0,0 +1,718 @@
+//! Removing nodes from the cluster, and moving the leadership away from a node.
+//!
+//! Both are done by the leader. A voter is removed in two membership changes: it is demoted to
+//! learner first, so that the quorum shrinks while it still receives the log, and then dropped.
+//!
+//! While it is the leader, a node can also keep the number of voters at a target on its own, see
+//! [`MembershipManager`].
+//!
+//! A node can also be drained, so that it can be taken down without waiting out an election:
+//! it stops standing for election, and hands the leadership over whenever it has it.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+use openraft::ChangeMembers;
+use openraft::ServerState;
+use tokio::sync::watch;
+use tracing::info;
+use tracing::warn;
+
+use crate::raft_types::*;
+use crate::NodeId;
+
+/// How long a leader waits for another node to take over when it hands the leadership over.
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum MembershipError {
+  /// This node is not the leader.
+  ForwardToLeader(ForwardToLeader),
+
+  /// The node is not a member of the cluster.
+  NotMember(NodeId),
+
+  /// The node is the only voter of the cluster, which can not do without it.
+  LastVoter(NodeId),
+
+  /// The node is not a voter, other than the leader, that could take over the leadership.
+  NotVoter(NodeId),
+
+  /// There is no other voter to take over the leadership.
+  NoTransferTarget,
+
+  /// The leadership did not move to another node in time.
+  TransferTimeout(Duration),
+
+  /// The leader demoted itself to learner, but no other node took over to remove it.
+  Demoted(NodeId),
+
+  /// The membership change could not be committed.
+  Write(String),
+}
+
+impl fmt::Display for MembershipError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MembershipError::ForwardToLeader(to) => write!(f, "not the leader: {}", to),
+      MembershipError::NotMember(id) => write!(f, "node {} is not a member", id),
+      MembershipError::LastVoter(id) => write!(f, "node {} is the last voter", id),
+      MembershipError::NotVoter(id) => write!(f, "node {} can not take over the leadership", id),
+      MembershipError::NoTransferTarget => write!(f, "no other voter can take over the leadership"),
+      MembershipError::TransferTimeout(timeout) => {
+        write!(f, "the leadership did not move in {:?}", timeout)
+      }
+      MembershipError::Demoted(id) => write!(
+        f,
+        "node {} was demoted to learner, but no other node took over to remove it",
+        id
+      ),
+      MembershipError::Write(e) => write!(f, "failed to change membership: {}", e),
+    }
+  }
+}
+
+impl std::error::Error for MembershipError {}
+
+impl From<RaftError<ClientWriteError>> for MembershipError {
+  fn from(e: RaftError<ClientWriteError>) -> Self {
+    match e {
+      RaftError::APIError(ClientWriteError::ForwardToLeader(to)) => {
+        MembershipError::ForwardToLeader(to)
+      }
+      e => MembershipError::Write(e.to_string()),
+    }
+  }
+}
+
+/// Returns the metrics of this node if it is the leader.
+fn leader_metrics(raft: &Raft) -> Result<RaftMetrics, ForwardToLeader> {
+  let metrics = raft.metrics().borrow().clone();
+
+  if metrics.state == ServerState::Leader {
+    return Ok(metrics);
+  }
+
+  let leader = metrics.current_leader.and_then(|id| {
+    let node = metrics.membership_config.membership().get_node(&id)?;
+    Some(ForwardToLeader::new(id, node.clone()))
+  });
+  Err(leader.unwrap_or_else(ForwardToLeader::empty))
+}
+
+/// Removes `node_id` from the cluster, demoting it to learner first if it is a voter.
+///
+/// Returns the response to the last membership change. A leader that removes itself steps down
+/// once it is a learner, and can not drop itself: it then waits for the new leader and fails with
+/// `ForwardToLeader`, so that the request is sent on to finish the removal.
+pub async fn remove_node(
+  raft: &Raft,
+  node_id: NodeId,
+) -> Result<ClientWriteResponse, MembershipError> {
+  let metrics = leader_metrics(raft).map_err(MembershipError::ForwardToLeader)?;
+  let membership = metrics.membership_config.membership();
+  if membership.get_node(&node_id).is_none() {
+    return Err(MembershipError::NotMember(node_id));
+  }
+  if is_last_voter(&metrics, node_id) {
+    return Err(MembershipError::LastVoter(node_id));
+  }
+
+  let ids = BTreeSet::from([node_id]);
+  if membership.voter_ids().any(|x| x == node_id) {
+    raft
+      .change_membership(ChangeMembers::RemoveVoters(ids.clone()), true)
+      .await?;
+  }
+
+  match raft
+    .change_membership(ChangeMembers::RemoveNodes(ids), false)
+    .await
+  {
+    Err(RaftError::APIError(ClientWriteError::ForwardToLeader(_))) if node_id == metrics.id => {
+      let to = match tokio::time::timeout(TRANSFER_TIMEOUT, wait_for_other_leader(raft)).await {
+        Ok(Ok(_)) => leader_metrics(raft).err(),
+        _ => None,
+      };
+      Err(to.map_or(
+        MembershipError::Demoted(node_id),
+        MembershipError::ForwardToLeader,
+      ))
+    }
+    result => Ok(result?),
+  }
+}
+
+/// Returns whether `node_id` is the only voter of the membership in `metrics`, which can then not
+/// be removed.
+pub fn is_last_voter(metrics: &RaftMetrics, node_id: NodeId) -> bool {
+  let mut voters = metrics.membership_config.membership().voter_ids();
+  voters.next() == Some(node_id) && voters.next().is_none()
+}
+
+/// Picks the voter, other than the leader, that has replicated the most of the log.
+pub fn transfer_target(metrics: &RaftMetrics) -> Option<NodeId> {
+  let replication = metrics.replication.as_ref()?;
+  metrics
+    .membership_config
+    .membership()
+    .voter_ids()
+    .filter(|id| *id != metrics.id)
+    .max_by_key(|id| replication.get(id).cloned().flatten())
+}
+
+/// Moves the leadership of this node to `target`, or to the voter picked by [`transfer_target`],
+/// and waits up to `timeout` until another node is the leader.
+///
+/// Returns the new leader.
+pub async fn transfer_leadership(
+  raft: &Raft,
+  target: Option<NodeId>,
+  timeout: Duration,
+) -> Result<NodeId, MembershipError> {
+  let metrics = leader_metrics(raft).map_err(MembershipError::ForwardToLeader)?;
+
+  let target = match target {
+    Some(id) => {
+      let voter = metrics
+        .membership_config
+        .membership()
+        .voter_ids()
+        .any(|x| x == id);
+      if !voter || id == metrics.id {
+        return Err(MembershipError::NotVoter(id));
+      }
+      id
+    }
+    None => transfer_target(&metrics).ok_or(MembershipError::NoTransferTarget)?,
+  };
+
+  raft
+    .trigger()
+    .transfer_leader(target)
+    .await
+    .map_err(|e| MembershipError::Write(e.to_string()))?;
+
+  tokio::time::timeout(timeout, wait_for_other_leader(raft))
+    .await
+    .map_err(|_| MembershipError::TransferTimeout(timeout))?
+}
+
+/// Waits until this node knows another node as the leader, and returns it.
+async fn wait_for_other_leader(raft: &Raft) -> Result<NodeId, MembershipError> {
+  let mut metrics = raft.metrics();
+  loop {
+    {
+      let m = metrics.borrow();
+      if let Some(leader) = m.current_leader.filter(|x| *x != m.id) {
+        return Ok(leader);
+      }
+    }
+    if metrics.changed().await.is_err() {
+      return Err(MembershipError::Write(
+        "the node is shutting down".to_string(),
+      ));
+    }
+  }
+}
+
+/// The drain mode of the local node.
+///
+/// The leader does not know which nodes are drained, so it may still hand the leadership to one;
+/// the drained node then hands it on. The mode is not persisted: a restarted node stands for
+/// election again.
+pub struct Drain {
+  raft: Raft,
+  drained: watch::Sender<bool>,
+}
+
+impl Drain {
+  pub fn new(raft: Raft) -> Self {
+    let (drained, _) = watch::channel(false);
+    Self { raft, drained }
+  }
+
+  pub fn is_drained(&self) -> bool {
+    *self.drained.borrow()
+  }
+
+  /// Returns a receiver notified each time the node is drained or undrained.
+  pub fn subscribe(&self) -> watch::Receiver<bool> {
+    self.drained.subscribe()
+  }
+
+  /// Turns the drain mode on or off. A drained leader hands the leadership to another voter
+  /// before this returns; if it can not, the mode is left as it was.
+  pub async fn set(&self, drain: bool) -> Result<(), MembershipError> {
+    let was_drained = self.drained.send_replace(drain);
+    self.raft.runtime_config().elect(!drain);
+
+    if drain && self.raft.metrics().borrow().state == ServerState::Leader {
+      if let Err(e) = transfer_leadership(&self.raft, None, TRANSFER_TIMEOUT).await {
+        self.drained.send_replace(was_drained);
+        self.raft.runtime_config().elect(!was_drained);
+        return Err(e);
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Keeps the number of voters at a target while this node is the leader.
+///
+/// Learners are promoted once they have caught up with the log, and voters beyond the target are
+/// demoted to learners. A voter that has not answered the leader for longer than `down_timeout`
+/// is demoted too, in the same membership change that promotes a learner to replace it. The
+/// count is kept odd: an even number of voters needs a larger quorum without tolerating more
+/// failures.
+pub struct MembershipManager {
+  raft: Raft,
+
+  /// The number of voters to keep, odd; 0 leaves the membership to the operator.
+  voters: usize,
+
+  /// How often to check the membership.
+  check_interval: Duration,
+
+  /// How many logs a learner may lag behind the leader to be promoted.
+  max_lag: u64,
+
+  /// How long a voter may not answer the leader before it is replaced.
+  down_timeout: Duration,
+}
+
+impl MembershipManager {
+  pub fn new(
+    raft: Raft,
+    voters: usize,
+    check_interval: Duration,
+    max_lag: u64,
+    down_timeout: Duration,
+  ) -> Self {
+    Self {
+      raft,
+      voters,
+      check_interval,
+      max_lag,
+      down_timeout,
+    }
+  }
+
+  /// Changes the voters while this node is the leader, until the process exits.
+  pub async fn run(self) {
+    if self.voters == 0 {
+      return;
+    }
+    info!("Keeping {} voters", self.voters);
+
+    // The term in which this node became the leader, and when. A node that never answered is
+    // down once it has not for `down_timeout` since then.
+    let mut leading: Option<(u64, Instant)> = None;
+
+    let mut interval = tokio::time::interval(self.check_interval);
+    loop {
+      interval.tick().await;
+
+      let metrics = self.raft.metrics().borrow().clone();
+      if metrics.state != ServerState::Leader {
+        leading = None;
+        continue;
+      }
+      let since = match leading {
+        Some((term, since)) if term == metrics.current_term => since,
+        _ => {
+          let now = Instant::now();
+          leading = Some((metrics.current_term, now));
+          now
+        }
+      };
+
+      let Some(voters) = self.plan(&metrics, since.elapsed()) else {
+        continue;
+      };
+      info!("Changing the voters to {:?}", voters);
+      let changes = ChangeMembers::ReplaceAllVoters(voters);
+      if let Err(e) = self.raft.change_membership(changes, true).await {
+        warn!("Failed to change the voters: {}", e);
+      }
+    }
+  }
+
+  /// Returns the voters to change to, if they should change.
+  fn plan(&self, metrics: &RaftMetrics, leading_for: Duration) -> Option<BTreeSet<NodeId>> {
+    let membership = metrics.membership_config.membership();
+
+    // Let a change in progress complete first
+    if membership.get_joint_config().len() > 1 {
+      return None;
+    }
+
+    let heartbeat = metrics.heartbeat.as_ref()?;
+    let replication = metrics.replication.as_ref()?;
+    let silent_for = |id: &NodeId| match heartbeat.get(id) {
+      Some(Some(acked)) => acked.elapsed(),
+      _ => leading_for,
+    };
+
+    let down = membership
+      .voter_ids()
+      .filter(|id| *id != metrics.id && silent_for(id) > self.down_timeout)
+      .collect::<BTreeSet<_>>();
+
+    // The learners that can be promoted, the most up-to-date first
+    let last_index = metrics.last_log_index.unwrap_or_default();
+    let mut learners = membership
+      .learner_ids()
+      .filter_map(|id| {
+        let matched = replication.get(&id).cloned().flatten()?.index();
+        let caught_up =
+          matched + self.max_lag >= last_index && silent_for(&id) <= self.down_timeout;
+        caught_up.then_some((matched, id))
+      })
+      .collect::<Vec<_>>();
+    learners.sort_by(|a, b| b.cmp(a));
+    let learners = learners.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+
+    let voters = membership.voter_ids().collect::<BTreeSet<_>>();
+    let next = next_voters(&voters, &learners, &down, metrics.id, self.voters);
+    (next != voters).then_some(next)
+  }
+}
+
+/// Picks the voters that keep `target` of them: drops the voters that are `down`, promotes
+/// `learners` in their order, and demotes the voters beyond the target, the highest ids first and
+/// never the `leader`. Short of the target, an even count is avoided by promoting one learner
+/// less, or by keeping a voter that is down.
+fn next_voters(
+  voters: &BTreeSet<NodeId>,
+  learners: &[NodeId],
+  down: &BTreeSet<NodeId>,
+  leader: NodeId,
+  target: usize,
+) -> BTreeSet<NodeId> {
+  let mut next = voters - down;
+
+  let wanted = target.saturating_sub(next.len()).min(learners.len());
+  let mut promoted = learners[..wanted].to_vec();
+  let count = next.len() + promoted.len();
+  if count < target && count % 2 == 0 && promoted.pop().is_none() {
+    if let Some(id) = down.iter().next() {
+      next.insert(*id);
+    }
+  }
+  next.extend(promoted);
+
+  while next.len() > target {
+    let Some(id) = next.iter().rev().find(|id| **id != leader).copied() else {
+      break;
+    };
+    next.remove(&id);
+  }
+  next
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::sync::Arc;
+
+  use openraft::metrics::SerdeInstant;
+
+  use super::*;
+  use crate::protobuf as pb;
+  use crate::testing;
+
+  fn metrics(voters: &[NodeId], matched: &[(NodeId, u64)]) -> RaftMetrics {
+    joint_metrics(&[voters], &[], matched)
+  }
+
+  /// Returns the metrics of leader 1, with the voters of each config of `configs` and `learners`
+  /// as the members, and the last log index each node replicated in `matched`.
+  fn joint_metrics(
+    configs: &[&[NodeId]],
+    learners: &[NodeId],
+    matched: &[(NodeId, u64)],
+  ) -> RaftMetrics {
+    let membership = pb::Membership {
+      configs: configs
+        .iter()
+        .map(|voters| pb::NodeIdSet {
+          node_ids: voters.iter().map(|id| (*id, ())).collect(),
+        })
+        .collect(),
+      nodes: configs
+        .iter()
+        .flat_map(|voters| voters.iter())
+        .chain(learners)
+        .map(|id| {
+          let node = pb::Node {
+            node_id: *id,
+            rpc_addr: format!("127.0.0.1:{}", 5000 + id),
+          };
+          (*id, node)
+        })
+        .collect(),
+    };
+
+    let mut metrics = RaftMetrics::new_initial(1);
+    metrics.state = ServerState::Leader;
+    metrics.membership_config = Arc::new(StoredMembership::new(None, membership.into()));
+    metrics.replication = Some(
+      matched
+        .iter()
+        .map(|(id, index)| {
+          let log_id = pb::LogId {
+            term: 1,
+            index: *index,
+          };
+          (*id, Some(log_id.into()))
+        })
+        .collect::<BTreeMap<_, _>>(),
+    );
+    metrics
+  }
+
+  #[test]
+  fn test_transfer_target() {
+    // The most up-to-date voter other than the leader.
+    let m = metrics(&[1, 2, 3], &[(1, 9), (2, 5), (3, 7)]);
+    assert_eq!(transfer_target(&m), Some(3));
+
+    // Learners do not take over.
+    let m = metrics(&[1, 2], &[(1, 9), (2, 5), (3, 7)]);
+    assert_eq!(transfer_target(&m), Some(2));
+
+    // A single voter has no one to hand over to.
+    let m = metrics(&[1], &[(1, 9)]);
+    assert_eq!(transfer_target(&m), None);
+  }
+
+  #[test]
+  fn test_is_last_voter() {
+    assert!(is_last_voter(&metrics(&[1], &[]), 1));
+    assert!(!is_last_voter(&metrics(&[1, 2], &[]), 1));
+    assert!(!is_last_voter(&metrics(&[2], &[]), 1));
+  }
+
+  #[tokio::test]
+  async fn test_remove_node_on_leader() {
+    let nodes = testing::start_cluster(3).await;
+
+    remove_node(&nodes[0].raft, 3).await.unwrap();
+    let metrics = nodes[0].raft.metrics().borrow().clone();
+    let membership = metrics.membership_config.membership();
+    assert_eq!(membership.voter_ids().collect::<Vec<_>>(), vec![1, 2]);
+    assert!(membership.get_node(&3).is_none());
+
+    let res = remove_node(&nodes[0].raft, 3).await;
+    assert!(matches!(res, Err(MembershipError::NotMember(3))));
+  }
+
+  #[tokio::test]
+  async fn test_remove_node_on_follower() {
+    let nodes = testing::start_cluster(3).await;
+
+    // The follower names the leader to send the removal to, and leaves the membership as it is
+    match remove_node(&nodes[1].raft, 3).await {
+      Err(MembershipError::ForwardToLeader(to)) => {
+        assert_eq!(to.leader_id, Some(1));
+        assert_eq!(to.leader_node, Some(nodes[0].node()));
+      }
+      res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    let metrics = nodes[0].raft.metrics().borrow().clone();
+    let membership = metrics.membership_config.membership();
+    assert!(membership.get_node(&3).is_some());
+  }
+
+  #[tokio::test]
+  async fn test_remove_leader() {
+    let nodes = testing::start_cluster(3).await;
+
+    // The leader demotes itself, then sends the rest of the removal to the node taking over
+    let to = match remove_node(&nodes[0].raft, 1).await {
+      Err(MembershipError::ForwardToLeader(to)) => to,
+      res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    };
+    let leader = &nodes[to.leader_id.unwrap() as usize - 1];
+    assert_ne!(leader.id, 1);
+    assert_eq!(to.leader_node, Some(leader.node()));
+
+    remove_node(&leader.raft, 1).await.unwrap();
+    let metrics = leader.raft.metrics().borrow().clone();
+    let membership = metrics.membership_config.membership();
+    assert_eq!(membership.voter_ids().collect::<Vec<_>>(), vec![2, 3]);
+    assert!(membership.get_node(&1).is_none());
+  }
+
+  #[tokio::test]
+  async fn test_remove_last_voter() {
+    let nodes = testing::start_cluster(1).await;
+
+    let res = remove_node(&nodes[0].raft, 1).await;
+    assert!(matches!(res, Err(MembershipError::LastVoter(1))));
+  }
+
+  #[tokio::test]
+  async fn test_drain_leader() {
+    let nodes = testing::start_cluster(3).await;
+
+    nodes[0].drain.set(true).await.unwrap();
+    assert!(nodes[0].drain.is_drained());
+    let metrics = nodes[0].raft.metrics().borrow().clone();
+    assert_ne!(metrics.state, ServerState::Leader);
+    assert_ne!(metrics.current_leader, Some(1));
+
+    nodes[0].drain.set(false).await.unwrap();
+    assert!(!nodes[0].drain.is_drained());
+  }
+
+  #[tokio::test]
+  async fn test_drain_without_transfer_target() {
+    let nodes = testing::start_cluster(1).await;
+
+    // The only voter can not hand the leadership over, and is left undrained
+    let res = nodes[0].drain.set(true).await;
+    assert!(matches!(res, Err(MembershipError::NoTransferTarget)));
+    assert!(!nodes[0].drain.is_drained());
+    assert_eq!(nodes[0].raft.metrics().borrow().state, ServerState::Leader);
+  }
+
+  #[tokio::test]
+  async fn test_transfer_leadership() {
+    let nodes = testing::start_cluster(3).await;
+
+    let leader = transfer_leadership(&nodes[0].raft, Some(3), testing::TIMEOUT)
+      .await
+      .unwrap();
+    assert_eq!(leader, 3);
+    nodes[2].wait_for_leader(3).await;
+
+    // Only the leader hands the leadership over, and only to another voter
+    let res = transfer_leadership(&nodes[0].raft, None, testing::TIMEOUT).await;
+    assert!(matches!(res, Err(MembershipError::ForwardToLeader(_))));
+    let res = transfer_leadership(&nodes[2].raft, Some(3), testing::TIMEOUT).await;
+    assert!(matches!(res, Err(MembershipError::NotVoter(3))));
+    let res = transfer_leadership(&nodes[2].raft, Some(4), testing::TIMEOUT).await;
+    assert!(matches!(res, Err(MembershipError::NotVoter(4))));
+  }
+
+  const DOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+  /// Returns the metrics of leader 1 at log 100, with `voters` and `learners` as the members, the
+  /// last log index each node replicated in `matched`, and the nodes of `down` not heard from for
+  /// longer than the down timeout.
+  fn manager_metrics(
+    voters: &[NodeId],
+    learners: &[NodeId],
+    matched: &[(NodeId, u64)],
+    down: &[NodeId],
+  ) -> RaftMetrics {
+    let mut metrics = joint_metrics(&[voters], learners, matched);
+    metrics.last_log_index = Some(100);
+
+    let now = tokio::time::Instant::now();
+    metrics.heartbeat = Some(
+      voters
+        .iter()
+        .chain(learners)
+        .filter(|id| **id != 1)
+        .map(|id| {
+          let acked = if down.contains(id) {
+            now - DOWN_TIMEOUT * 2
+          } else {
+            now
+          };
+          (*id, Some(SerdeInstant::new(acked)))
+        })
+        .collect(),
+    );
+    metrics
+  }
+
+  #[tokio::test]
+  async fn test_plan() {
+    let node = testing::TestNode::start(1).await;
+    let manager = MembershipManager::new(
+      node.raft.clone(),
+      3,
+      Duration::from_secs(1),
+      10,
+      DOWN_TIMEOUT,
+    );
+    let plan = |metrics: &RaftMetrics| manager.plan(metrics, Duration::ZERO);
+    let set = |ids: &[NodeId]| ids.iter().copied().collect::<BTreeSet<_>>();
+
+    // Learners that have caught up are promoted.
+    let m = manager_metrics(&[1], &[2, 3], &[(2, 100), (3, 95)], &[]);
+    assert_eq!(plan(&m), Some(set(&[1, 2, 3])));
+
+    // A learner lagging behind is not, and the other one neither, which would leave two voters.
+    let m = manager_metrics(&[1], &[2, 3], &[(2, 100), (3, 50)], &[]);
+    assert_eq!(plan(&m), None);
+
+    // Neither is a learner that is down.
+    let m = manager_metrics(&[1], &[2, 3], &[(2, 100), (3, 100)], &[3]);
+    assert_eq!(plan(&m), None);
+
+    // A voter that is down is replaced by a learner.
+    let m = manager_metrics(&[1, 2, 3], &[4], &[(2, 100), (3, 100), (4, 100)], &[3]);
+    assert_eq!(plan(&m), Some(set(&[1, 2, 4])));
+
+    // Without a learner to replace it, it is kept rather than leaving two voters.
+    let m = manager_metrics(&[1, 2, 3], &[4], &[(2, 100), (3, 100), (4, 50)], &[3]);
+    assert_eq!(plan(&m), None);
+
+    // Voters beyond the target are demoted to an odd count.
+    let m = manager_metrics(&[1, 2, 3, 4], &[], &[(2, 100), (3, 100), (4, 100)], &[]);
+    assert_eq!(plan(&m), Some(set(&[1, 2, 3])));
+
+    // Nothing changes while a membership change is in progress.
+    let m = joint_metrics(&[&[1], &[1, 2, 3]], &[], &[(2, 100), (3, 100)]);
+    assert_eq!(plan(&m), None);
+  }
+
+  #[test]
+  fn test_next_voters() {
+    let set = |ids: &[NodeId]| ids.iter().copied().collect::<BTreeSet<_>>();
+
+    // Caught-up learners are promoted up to the target.
+    assert_eq!(
+      next_voters(&set(&[1]), &[3, 2, 4], &set(&[]), 1, 3),
+      set(&[1, 2, 3])
+    );
+
+    // Not to an even count short of the target.
+    assert_eq!(next_voters(&set(&[1]), &[2], &set(&[]), 1, 3), set(&[1]));
+    assert_eq!(
+      next_voters(&set(&[1, 2, 3]), &[4], &set(&[]), 1, 5),
+      set(&[1, 2, 3])
+    );
+
+    // Voters beyond the target are demoted, never the leader.
+    assert_eq!(
+      next_voters(&set(&[1, 2, 3, 4, 5]), &[], &set(&[]), 5, 3),
+      set(&[1, 2, 5])
+    );
+
+    // A voter that is down is replaced by a learner.
+    assert_eq!(
+      next_voters(&set(&[1, 2, 3]), &[4], &set(&[3]), 1, 3),
+      set(&[1, 2, 4])
+    );
+
+    // Without a learner to replace it, it is kept rather than leaving two voters.
+    assert_eq!(
+      next_voters(&set(&[1, 2, 3]), &[], &set(&[3]), 1, 3),
+      set(&[1, 2, 3])
+    );
+
+    // Two voters that are down out of five leave three.
+    assert_eq!(
+      next_voters(&set(&[1, 2, 3, 4, 5]), &[], &set(&[4, 5]), 1, 5),
+      set(&[1, 2, 3])
+    );
+  }
+}
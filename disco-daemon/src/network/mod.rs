@@ -1,22 +1,80 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::stream;
+use futures::StreamExt;
 use openraft::error::NetworkError;
+use openraft::error::RPCTypes;
+use openraft::error::ReplicationClosed;
+use openraft::error::Timeout;
 use openraft::error::Unreachable;
 use openraft::network::v2::RaftNetworkV2;
+use openraft::network::Backoff;
 use openraft::network::RPCOption;
 use openraft::AnyError;
 use openraft::RaftNetworkFactory;
-use tonic::codegen::tokio_stream::wrappers::ReceiverStream;
+use tokio::time::Instant;
 use tonic::transport::Channel;
+use tonic::transport::Endpoint;
+use tonic::Code;
+use tonic::Request;
+use tonic::Response;
+use tonic::Status;
 
 use crate::protobuf;
+use crate::protobuf::raft_service_client::RaftServiceClient;
 use crate::raft_types::*;
+use crate::store::SnapshotFile;
+use crate::tls;
+use crate::tls::TlsConfig;
 use crate::NodeId;
 use crate::TypeConfig;
 
+/// Size of the data chunks a snapshot is streamed in.
+const SNAPSHOT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The delay before retrying a node that could not be reached, doubled after each further
+/// failure up to `RECONNECT_BACKOFF_MAX`.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
 /// Network implementation for gRPC-based Raft communication.
 /// Provides the networking layer for Raft nodes to communicate with each other.
-pub struct Network {}
+///
+/// Connecting costs a TCP and an HTTP/2 handshake, more than the heartbeat it would be made for,
+/// so a channel is kept to each node and shared by all the connections to it. Channels
+/// multiplex their RPCs, so a snapshot being streamed does not hold back the heartbeats.
+#[derive(Clone)]
+pub struct Network {
+  /// The id of this node, which sends the RPCs.
+  id: NodeId,
+
+  /// The channel to each node, by id and address.
+  channels: Channels,
 
-impl Network {}
+  /// Connects to the other nodes over TLS if set, checking that each is the node it should be.
+  tls: Option<Arc<TlsConfig>>,
+
+  /// The largest message sent or accepted, which must fit the entries of an AppendEntries RPC.
+  max_message_size: usize,
+}
+
+type Channels = Arc<Mutex<HashMap<(NodeId, String), Channel>>>;
+
+impl Network {
+  pub fn new(id: NodeId, tls: Option<Arc<TlsConfig>>, max_message_size: usize) -> Self {
+    Self {
+      id,
+      channels: Arc::default(),
+      tls,
+      max_message_size,
+    }
+  }
+}
 
 /// Implementation of the RaftNetworkFactory trait for creating new network connections.
 /// This factory creates gRPC client connections to other Raft nodes.
@@ -25,20 +83,147 @@ impl RaftNetworkFactory<TypeConfig> for Network {
 
   #[tracing::instrument(level = "debug", skip_all)]
   async fn new_client(&mut self, _: NodeId, node: &Node) -> Self::Network {
-    NetworkConnection::new(node.clone())
+    NetworkConnection::new(
+      self.id,
+      node.clone(),
+      self.channels.clone(),
+      self.tls.clone(),
+      self.max_message_size,
+    )
   }
 }
 
 /// Represents an active network connection to a remote Raft node.
 /// Handles serialization and deserialization of Raft messages over gRPC.
 pub struct NetworkConnection {
+  id: NodeId,
   target_node: protobuf::Node,
+
+  /// The channels of the network, shared with the other connections.
+  channels: Channels,
+
+  tls: Option<Arc<TlsConfig>>,
+
+  max_message_size: usize,
 }
 
 impl NetworkConnection {
-  /// Creates a new NetworkConnection with the provided gRPC client.
-  pub fn new(target_node: Node) -> Self {
-    NetworkConnection { target_node }
+  /// Creates a new NetworkConnection from node `id` to `target_node`, using the channel to it in
+  /// `channels` or adding one on the first RPC.
+  pub fn new(
+    id: NodeId,
+    target_node: Node,
+    channels: Channels,
+    tls: Option<Arc<TlsConfig>>,
+    max_message_size: usize,
+  ) -> Self {
+    NetworkConnection {
+      id,
+      target_node,
+      channels,
+      tls,
+      max_message_size,
+    }
+  }
+
+  fn channel_key(&self) -> (NodeId, String) {
+    (self.target_node.node_id, self.target_node.rpc_addr.clone())
+  }
+
+  /// Returns a client of the target node, connecting to it if there is no channel to it yet.
+  /// Connecting fails if it takes longer than `timeout`.
+  async fn client(&self, timeout: Duration) -> Result<RaftServiceClient<Channel>, Unreachable> {
+    let addr = &self.target_node.rpc_addr;
+    let pooled = self
+      .channels
+      .lock()
+      .unwrap()
+      .get(&self.channel_key())
+      .cloned();
+    if let Some(channel) = pooled {
+      return Ok(self.raft_client(channel));
+    }
+
+    // An invalid address is treated like a node that is down: it is retried with backoff, and
+    // fixed by a membership change replacing the node.
+    let endpoint = Endpoint::from_shared(format!("http://{}", addr))
+      .map_err(|e| Unreachable::new(&e))?
+      .tcp_nodelay(true)
+      .connect_timeout(timeout);
+    let channel = tls::connect(endpoint, self.tls.as_ref(), Some(self.target_node.node_id))
+      .await
+      .map_err(|e| Unreachable::new(&e))?;
+
+    self
+      .channels
+      .lock()
+      .unwrap()
+      .insert(self.channel_key(), channel.clone());
+    Ok(self.raft_client(channel))
+  }
+
+  fn raft_client(&self, channel: Channel) -> RaftServiceClient<Channel> {
+    RaftServiceClient::new(channel)
+      .max_decoding_message_size(self.max_message_size)
+      .max_encoding_message_size(self.max_message_size)
+  }
+
+  /// Sends an RPC with `send`, failing with a timeout if it is not answered within the hard ttl
+  /// of `option`.
+  ///
+  /// The deadline is sent along with the request too, so that the target node gives up on it as
+  /// well.
+  async fn call<Req, Res, F, Fut>(
+    &self,
+    action: RPCTypes,
+    option: &RPCOption,
+    req: Req,
+    send: F,
+  ) -> Result<Res, RPCError>
+  where
+    F: FnOnce(RaftServiceClient<Channel>, Request<Req>) -> Fut,
+    Fut: Future<Output = Result<Response<Res>, Status>>,
+  {
+    let ttl = option.hard_ttl();
+    let deadline = Instant::now() + ttl;
+
+    let client = tokio::time::timeout_at(deadline, self.client(ttl))
+      .await
+      .map_err(|_| self.timeout_error(action, ttl))?
+      .map_err(RPCError::Unreachable)?;
+
+    let mut request = Request::new(req);
+    request.set_timeout(deadline.saturating_duration_since(Instant::now()));
+
+    match tokio::time::timeout_at(deadline, send(client, request)).await {
+      Ok(Ok(response)) => Ok(response.into_inner()),
+      Ok(Err(status)) => Err(self.rpc_error(action, ttl, status)),
+      Err(_) => Err(self.timeout_error(action, ttl)),
+    }
+  }
+
+  fn timeout_error(&self, action: RPCTypes, timeout: Duration) -> RPCError {
+    RPCError::Timeout(Timeout {
+      action,
+      id: self.id,
+      target: self.target_node.node_id,
+      timeout,
+    })
+  }
+
+  /// Converts the status of a failed RPC into an error.
+  ///
+  /// If the node could not be reached, its channel is dropped so that the next RPC, made after
+  /// the backoff, connects anew.
+  fn rpc_error(&self, action: RPCTypes, timeout: Duration, status: Status) -> RPCError {
+    match status.code() {
+      Code::DeadlineExceeded => self.timeout_error(action, timeout),
+      Code::Unavailable => {
+        self.channels.lock().unwrap().remove(&self.channel_key());
+        RPCError::Unreachable(Unreachable::new(&status))
+      }
+      _ => RPCError::Network(NetworkError::new(&status)),
+    }
   }
 }
 
@@ -48,25 +233,16 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
   async fn append_entries(
     &mut self,
     req: AppendEntriesRequest,
-    _option: RPCOption,
+    option: RPCOption,
   ) -> Result<AppendEntriesResponse, RPCError> {
-    let server_addr = self.target_node.rpc_addr.clone();
-    let channel = match Channel::builder(format!("http://{}", server_addr).parse().unwrap())
-      .connect()
-      .await
-    {
-      Ok(channel) => channel,
-      Err(e) => {
-        return Err(RPCError::Unreachable(Unreachable::new(&e)));
-      }
-    };
-    let mut client = protobuf::raft_service_client::RaftServiceClient::new(channel);
-
-    let response = client
-      .append_entries(protobuf::AppendEntriesRequest::from(req))
-      .await
-      .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
-    let response = response.into_inner();
+    let response = self
+      .call(
+        RPCTypes::AppendEntries,
+        &option,
+        protobuf::AppendEntriesRequest::from(req),
+        |mut c, r| async move { c.append_entries(r).await },
+      )
+      .await?;
     Ok(AppendEntriesResponse::from(response))
   }
 
@@ -74,35 +250,26 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
     &mut self,
     vote: Vote,
     snapshot: Snapshot,
-    _cancel: impl std::future::Future<Output = openraft::error::ReplicationClosed>
-      + openraft::OptionalSend
-      + 'static,
-    _option: RPCOption,
+    cancel: impl std::future::Future<Output = ReplicationClosed> + openraft::OptionalSend + 'static,
+    option: RPCOption,
   ) -> Result<SnapshotResponse, crate::raft_types::StreamingError> {
-    let server_addr = self.target_node.rpc_addr.clone();
-    let channel = match Channel::builder(format!("http://{}", server_addr).parse().unwrap())
-      .connect()
-      .await
-    {
-      Ok(channel) => channel,
-      Err(e) => {
-        return Err(RPCError::Unreachable(Unreachable::new(&e)).into());
-      }
-    };
+    // A snapshot can take much longer to send than any ttl, so the hard ttl bounds each step
+    // instead: connecting, sending each chunk, and installing the snapshot once it is sent.
+    let ttl = option.hard_ttl();
+    let action = RPCTypes::InstallSnapshot;
 
-    let (tx, rx) = tokio::sync::mpsc::channel(1024);
-    let strm = ReceiverStream::new(rx);
+    let mut client = tokio::time::timeout(ttl, self.client(ttl))
+      .await
+      .map_err(|_| self.timeout_error(action, ttl))?
+      .map_err(RPCError::Unreachable)?;
 
-    let mut client = protobuf::raft_service_client::RaftServiceClient::new(channel);
-    let response = client
-      .snapshot(strm)
+    let meta = &snapshot.meta;
+    let (data, checksum) = checksum(snapshot.snapshot)
       .await
       .map_err(|e| NetworkError::new(&e))?;
 
     // 1. Send meta chunk
 
-    let meta = &snapshot.meta;
-
     let request = protobuf::SnapshotRequest {
       payload: Some(protobuf::snapshot_request::Payload::Meta(
         protobuf::SnapshotRequestMeta {
@@ -111,25 +278,59 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
           last_membership_log_id: meta.last_membership.log_id().map(|log_id| log_id.into()),
           last_membership: Some(meta.last_membership.membership().clone().into()),
           snapshot_id: meta.snapshot_id.to_string(),
+          checksum,
         },
       )),
     };
 
-    tx.send(request).await.map_err(|e| NetworkError::new(&e))?;
+    // 2. Send data chunks, read from the snapshot file only as the stream is polled, that is
+    // once the previous chunk is sent. Each poll counts as progress.
 
-    // 2. Send data chunks
+    let progress = Arc::new(Mutex::new(Instant::now()));
+    let state = (data, progress.clone());
+    let snapshot_id = meta.snapshot_id.clone();
+    let chunks = stream::unfold(state, move |(data, progress): (SnapshotFile, _)| {
+      let snapshot_id = snapshot_id.clone();
+      async move {
+        *progress.lock().unwrap() = Instant::now();
+        match read_chunk(data).await {
+          Ok((_, chunk)) if chunk.is_empty() => None,
+          Ok((data, chunk)) => {
+            let request = protobuf::SnapshotRequest {
+              payload: Some(protobuf::snapshot_request::Payload::Chunk(chunk)),
+            };
+            Some((request, (data, progress)))
+          }
+          Err(e) => {
+            // Ending the stream early makes the receiver reject the snapshot on checksum mismatch.
+            tracing::error!("failed to read snapshot {}: {}", snapshot_id, e);
+            None
+          }
+        }
+      }
+    });
 
-    let chunk_size = 1024 * 1024;
-    for chunk in snapshot.snapshot.chunks(chunk_size) {
-      let request = protobuf::SnapshotRequest {
-        payload: Some(protobuf::snapshot_request::Payload::Chunk(chunk.to_vec())),
-      };
-      tx.send(request).await.map_err(|e| NetworkError::new(&e))?;
-    }
+    // Resolves once no progress has been made for a whole ttl.
+    let stalled = async {
+      loop {
+        let deadline = *progress.lock().unwrap() + ttl;
+        if Instant::now() >= deadline {
+          break;
+        }
+        tokio::time::sleep_until(deadline).await;
+      }
+    };
 
-    // 3. receive response
+    // 3. receive response, unless the replication is closed or the target stalls first.
+    // Dropping the RPC resets its stream, so the target discards what it received.
 
-    let message = response.into_inner();
+    let message = tokio::select! {
+      response = client.snapshot(stream::once(async { request }).chain(chunks)) => {
+        response.map_err(|e| self.rpc_error(action, ttl, e))?.into_inner()
+      }
+      closed = cancel => return Err(StreamingError::Closed(closed)),
+      _ = stalled => return Err(self.timeout_error(action, ttl).into()),
+    };
 
     Ok(SnapshotResponse {
       vote: message.vote.ok_or_else(|| {
@@ -138,33 +339,224 @@ impl RaftNetworkV2<TypeConfig> for NetworkConnection {
     })
   }
 
-  async fn vote(&mut self, req: VoteRequest, _option: RPCOption) -> Result<VoteResponse, RPCError> {
-    let server_addr = self.target_node.rpc_addr.clone();
-    let channel = match Channel::builder(format!("http://{}", server_addr).parse().unwrap())
-      .connect()
-      .await
-    {
-      Ok(channel) => channel,
-      Err(e) => {
-        return Err(RPCError::Unreachable(Unreachable::new(&e)));
-      }
-    };
-    let mut client = protobuf::raft_service_client::RaftServiceClient::new(channel);
-
+  async fn vote(&mut self, req: VoteRequest, option: RPCOption) -> Result<VoteResponse, RPCError> {
     // Convert the openraft VoteRequest to protobuf VoteRequest
     let proto_vote_req: protobuf::VoteRequest = req.into();
 
-    // Create a tonic Request with the protobuf VoteRequest
-    let request = tonic::Request::new(proto_vote_req);
-
     // Send the vote request
-    let response = client
-      .vote(request)
-      .await
-      .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+    let proto_vote_resp = self
+      .call(
+        RPCTypes::Vote,
+        &option,
+        proto_vote_req,
+        |mut c, r| async move { c.vote(r).await },
+      )
+      .await?;
 
     // Convert the response back to openraft VoteResponse
-    let proto_vote_resp: protobuf::VoteResponse = response.into_inner();
     Ok(proto_vote_resp.into())
   }
+
+  /// Backs off exponentially while the target node cannot be reached, rather than retrying it at
+  /// a fixed rate.
+  fn backoff(&self) -> Backoff {
+    Backoff::new(std::iter::successors(
+      Some(RECONNECT_BACKOFF_MIN),
+      |delay| Some((*delay * 2).min(RECONNECT_BACKOFF_MAX)),
+    ))
+  }
+}
+
+/// Computes the checksum of a snapshot on a blocking thread.
+async fn checksum(mut data: SnapshotFile) -> io::Result<(SnapshotFile, u32)> {
+  tokio::task::spawn_blocking(move || {
+    let checksum = data.checksum()?;
+    Ok((data, checksum))
+  })
+  .await
+  .map_err(io::Error::other)?
+}
+
+/// Reads the next chunk of a snapshot on a blocking thread. The chunk is empty at the end.
+async fn read_chunk(mut data: SnapshotFile) -> io::Result<(SnapshotFile, Vec<u8>)> {
+  tokio::task::spawn_blocking(move || {
+    let chunk = data.read_chunk(SNAPSHOT_CHUNK_SIZE)?;
+    Ok((data, chunk))
+  })
+  .await
+  .map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+  use tempfile::TempDir;
+  use tokio::net::TcpListener;
+  use tokio::sync::mpsc;
+  use tonic::transport::server::TcpIncoming;
+  use tonic::transport::Server;
+  use tonic::Streaming;
+
+  use super::*;
+  use crate::protobuf::raft_service_server::RaftService;
+  use crate::protobuf::raft_service_server::RaftServiceServer;
+  use crate::testing;
+
+  /// A node that accepts the RPCs but never answers them. It only reads the first message of a
+  /// snapshot, so that the stream of the data stalls once the window of the stream is full.
+  struct Unresponsive {
+    /// Told when a snapshot RPC is received.
+    started: mpsc::UnboundedSender<()>,
+
+    /// Told when the snapshot RPC is dropped, as it is when the sender resets the stream.
+    dropped: mpsc::UnboundedSender<()>,
+  }
+
+  struct DropGuard(mpsc::UnboundedSender<()>);
+
+  impl Drop for DropGuard {
+    fn drop(&mut self) {
+      let _ = self.0.send(());
+    }
+  }
+
+  #[tonic::async_trait]
+  impl RaftService for Unresponsive {
+    async fn vote(
+      &self,
+      _: Request<protobuf::VoteRequest>,
+    ) -> Result<Response<protobuf::VoteResponse>, Status> {
+      std::future::pending().await
+    }
+
+    async fn append_entries(
+      &self,
+      _: Request<protobuf::AppendEntriesRequest>,
+    ) -> Result<Response<protobuf::AppendEntriesResponse>, Status> {
+      std::future::pending().await
+    }
+
+    async fn snapshot(
+      &self,
+      request: Request<Streaming<protobuf::SnapshotRequest>>,
+    ) -> Result<Response<protobuf::SnapshotResponse>, Status> {
+      let _guard = DropGuard(self.dropped.clone());
+      request.into_inner().message().await?;
+      let _ = self.started.send(());
+      std::future::pending().await
+    }
+  }
+
+  /// Starts an unresponsive node 2, and returns a connection of node 1 to it, with the receivers
+  /// told when the node receives and drops a snapshot RPC.
+  async fn connect_unresponsive() -> (
+    NetworkConnection,
+    mpsc::UnboundedReceiver<()>,
+    mpsc::UnboundedReceiver<()>,
+  ) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = protobuf::Node {
+      node_id: 2,
+      rpc_addr: listener.local_addr().unwrap().to_string(),
+    };
+
+    let (started, started_rx) = mpsc::unbounded_channel();
+    let (dropped, dropped_rx) = mpsc::unbounded_channel();
+    let router =
+      Server::builder().add_service(RaftServiceServer::new(Unresponsive { started, dropped }));
+    let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+    tokio::spawn(router.serve_with_incoming(incoming));
+
+    let connection = NetworkConnection::new(1, target, Channels::default(), None, 4 << 20);
+    (connection, started_rx, dropped_rx)
+  }
+
+  /// Returns a snapshot of `len` bytes, too large to be sent before the stream stalls.
+  fn snapshot(dir: &TempDir, len: usize) -> Snapshot {
+    let mut file = SnapshotFile::create(dir.path().join("snapshot")).unwrap();
+    std::io::Write::write_all(&mut file, &vec![0; len]).unwrap();
+    Snapshot {
+      meta: SnapshotMeta {
+        last_log_id: None,
+        last_membership: StoredMembership::default(),
+        snapshot_id: "1-1-1".to_string(),
+      },
+      snapshot: file,
+    }
+  }
+
+  #[tokio::test]
+  async fn test_unanswered_rpc_times_out() {
+    let (mut connection, _, _) = connect_unresponsive().await;
+
+    let req = VoteRequest::new(protobuf::Vote::default(), None);
+    let option = RPCOption::new(Duration::from_millis(200));
+    let res = connection.vote(req, option).await;
+    assert!(matches!(res, Err(RPCError::Timeout(_))), "{:?}", res);
+  }
+
+  #[tokio::test]
+  async fn test_rpc_error() {
+    let (connection, _, _) = connect_unresponsive().await;
+    let ttl = Duration::from_secs(1);
+
+    let status = Status::deadline_exceeded("deadline exceeded");
+    let err = connection.rpc_error(RPCTypes::Vote, ttl, status);
+    assert!(matches!(err, RPCError::Timeout(_)), "{:?}", err);
+
+    // The channel to a node that cannot be reached is dropped.
+    connection.client(ttl).await.unwrap();
+    assert_eq!(connection.channels.lock().unwrap().len(), 1);
+    let err = connection.rpc_error(RPCTypes::Vote, ttl, Status::unavailable("unavailable"));
+    assert!(matches!(err, RPCError::Unreachable(_)), "{:?}", err);
+    assert!(connection.channels.lock().unwrap().is_empty());
+
+    let err = connection.rpc_error(RPCTypes::Vote, ttl, Status::internal("internal"));
+    assert!(matches!(err, RPCError::Network(_)), "{:?}", err);
+  }
+
+  #[tokio::test]
+  async fn test_stalled_snapshot_is_aborted() {
+    let (mut connection, _, mut dropped) = connect_unresponsive().await;
+    let dir = TempDir::new().unwrap();
+
+    let res = connection
+      .full_snapshot(
+        protobuf::Vote::default(),
+        snapshot(&dir, 16 << 20),
+        std::future::pending(),
+        RPCOption::new(Duration::from_millis(500)),
+      )
+      .await;
+    assert!(matches!(res, Err(StreamingError::Timeout(_))), "{:?}", res);
+
+    tokio::time::timeout(testing::TIMEOUT, dropped.recv())
+      .await
+      .unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_closed_replication_aborts_snapshot() {
+    let (mut connection, mut started, mut dropped) = connect_unresponsive().await;
+    let dir = TempDir::new().unwrap();
+
+    // The replication is closed while the snapshot is being sent
+    let closed = async move {
+      started.recv().await;
+      ReplicationClosed::new("replication stopped")
+    };
+
+    let res = connection
+      .full_snapshot(
+        protobuf::Vote::default(),
+        snapshot(&dir, 16 << 20),
+        closed,
+        RPCOption::new(testing::TIMEOUT),
+      )
+      .await;
+    assert!(matches!(res, Err(StreamingError::Closed(_))), "{:?}", res);
+
+    tokio::time::timeout(testing::TIMEOUT, dropped.recv())
+      .await
+      .unwrap();
+  }
 }
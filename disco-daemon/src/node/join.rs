@@ -0,0 +1,190 @@
+//! Joining a running cluster through the address of any of its members.
+//!
+//! The joining node asks the member to add it as a learner. The request is forwarded to the
+//! leader, which answers once it has replicated its log to the node. The node then waits until
+//! it has applied the entry that added it, and asks to be promoted to voter. When the cluster
+//! keeps a number of voters on its own, the node stays a learner instead, and the leader promotes
+//! it if a voter is needed.
+//!
+//! Each step is a no-op if it was already done, so a node that restarts while joining goes
+//! through the steps again, and a node that already is a voter does not contact the cluster.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tonic::transport::Endpoint;
+use tracing::info;
+use tracing::warn;
+
+use crate::protobuf;
+use crate::protobuf::app_service_client::AppServiceClient;
+use crate::raft_types::Raft;
+use crate::tls;
+use crate::tls::TlsConfig;
+use crate::NodeId;
+
+/// The delay before trying to join again after a failure, doubled after each further failure up
+/// to `RETRY_MAX`.
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// How long the node may take to apply the entry that added it as a learner.
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(600);
+
+type JoinError = Box<dyn Error + Send + Sync>;
+
+/// Joins `node` to the cluster of the member at `seed`, retrying until it is a voter, or only a
+/// learner unless `promote` is set.
+pub(super) async fn join(
+  raft: Raft,
+  node: protobuf::Node,
+  seed: String,
+  tls: Option<Arc<TlsConfig>>,
+  promote: bool,
+) {
+  let mut delay = RETRY_MIN;
+  loop {
+    match try_join(&raft, &node, &seed, tls.as_ref(), promote).await {
+      Ok(()) => {
+        info!("Node {} is a member of the cluster", node.node_id);
+        return;
+      }
+      Err(e) => warn!(
+        "Failed to join the cluster through {}: {}; retrying in {:?}",
+        seed, e, delay
+      ),
+    }
+
+    tokio::time::sleep(delay).await;
+    delay = (delay * 2).min(RETRY_MAX);
+  }
+}
+
+async fn try_join(
+  raft: &Raft,
+  node: &protobuf::Node,
+  seed: &str,
+  tls: Option<&Arc<TlsConfig>>,
+  promote: bool,
+) -> Result<(), JoinError> {
+  if is_voter(raft, node.node_id) {
+    return Ok(());
+  }
+
+  // Any member can answer: the certificate of the seed is not checked against a node id.
+  let endpoint = Endpoint::from_shared(format!("http://{}", seed))?;
+  let mut client = AppServiceClient::new(tls::connect(endpoint, tls, None).await?);
+
+  info!("Adding node {} to the cluster as a learner", node.node_id);
+  let added = client
+    .add_learner(protobuf::AddLearnerRequest {
+      node: Some(node.clone()),
+    })
+    .await?
+    .into_inner();
+
+  let index = added.log_id.map(|x| x.index).unwrap_or_default();
+  info!("Waiting for node {} to apply log {}", node.node_id, index);
+  wait_applied(raft, index).await?;
+
+  if !promote {
+    info!(
+      "Node {} is a learner, the leader promotes it if a voter is needed",
+      node.node_id
+    );
+    return Ok(());
+  }
+
+  info!("Promoting node {} to voter", node.node_id);
+  let promoted = client
+    .change_membership(protobuf::ChangeMembershipRequest {
+      members: vec![node.node_id],
+      retain: true,
+      add: true,
+    })
+    .await?
+    .into_inner();
+
+  match promoted.membership {
+    Some(membership) if has_voter(&membership, node.node_id) => Ok(()),
+    _ => Err("the node is not a voter after its promotion".into()),
+  }
+}
+
+/// Returns whether the local membership already makes `node_id` a voter.
+fn is_voter(raft: &Raft, node_id: NodeId) -> bool {
+  raft
+    .metrics()
+    .borrow()
+    .membership_config
+    .membership()
+    .voter_ids()
+    .any(|x| x == node_id)
+}
+
+/// Returns whether `membership` makes `node_id` a voter. While the membership is joint, the node
+/// must be a voter of every config, since the change may still be rolled back to the old one.
+fn has_voter(membership: &protobuf::Membership, node_id: NodeId) -> bool {
+  !membership.configs.is_empty()
+    && membership
+      .configs
+      .iter()
+      .all(|x| x.node_ids.contains_key(&node_id))
+}
+
+/// Waits until the local state machine has applied the log up to `index`.
+async fn wait_applied(raft: &Raft, index: u64) -> Result<(), JoinError> {
+  let mut metrics = raft.metrics();
+  let caught_up = async {
+    loop {
+      if metrics.borrow().last_applied.map(|x| x.index) >= Some(index) {
+        return Ok(());
+      }
+      if metrics.changed().await.is_err() {
+        return Err("the node is shutting down".into());
+      }
+    }
+  };
+
+  tokio::time::timeout(CATCH_UP_TIMEOUT, caught_up)
+    .await
+    .map_err(|_| format!("log {} was not applied in {:?}", index, CATCH_UP_TIMEOUT))?
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn config(ids: &[NodeId]) -> protobuf::NodeIdSet {
+    protobuf::NodeIdSet {
+      node_ids: ids.iter().map(|id| (*id, ())).collect(),
+    }
+  }
+
+  #[test]
+  fn test_has_voter() {
+    let membership = |configs| protobuf::Membership {
+      configs,
+      nodes: Default::default(),
+    };
+
+    let uniform = membership(vec![config(&[1, 2, 3])]);
+    assert!(has_voter(&uniform, 3));
+    assert!(!has_voter(&uniform, 4));
+    assert!(!has_voter(&membership(Vec::new()), 1));
+  }
+
+  #[test]
+  fn test_has_voter_in_joint_membership() {
+    let joint = protobuf::Membership {
+      configs: vec![config(&[1, 2]), config(&[1, 2, 3])],
+      nodes: Default::default(),
+    };
+
+    // Node 3 is only a voter of the new config until the change completes
+    assert!(has_voter(&joint, 1));
+    assert!(!has_voter(&joint, 3));
+    assert!(!has_voter(&joint, 4));
+  }
+}
@@ -1,25 +1,56 @@
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing::info;
+use tracing::warn;
 
 use openraft::Config;
 use openraft::ServerState;
+use openraft::SnapshotPolicy;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio::sync::Mutex;
 use tonic::transport::Server;
+use tonic::Code;
 
 use crate::controller::Controller;
+use crate::controller::NodeCoordinator;
 use crate::grpc::app_service::AppServiceImpl;
+use crate::grpc::forward;
+use crate::grpc::limits;
+use crate::grpc::limits::RequestLimits;
 use crate::grpc::raft_service::RaftServiceImpl;
+use crate::lease::LeaseManager;
+use crate::lock::Locks;
+use crate::membership;
+use crate::membership::Drain;
+use crate::membership::MembershipError;
+use crate::membership::MembershipManager;
+use crate::membership::TRANSFER_TIMEOUT;
 use crate::network::Network;
 use crate::protobuf;
 use crate::raft_types::Raft;
 use crate::settings::Settings;
+use crate::store::DirLock;
 use crate::store::LogStore;
 use crate::store::StateMachineStore;
+use crate::tls;
+use crate::tls::TlsConfig;
 
+use super::join;
 use super::runtime;
 
 pub type NodeId = u64;
 
+/// How many times a node that shuts down tries to remove itself from the membership, and the
+/// delay between the attempts, while the new leader is elected.
+const LEAVE_ATTEMPTS: u32 = 5;
+const LEAVE_RETRY: Duration = Duration::from_millis(500);
+
+/// How long the server may take to answer the requests in flight once it stops accepting
+/// connections.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Node {
   inner: Arc<NodeInner>, // Removed RwLock
 }
@@ -31,17 +62,39 @@ pub struct NodeInner {
   state_machine_store: Arc<StateMachineStore>,
   settings: Settings,
 
+  // set if the traffic of the node is encrypted
+  tls: Option<Arc<TlsConfig>>,
+
+  // lease expiry and distributed locks, handled by this node while it is the leader
+  lease_manager: Arc<LeaseManager>,
+  locks: Arc<Locks>,
+
+  // while drained, the node does not stand for election nor run the controller
+  drain: Arc<Drain>,
+
   // controller is started and stopped based on raft leader status
   controller: Arc<Mutex<Option<Controller>>>,
+
+  // held while the node runs, so that no other process writes to its data directory
+  _dir_lock: DirLock,
 }
 
 impl Node {
-  pub async fn new(node_id: NodeId, addr: String, settings: Settings) -> Node {
-    let log_store = LogStore::default();
-    let state_machine_store = Arc::new(StateMachineStore::default());
+  pub async fn new(
+    node_id: NodeId,
+    addr: String,
+    data_dir: PathBuf,
+    settings: Settings,
+  ) -> Result<Node, Box<dyn std::error::Error>> {
+    let dir_lock = DirLock::lock(&data_dir)?;
+    let log_store = LogStore::open(data_dir.join("log"))?;
+    let state_machine_store = Arc::new(StateMachineStore::open(data_dir.join("snapshot"))?);
+
+    let tls = settings.tls.as_ref().map(TlsConfig::node).transpose()?;
 
     // Create the network layer
-    let network = Network {};
+    let max_message_size = RequestLimits::new(&settings).max_message_size();
+    let network = Network::new(node_id, tls.clone(), max_message_size);
 
     let config: Config = Config {
       cluster_name: settings.cluster_name.clone(),
@@ -49,6 +102,13 @@ impl Node {
       election_timeout_max: settings.election_timeout_max,
       heartbeat_interval: settings.heartbeat_interval,
       install_snapshot_timeout: settings.install_snapshot_timeout,
+      snapshot_policy: match settings.snapshot_logs_since_last {
+        0 => SnapshotPolicy::Never,
+        n => SnapshotPolicy::LogsSinceLast(n),
+      },
+      max_in_snapshot_log_to_keep: settings.max_in_snapshot_log_to_keep,
+      purge_batch_size: settings.purge_batch_size,
+      max_payload_entries: limits::MAX_PAYLOAD_ENTRIES,
       ..Default::default()
     }
     .validate()
@@ -65,18 +125,48 @@ impl Node {
     .await
     .unwrap();
 
+    let lease_manager = Arc::new(LeaseManager::new(
+      raft.clone(),
+      state_machine_store.clone(),
+      Duration::from_millis(settings.lease_check_interval),
+    ));
+    let locks = Arc::new(Locks::new(raft.clone(), state_machine_store.clone()));
+    let drain = Arc::new(Drain::new(raft.clone()));
+
     let node_inner = NodeInner {
       node_id,
       addr,
       raft,
       state_machine_store,
       settings,
+      tls,
+      lease_manager,
+      locks,
+      drain,
       controller: Arc::new(Mutex::new(None)),
+      _dir_lock: dir_lock,
     };
 
-    Node {
+    Ok(Node {
       inner: Arc::new(node_inner),
-    }
+    })
+  }
+
+  /// Makes the node join the cluster of the member at `seed` once it runs, unless it already is a
+  /// voter of a cluster. The node is only promoted to voter by itself if the leader does not keep
+  /// the number of voters.
+  pub fn join(&self, seed: String) {
+    let node = protobuf::Node {
+      node_id: self.inner.node_id,
+      rpc_addr: self.inner.addr.clone(),
+    };
+    runtime::spawn(join::join(
+      self.inner.raft.clone(),
+      node,
+      seed,
+      self.inner.tls.clone(),
+      self.inner.settings.voters == 0,
+    ));
   }
 
   pub async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
@@ -85,6 +175,19 @@ impl Node {
     // Spawn the leader election monitor
     runtime::spawn(Self::monitor_leader_election(inner_arc.clone()));
 
+    // Spawn the lease expiry monitor, which only acts on the leader
+    runtime::spawn(inner_arc.lease_manager.clone().run());
+
+    // Spawn the membership manager, which only acts on the leader
+    let membership_manager = MembershipManager::new(
+      inner_arc.raft.clone(),
+      inner_arc.settings.voters,
+      Duration::from_millis(inner_arc.settings.membership_check_interval),
+      inner_arc.settings.learner_max_lag,
+      Duration::from_secs(inner_arc.settings.voter_down_timeout),
+    );
+    runtime::spawn(membership_manager.run());
+
     // Now we can directly use the inner fields without any locking
     info!(
       "Node {} starting server at {}",
@@ -92,91 +195,282 @@ impl Node {
     );
 
     // Create the services
-    let internal_service = RaftServiceImpl::new(inner_arc.raft.clone());
+    let internal_service = RaftServiceImpl::new(
+      inner_arc.raft.clone(),
+      inner_arc.state_machine_store.clone(),
+    );
+    let limits = RequestLimits::new(&inner_arc.settings);
     let api_service = AppServiceImpl::new(
       inner_arc.raft.clone(),
       inner_arc.state_machine_store.clone(),
+      inner_arc.lease_manager.clone(),
+      inner_arc.locks.clone(),
+      limits,
+      inner_arc.tls.clone(),
+      inner_arc.drain.clone(),
     );
 
     // Start and await the server
-    Server::builder()
-      .add_service(protobuf::raft_service_server::RaftServiceServer::new(
-        internal_service,
-      ))
-      .add_service(protobuf::app_service_server::AppServiceServer::new(
-        api_service,
-      ))
-      .serve(inner_arc.addr.parse()?)
-      .await?;
+    let router = Server::builder()
+      .add_service(
+        protobuf::raft_service_server::RaftServiceServer::new(internal_service)
+          .max_decoding_message_size(limits.max_message_size())
+          .max_encoding_message_size(limits.max_message_size()),
+      )
+      .add_service(
+        protobuf::app_service_server::AppServiceServer::new(api_service)
+          .max_decoding_message_size(limits.max_message_size())
+          .max_encoding_message_size(limits.max_message_size()),
+      );
+
+    // The server stops accepting connections once `drain` resolves, and returns once the
+    // requests in flight are answered
+    let (drain_tx, drain_rx) = oneshot::channel::<()>();
+    let drain = async {
+      drain_rx.await.ok();
+    };
+    let server = async {
+      match (&inner_arc.tls, inner_arc.settings.tls.as_ref()) {
+        (Some(tls), Some(settings)) => {
+          // Pick up rotated certificates, unless the reload interval is 0
+          runtime::spawn(
+            tls
+              .clone()
+              .watch(Duration::from_secs(settings.reload_interval)),
+          );
+
+          let listener = tokio::net::TcpListener::bind(&inner_arc.addr).await?;
+          router
+            .serve_with_incoming_shutdown(tls::incoming(listener, tls), drain)
+            .await?;
+        }
+        _ => {
+          router
+            .serve_with_shutdown(inner_arc.addr.parse()?, drain)
+            .await?
+        }
+      }
+      Ok::<(), Box<dyn std::error::Error>>(())
+    };
+    tokio::pin!(server);
+
+    tokio::select! {
+      result = &mut server => return result,
+      _ = shutdown_signal() => {}
+    }
 
+    info!("Node {} shutting down", inner_arc.node_id);
+    inner_arc.leave().await;
+
+    // The server keeps answering while the node leaves, so that the leadership transfer and the
+    // membership changes can reach it
+    let _ = drain_tx.send(());
+    match tokio::time::timeout(DRAIN_TIMEOUT, server).await {
+      Ok(result) => result?,
+      Err(_) => warn!("Requests still in flight after {:?}", DRAIN_TIMEOUT),
+    }
+
+    if let Err(e) = inner_arc.raft.shutdown().await {
+      warn!("Failed to shut down raft: {}", e);
+    }
     Ok(())
   }
 
+  /// Runs the controller while this node is the leader, and stops it as soon as it is not: when
+  /// it becomes a follower or a candidate, when it is drained, or when it is elected again in a
+  /// later term, in which case a new controller is started for the new term.
   async fn monitor_leader_election(inner_arc: Arc<NodeInner>) {
     info!("Monitoring leader election");
 
     // Get metrics directly
     let mut metrics = inner_arc.raft.server_metrics();
-    let max_concurrent_tasks = inner_arc.settings.external_commands_max.clone();
-    let mut current_state: Option<ServerState> = None;
+    let mut drained = inner_arc.drain.subscribe();
+    let mut current: Option<(ServerState, u64)> = None;
 
     loop {
-      if let Err(err) = metrics.changed().await {
-        info!(
-          "{}; when:(watching metrics); quit monitor_leader_election() loop",
-          err
-        );
-        break;
+      tokio::select! {
+        changed = metrics.changed() => {
+          if let Err(err) = changed {
+            info!(
+              "{}; when:(watching metrics); quit monitor_leader_election() loop",
+              err
+            );
+            break;
+          }
+        }
+        changed = drained.changed() => {
+          if changed.is_err() {
+            break;
+          }
+          if *drained.borrow_and_update() {
+            info!("Node {} is drained", inner_arc.node_id);
+            NodeInner::stop_controller(&inner_arc.controller).await;
+          } else {
+            info!("Node {} is no longer drained", inner_arc.node_id);
+            if let Some((ServerState::Leader, term)) = current {
+              Self::start_leader_controller(&inner_arc, term).await;
+            }
+          }
+          continue;
+        }
       }
 
       let mm = metrics.borrow().clone();
+      let term = mm
+        .vote
+        .leader_id
+        .as_ref()
+        .map(|x| x.term)
+        .unwrap_or_default();
 
-      // Only act if state has changed
-      if current_state == Some(mm.state) {
+      // Only act if the state or the term has changed
+      if current == Some((mm.state, term)) {
         continue;
       }
 
-      current_state = Some(mm.state);
+      current = Some((mm.state, term));
 
-      match current_state {
-        Some(ServerState::Leader) => {
-          info!("Node {} is the leader", mm.id);
-
-          // Only lock the controller when we need to modify it
-          NodeInner::start_controller(&inner_arc.controller, max_concurrent_tasks).await;
+      match mm.state {
+        ServerState::Leader if inner_arc.drain.is_drained() => {
+          // The leadership was handed to this node while it is drained: hand it on
+          info!("Node {} is the leader while drained", mm.id);
+          let raft = inner_arc.raft.clone();
+          runtime::spawn(async move {
+            if let Err(e) = membership::transfer_leadership(&raft, None, TRANSFER_TIMEOUT).await {
+              warn!("Failed to transfer the leadership: {}", e);
+            }
+          });
         }
-        Some(ServerState::Follower) => {
-          info!("Node {} is a follower", mm.id);
+        ServerState::Leader => {
+          info!("Node {} is the leader of term {}", mm.id, term);
+          Self::start_leader_controller(&inner_arc, term).await;
         }
-        _ => {
-          // info!("Node {} is a something", mm.id);
+        state => {
+          if state == ServerState::Follower {
+            info!("Node {} is a follower", mm.id);
+          }
+
+          // Another node may lead already: its controller must be the only one running
+          NodeInner::stop_controller(&inner_arc.controller).await;
         }
       }
     }
   }
+
+  async fn start_leader_controller(inner_arc: &Arc<NodeInner>, term: u64) {
+    // The coordinator gives up on the requests of the controller once it is stopped
+    let (cancel, cancelled) = watch::channel(false);
+    let coordinator = Arc::new(NodeCoordinator::new(
+      inner_arc.raft.clone(),
+      inner_arc.lease_manager.clone(),
+      inner_arc.locks.clone(),
+      term,
+      cancelled,
+    ));
+    NodeInner::start_controller(
+      &inner_arc.controller,
+      inner_arc.settings.external_commands_max,
+      coordinator,
+      term,
+      cancel,
+    )
+    .await;
+  }
 }
 
 impl NodeInner {
+  /// Hands the work of this node over to the rest of the cluster before it stops: drains it, which
+  /// moves the leadership to another voter, removes the node from the membership if
+  /// `leave_on_shutdown` is set and it is not the last voter, and stops the controller.
+  async fn leave(&self) {
+    // Draining also keeps the node from being elected again while it leaves
+    if let Err(e) = self.drain.set(true).await {
+      warn!("Failed to transfer the leadership: {}", e);
+    }
+
+    // The cluster can not do without its last voter: it is not removed, rather than retrying.
+    let last_voter = membership::is_last_voter(&self.raft.metrics().borrow(), self.node_id);
+    if self.settings.leave_on_shutdown && last_voter {
+      warn!(
+        "Node {} is the last voter of the cluster, and stays a member",
+        self.node_id
+      );
+    } else if self.settings.leave_on_shutdown {
+      for attempt in 1..=LEAVE_ATTEMPTS {
+        match self.remove_self().await {
+          Ok(()) => {
+            info!("Node {} left the cluster", self.node_id);
+            break;
+          }
+          Err(e) => warn!(
+            "Failed to leave the cluster (attempt {}/{}): {}",
+            attempt, LEAVE_ATTEMPTS, e
+          ),
+        }
+        if attempt < LEAVE_ATTEMPTS {
+          tokio::time::sleep(LEAVE_RETRY).await;
+        }
+      }
+    }
+
+    Self::stop_controller(&self.controller).await;
+  }
+
+  /// Removes this node from the membership, through the leader if it is not the leader.
+  async fn remove_self(&self) -> Result<(), Box<dyn std::error::Error>> {
+    let to = match membership::remove_node(&self.raft, self.node_id).await {
+      Ok(_) | Err(MembershipError::NotMember(_)) => return Ok(()),
+      Err(MembershipError::ForwardToLeader(to)) => to,
+      Err(e) => return Err(e.into()),
+    };
+
+    let req = protobuf::RemoveNodeRequest {
+      node_id: self.node_id,
+    };
+    let max_message_size = RequestLimits::new(&self.settings).max_message_size();
+    let forwarded = forward::forward_to_leader(
+      self.tls.as_ref(),
+      max_message_size,
+      to,
+      0,
+      req,
+      |mut c, r| async move { c.remove_node(r).await },
+    );
+    match forwarded.await {
+      Ok(_) => Ok(()),
+      Err(status) if status.code() == Code::NotFound => Ok(()),
+      Err(status) => Err(status.into()),
+    }
+  }
+
   pub async fn start_controller(
     controller: &Arc<Mutex<Option<Controller>>>,
     max_concurrent_tasks: usize,
+    coordinator: Arc<NodeCoordinator>,
+    term: u64,
+    cancel: watch::Sender<bool>,
   ) {
     let mut controller_guard = controller.lock().await;
-    if controller_guard.is_none() {
-      *controller_guard = Some(Controller::new(max_concurrent_tasks));
-      info!("Started controller");
-
-      // We need to drop the guard to avoid deadlock when running the command
-      let controller_ref = controller_guard.as_ref().unwrap();
-      for i in 1..=20 {
-        if let Err(e) = controller_ref
-          .run_command(format!("sleep 5; echo 'Hello world {}'", i))
-          .await
-        {
-          info!("Failed to run command {}: {:?}", i, e);
-        }
+    if let Some(running) = controller_guard.as_ref() {
+      if running.term() == term {
+        return;
+      }
+
+      // Left over from an earlier leadership of this node; stopped before the new one starts
+      let stale = controller_guard.take().unwrap();
+      if let Err(e) = stale.stop().await {
+        info!("Failed to stop controller: {:?}", e);
       }
     }
+
+    // The init script runs in the background, so the lock is not held while it waits
+    *controller_guard = Some(Controller::new(
+      max_concurrent_tasks,
+      coordinator,
+      term,
+      cancel,
+    ));
+    info!("Started controller for term {}", term);
   }
 
   pub async fn stop_controller(controller: &Arc<Mutex<Option<Controller>>>) {
@@ -190,3 +484,26 @@ impl NodeInner {
     }
   }
 }
+
+/// Resolves when the process is asked to stop, with SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+  #[cfg(unix)]
+  let terminate = async {
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+      Ok(mut signal) => {
+        signal.recv().await;
+      }
+      Err(e) => {
+        warn!("Failed to listen for SIGTERM: {}", e);
+        std::future::pending::<()>().await
+      }
+    }
+  };
+  #[cfg(not(unix))]
+  let terminate = std::future::pending::<()>();
+
+  tokio::select! {
+    _ = tokio::signal::ctrl_c() => {}
+    _ = terminate => {}
+  }
+}
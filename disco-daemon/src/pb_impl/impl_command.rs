@@ -0,0 +1,75 @@
+use crate::protobuf;
+use crate::protobuf::command::Op;
+
+impl protobuf::Command {
+  /// Makes the command refused unless it is written in `term`.
+  pub fn fenced(self, term: u64) -> Self {
+    protobuf::Command {
+      fencing_term: term,
+      ..self
+    }
+  }
+}
+
+impl From<protobuf::SetRequest> for protobuf::Command {
+  fn from(req: protobuf::SetRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::Set(req)),
+      fencing_term: 0,
+    }
+  }
+}
+
+impl From<protobuf::DeleteRequest> for protobuf::Command {
+  fn from(req: protobuf::DeleteRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::Delete(req)),
+      fencing_term: 0,
+    }
+  }
+}
+
+impl From<protobuf::CompareAndSwapRequest> for protobuf::Command {
+  fn from(req: protobuf::CompareAndSwapRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::CompareAndSwap(req)),
+      fencing_term: 0,
+    }
+  }
+}
+
+impl From<protobuf::GrantLeaseRequest> for protobuf::Command {
+  fn from(req: protobuf::GrantLeaseRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::GrantLease(req)),
+      fencing_term: 0,
+    }
+  }
+}
+
+impl From<protobuf::RevokeLeaseRequest> for protobuf::Command {
+  fn from(req: protobuf::RevokeLeaseRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::RevokeLease(req)),
+      fencing_term: 0,
+    }
+  }
+}
+
+impl From<protobuf::TxnRequest> for protobuf::Command {
+  fn from(req: protobuf::TxnRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::Txn(req)),
+      fencing_term: 0,
+    }
+  }
+}
+
+impl From<protobuf::SetQuotaRequest> for protobuf::Command {
+  fn from(req: protobuf::SetQuotaRequest) -> Self {
+    protobuf::Command {
+      op: Some(Op::SetQuota(req)),
+      fencing_term: 0,
+    }
+  }
+}
@@ -34,6 +34,7 @@ impl RaftEntry<TypeConfig> for protobuf::Entry {
     Self {
       term: log_id.leader_id,
       index: log_id.index,
+      legacy_app_data: None,
       app_data,
       membership,
     }
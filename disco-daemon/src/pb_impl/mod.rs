@@ -3,6 +3,7 @@
 mod impl_append_entries_request;
 mod impl_append_entries_response;
 mod impl_client_write_response;
+mod impl_command;
 mod impl_entry;
 mod impl_leader_id;
 mod impl_log_id;
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 
@@ -9,6 +11,55 @@ pub struct Settings {
   pub heartbeat_interval: u64,
   pub install_snapshot_timeout: u64,
   pub external_commands_max: usize,
+  /// Build a snapshot once this many logs were applied since the last one; 0 disables it.
+  pub snapshot_logs_since_last: u64,
+  /// Number of logs already included in a snapshot to keep before purging them.
+  pub max_in_snapshot_log_to_keep: u64,
+  /// Minimum number of logs to purge at once.
+  pub purge_batch_size: u64,
+  /// How often the leader looks for expired leases, in milliseconds.
+  pub lease_check_interval: u64,
+  /// Largest key accepted by the key-value API, in bytes.
+  pub max_key_size: usize,
+  /// Largest value accepted by the key-value API, in bytes.
+  pub max_value_size: usize,
+  /// Largest write request accepted by the key-value API, in bytes, as written to the log.
+  pub max_entry_size: usize,
+  /// Whether the node removes itself from the cluster when it is stopped with SIGTERM, instead
+  /// of staying a member that is down.
+  pub leave_on_shutdown: bool,
+  /// Number of voters the leader keeps by promoting and demoting nodes, odd; 0 leaves the
+  /// membership to the operator.
+  pub voters: usize,
+  /// How often the leader checks the membership, in milliseconds.
+  pub membership_check_interval: u64,
+  /// How many logs a learner may lag behind the leader to be promoted.
+  pub learner_max_lag: u64,
+  /// How long a voter may not answer the leader before it is replaced, in seconds.
+  pub voter_down_timeout: u64,
+  /// TLS for the Raft and client traffic; plaintext if not set.
+  pub tls: Option<TlsSettings>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsSettings {
+  /// PEM file of the CA certificates that issue the certificates of the nodes and clients.
+  pub ca: PathBuf,
+  /// PEM file of the certificate of this node, which must name it `node-<id>`.
+  pub cert: PathBuf,
+  /// PEM file of the private key of the certificate.
+  pub key: PathBuf,
+  /// Whether clients must present a certificate issued by the CA. Nodes always must.
+  #[serde(default)]
+  pub require_client_cert: bool,
+  /// How often the files are read again to pick up rotated certificates, in seconds; 0 disables
+  /// it.
+  #[serde(default = "default_tls_reload_interval")]
+  pub reload_interval: u64,
+}
+
+fn default_tls_reload_interval() -> u64 {
+  60
 }
 
 impl Settings {
@@ -21,6 +72,18 @@ impl Settings {
       .set_default("heartbeat_interval", 50)?
       .set_default("install_snapshot_timeout", 120)?
       .set_default("external_commands_max", 100)?
+      .set_default("snapshot_logs_since_last", 5000)?
+      .set_default("max_in_snapshot_log_to_keep", 1000)?
+      .set_default("purge_batch_size", 1)?
+      .set_default("lease_check_interval", 500)?
+      .set_default("max_key_size", 4096)?
+      .set_default("max_value_size", 1024 * 1024)?
+      .set_default("max_entry_size", 3 * 512 * 1024)?
+      .set_default("leave_on_shutdown", false)?
+      .set_default("voters", 0)?
+      .set_default("membership_check_interval", 1000)?
+      .set_default("learner_max_lag", 100)?
+      .set_default("voter_down_timeout", 60)?
       // Load from a config file
       // Will look for config.yaml, config.json, config.toml, etc.
       .add_source(File::with_name("config").required(false))
@@ -29,6 +92,14 @@ impl Settings {
       .build()?;
 
     // Deserialize the configuration into our Settings struct
-    config.try_deserialize()
+    let settings: Settings = config.try_deserialize()?;
+
+    if settings.voters % 2 == 0 && settings.voters != 0 {
+      return Err(ConfigError::Message(format!(
+        "voters must be odd, not {}",
+        settings.voters
+      )));
+    }
+    Ok(settings)
   }
 }
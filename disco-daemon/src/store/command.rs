@@ -0,0 +1,681 @@
+//! Applies replicated key-value commands to the state machine data.
+//!
+//! Everything in here must be deterministic: every node applies the same commands in the same
+//! order and has to end up with the same data.
+
+use crate::protobuf as pb;
+use crate::protobuf::command::Op;
+use crate::protobuf::compare;
+use crate::protobuf::compare_and_swap_request::Expected;
+use crate::protobuf::txn_op;
+use crate::protobuf::Rejection;
+use crate::protobuf::Response;
+use crate::store::namespace;
+use crate::store::namespace::QuotaCheck;
+use crate::store::watch;
+
+/// Applies a single command written at log `index` in `term` to the state machine data.
+///
+/// The changes made to keys are pushed to `events`, to be published to watchers. A command with
+/// a fencing term is refused unless it is `term`.
+pub(crate) fn apply_command(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  term: u64,
+  cmd: pb::Command,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  if cmd.fencing_term != 0 && cmd.fencing_term != term {
+    return rejected(Rejection::Fenced);
+  }
+
+  match cmd.op {
+    Some(Op::Set(req)) => set(sm, index, req, events),
+    Some(Op::Delete(req)) => delete(sm, index, &req.namespace, &req.key, events),
+    Some(Op::CompareAndSwap(req)) => compare_and_swap(sm, index, req, events),
+    Some(Op::GrantLease(req)) => grant_lease(sm, index, req.ttl),
+    Some(Op::RevokeLease(req)) => revoke_lease(sm, index, req.id, events),
+    Some(Op::Txn(req)) => txn(sm, index, req, events),
+    Some(Op::SetQuota(req)) => set_quota(sm, req),
+    None => Response::default(),
+  }
+}
+
+/// Response to a write that was refused, leaving the data untouched.
+fn rejected(rejection: Rejection) -> Response {
+  Response {
+    succeeded: Some(false),
+    rejection: rejection.into(),
+    ..Default::default()
+  }
+}
+
+/// Writes a key. Fails, leaving the key untouched, if the lease is set but does not exist, or if
+/// the write would make the namespace exceed its quota.
+fn set(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  req: pb::SetRequest,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  let namespace = namespace::resolve(&req.namespace).to_string();
+  if req.lease != 0 && !sm.leases.contains_key(&req.lease) {
+    return rejected(Rejection::LeaseNotFound);
+  }
+
+  let mut check = QuotaCheck::new(sm);
+  check.set(&namespace, &req.key, &req.value);
+  if check.exceeds_quota() {
+    return rejected(Rejection::QuotaExceeded);
+  }
+
+  write(sm, index, &namespace, req, events)
+}
+
+/// Writes a key of `namespace`, once the write has been checked.
+fn write(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  namespace: &str,
+  req: pb::SetRequest,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  let pb::SetRequest {
+    key,
+    value,
+    lease,
+    content_type,
+    ..
+  } = req;
+  let ns = sm.namespaces.entry(namespace.to_string()).or_default();
+  let size = namespace::size(&key, &value);
+  let prev_size = ns.data.get(&key).map(|x| namespace::size(&key, &x.value));
+  let kv = ns.data.entry(key.clone()).or_insert_with(|| pb::KeyValue {
+    create_revision: index,
+    ..Default::default()
+  });
+  let prev_lease = kv.lease;
+  kv.value = value;
+  kv.content_type = content_type;
+  kv.mod_revision = index;
+  kv.version += 1;
+  kv.lease = lease;
+  events.push(watch::put_event(namespace, &key, kv));
+  let res = response(Some(&*kv));
+  ns.bytes = ns.bytes + size - prev_size.unwrap_or_default();
+
+  if prev_lease != lease {
+    detach(sm, prev_lease, namespace, &key);
+    if let Some(lease) = sm.leases.get_mut(&lease) {
+      let keys = lease.keys.entry(namespace.to_string()).or_default();
+      keys.keys.insert(key, ());
+    }
+  }
+
+  res
+}
+
+fn delete(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  namespace: &str,
+  key: &str,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  let namespace = namespace::resolve(namespace);
+  let Some(ns) = sm.namespaces.get_mut(namespace) else {
+    return Response::default();
+  };
+  let Some(prev) = ns.data.remove(key) else {
+    return Response::default();
+  };
+
+  ns.bytes -= namespace::size(key, &prev.value);
+  // Namespaces are created by their first write, and dropped once they no longer hold anything.
+  if ns.data.is_empty() && ns.quota.unwrap_or_default() == pb::Quota::default() {
+    sm.namespaces.remove(namespace);
+  }
+
+  detach(sm, prev.lease, namespace, key);
+  events.push(watch::delete_event(namespace, key, index));
+  response(Some(&prev))
+}
+
+/// Removes `key` of `namespace` from the keys attached to `lease`.
+fn detach(sm: &mut pb::StateMachineData, lease: u64, namespace: &str, key: &str) {
+  let Some(lease) = sm.leases.get_mut(&lease) else {
+    return;
+  };
+  if let Some(keys) = lease.keys.get_mut(namespace) {
+    keys.keys.remove(key);
+    if keys.keys.is_empty() {
+      lease.keys.remove(namespace);
+    }
+  }
+}
+
+fn compare_and_swap(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  req: pb::CompareAndSwapRequest,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  let namespace = namespace::resolve(&req.namespace);
+  let current = namespace::get(sm, namespace, &req.key);
+  let matches = match &req.expected {
+    Some(Expected::Value(value)) => current.map(|x| &x.value) == Some(value),
+    Some(Expected::Revision(revision)) => current.map(|x| x.mod_revision) == Some(*revision),
+    Some(Expected::Absent(())) => current.is_none(),
+    None => false,
+  };
+
+  if !matches {
+    return Response {
+      succeeded: Some(false),
+      ..response(current)
+    };
+  }
+
+  let res = match req.new_value {
+    Some(value) => set(
+      sm,
+      index,
+      pb::SetRequest {
+        key: req.key,
+        value,
+        lease: req.lease,
+        namespace: namespace.to_string(),
+        content_type: req.content_type,
+      },
+      events,
+    ),
+    None => {
+      delete(sm, index, namespace, &req.key, events);
+      Response::default()
+    }
+  };
+
+  // Setting the key is refused if its lease does not exist or its namespace is full.
+  Response {
+    succeeded: Some(res.succeeded.unwrap_or(true)),
+    ..res
+  }
+}
+
+/// Applies the operations of one branch of a transaction, depending on whether every guard
+/// holds. Every change is made at `index`.
+///
+/// If a write of the branch names a lease that does not exist, or the writes would make a
+/// namespace exceed its quota, nothing is applied and `succeeded` is not set.
+fn txn(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  req: pb::TxnRequest,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  let succeeded = req.compare.iter().all(|x| compare(sm, x));
+  let ops = if succeeded { req.success } else { req.failure };
+
+  let missing_lease = ops.iter().any(|x| match &x.op {
+    Some(txn_op::Op::Set(set)) => set.lease != 0 && !sm.leases.contains_key(&set.lease),
+    _ => false,
+  });
+  if missing_lease {
+    return Response {
+      rejection: Rejection::LeaseNotFound.into(),
+      ..Default::default()
+    };
+  }
+
+  let mut check = QuotaCheck::new(sm);
+  for op in &ops {
+    match &op.op {
+      Some(txn_op::Op::Set(req)) => {
+        check.set(namespace::resolve(&req.namespace), &req.key, &req.value)
+      }
+      Some(txn_op::Op::Delete(req)) => check.delete(namespace::resolve(&req.namespace), &req.key),
+      _ => {}
+    }
+  }
+  if check.exceeds_quota() {
+    return Response {
+      rejection: Rejection::QuotaExceeded.into(),
+      ..Default::default()
+    };
+  }
+
+  let responses = ops
+    .into_iter()
+    .map(|x| match x.op {
+      Some(txn_op::Op::Set(req)) => {
+        let namespace = namespace::resolve(&req.namespace).to_string();
+        write(sm, index, &namespace, req, events)
+      }
+      Some(txn_op::Op::Delete(req)) => delete(sm, index, &req.namespace, &req.key, events),
+      Some(txn_op::Op::Get(req)) => response(namespace::get(
+        sm,
+        namespace::resolve(&req.namespace),
+        &req.key,
+      )),
+      None => Response::default(),
+    })
+    .collect();
+
+  Response {
+    succeeded: Some(succeeded),
+    responses,
+    ..Default::default()
+  }
+}
+
+/// Returns whether the guard of a transaction holds.
+fn compare(sm: &pb::StateMachineData, guard: &pb::Compare) -> bool {
+  let current = namespace::get(sm, namespace::resolve(&guard.namespace), &guard.key);
+  match &guard.expected {
+    Some(compare::Expected::Value(value)) => current.map(|x| &x.value) == Some(value),
+    Some(compare::Expected::Revision(revision)) => {
+      current.map(|x| x.mod_revision) == Some(*revision)
+    }
+    Some(compare::Expected::Absent(())) => current.is_none(),
+    None => false,
+  }
+}
+
+/// Replaces the quota of a namespace.
+fn set_quota(sm: &mut pb::StateMachineData, req: pb::SetQuotaRequest) -> Response {
+  let namespace = namespace::resolve(&req.namespace);
+  let quota = req.quota.unwrap_or_default();
+  let ns = sm.namespaces.entry(namespace.to_string()).or_default();
+  ns.quota = Some(quota);
+  if ns.data.is_empty() && quota == pb::Quota::default() {
+    sm.namespaces.remove(namespace);
+  }
+
+  Response {
+    succeeded: Some(true),
+    ..Default::default()
+  }
+}
+
+/// Grants a lease, using the log index as its id.
+fn grant_lease(sm: &mut pb::StateMachineData, index: u64, ttl: u64) -> Response {
+  sm.leases.insert(
+    index,
+    pb::Lease {
+      id: index,
+      ttl,
+      ..Default::default()
+    },
+  );
+  Response {
+    lease: Some(index),
+    ..Default::default()
+  }
+}
+
+/// Removes a lease and deletes the keys attached to it.
+fn revoke_lease(
+  sm: &mut pb::StateMachineData,
+  index: u64,
+  id: u64,
+  events: &mut Vec<pb::Event>,
+) -> Response {
+  let Some(lease) = sm.leases.remove(&id) else {
+    return Response {
+      succeeded: Some(false),
+      ..Default::default()
+    };
+  };
+
+  for (namespace, keys) in &lease.keys {
+    for key in keys.keys.keys() {
+      delete(sm, index, namespace, key, events);
+    }
+  }
+  Response {
+    succeeded: Some(true),
+    ..Default::default()
+  }
+}
+
+/// Builds the response describing a key, or a missing key if `kv` is `None`.
+pub(crate) fn response(kv: Option<&pb::KeyValue>) -> Response {
+  match kv {
+    Some(kv) => Response {
+      value: Some(kv.value.clone()),
+      content_type: kv.content_type.clone(),
+      revision: Some(kv.mod_revision),
+      create_revision: Some(kv.create_revision),
+      version: Some(kv.version),
+      lease: Some(kv.lease).filter(|x| *x != 0),
+      ..Default::default()
+    },
+    None => Response::default(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn apply(sm: &mut pb::StateMachineData, index: u64, cmd: pb::Command) -> Response {
+    apply_command(sm, index, 1, cmd, &mut Vec::new())
+  }
+
+  fn value<'a>(sm: &'a pb::StateMachineData, key: &str) -> Option<&'a str> {
+    namespace::get(sm, namespace::DEFAULT, key).map(|x| std::str::from_utf8(&x.value).unwrap())
+  }
+
+  fn cas(key: &str, expected: Option<Expected>, new_value: Option<&str>) -> pb::Command {
+    pb::Command {
+      op: Some(Op::CompareAndSwap(pb::CompareAndSwapRequest {
+        key: key.to_string(),
+        expected,
+        new_value: new_value.map(|x| x.into()),
+        lease: 0,
+        namespace: String::new(),
+        content_type: String::new(),
+      })),
+      fencing_term: 0,
+    }
+  }
+
+  #[test]
+  fn test_fencing() {
+    let mut sm = pb::StateMachineData::default();
+    let set = |term| {
+      pb::Command::from(pb::SetRequest {
+        key: "foo".to_string(),
+        value: "a".into(),
+        lease: 0,
+        namespace: String::new(),
+        content_type: String::new(),
+      })
+      .fenced(term)
+    };
+
+    // Refused when written in another term than the one presented.
+    let res = apply_command(&mut sm, 1, 3, set(2), &mut Vec::new());
+    assert_eq!(res.rejection(), Rejection::Fenced);
+    assert_eq!(value(&sm, "foo"), None);
+
+    let res = apply_command(&mut sm, 2, 3, set(3), &mut Vec::new());
+    assert_eq!(res.rejection(), Rejection::None);
+    assert_eq!(value(&sm, "foo"), Some("a"));
+  }
+
+  #[test]
+  fn test_compare_and_swap() {
+    let mut sm = pb::StateMachineData::default();
+
+    // Create only if absent.
+    let res = apply(&mut sm, 1, cas("foo", Some(Expected::Absent(())), Some("a")));
+    assert_eq!(res.succeeded, Some(true));
+    assert_eq!(res.revision, Some(1));
+
+    let res = apply(&mut sm, 2, cas("foo", Some(Expected::Absent(())), Some("b")));
+    assert_eq!(res.succeeded, Some(false));
+    assert_eq!(res.value, Some(b"a".to_vec()));
+
+    // Swap on the expected value.
+    let res = apply(&mut sm, 3, cas("foo", Some(Expected::Value("x".into())), Some("b")));
+    assert_eq!(res.succeeded, Some(false));
+    let res = apply(&mut sm, 4, cas("foo", Some(Expected::Value("a".into())), Some("b")));
+    assert_eq!(res.succeeded, Some(true));
+    assert_eq!(res.value, Some(b"b".to_vec()));
+
+    // Swap on the expected revision.
+    let res = apply(&mut sm, 5, cas("foo", Some(Expected::Revision(1)), Some("c")));
+    assert_eq!(res.succeeded, Some(false));
+    assert_eq!(res.revision, Some(4));
+    let res = apply(&mut sm, 6, cas("foo", Some(Expected::Revision(4)), Some("c")));
+    assert_eq!(res.succeeded, Some(true));
+    assert_eq!(value(&sm, "foo"), Some("c"));
+
+    // Compare and delete.
+    let res = apply(&mut sm, 7, cas("foo", Some(Expected::Revision(6)), None));
+    assert_eq!(res.succeeded, Some(true));
+    assert!(sm.namespaces.is_empty());
+  }
+
+  #[test]
+  fn test_leases() {
+    let mut sm = pb::StateMachineData::default();
+    let set = |key: &str, lease: u64| {
+      pb::Command::from(pb::SetRequest {
+        key: key.to_string(),
+        value: "v".into(),
+        lease,
+        namespace: String::new(),
+        content_type: String::new(),
+      })
+    };
+    let grant = pb::Command::from(pb::GrantLeaseRequest { ttl: 10 });
+
+    // Unknown leases are refused.
+    let res = apply(&mut sm, 1, set("foo", 7));
+    assert_eq!(res.succeeded, Some(false));
+    assert!(sm.namespaces.is_empty());
+
+    let lease = apply(&mut sm, 2, grant.clone()).lease.unwrap();
+    let other = apply(&mut sm, 3, grant).lease.unwrap();
+    assert_eq!((lease, other), (2, 3));
+
+    apply(&mut sm, 4, set("foo", lease));
+    apply(&mut sm, 5, set("bar", lease));
+    apply(&mut sm, 6, set("baz", lease));
+    // Moved to another lease, and detached.
+    apply(&mut sm, 7, set("bar", other));
+    apply(&mut sm, 8, set("baz", 0));
+
+    let mut events = Vec::new();
+    let res = apply_command(
+      &mut sm,
+      9,
+      1,
+      pb::RevokeLeaseRequest { id: lease }.into(),
+      &mut events,
+    );
+    assert_eq!(res.succeeded, Some(true));
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+      sm.namespaces[namespace::DEFAULT]
+        .data
+        .keys()
+        .collect::<Vec<_>>(),
+      vec!["bar", "baz"]
+    );
+    assert!(!sm.leases.contains_key(&lease));
+    assert_eq!(sm.leases[&other].keys[namespace::DEFAULT].keys.len(), 1);
+
+    let res = apply(&mut sm, 10, pb::RevokeLeaseRequest { id: lease }.into());
+    assert_eq!(res.succeeded, Some(false));
+  }
+
+  #[test]
+  fn test_txn() {
+    let mut sm = pb::StateMachineData::default();
+    let op = |op| pb::TxnOp { op: Some(op) };
+    let set = |key: &str, value: &str, lease: u64| {
+      op(txn_op::Op::Set(pb::SetRequest {
+        key: key.to_string(),
+        value: value.into(),
+        lease,
+        namespace: String::new(),
+        content_type: String::new(),
+      }))
+    };
+    let get = |key: &str| {
+      op(txn_op::Op::Get(pb::GetRequest {
+        key: key.to_string(),
+        consistency: 0,
+        namespace: String::new(),
+      }))
+    };
+    let absent = |key: &str| pb::Compare {
+      key: key.to_string(),
+      expected: Some(compare::Expected::Absent(())),
+      namespace: String::new(),
+    };
+
+    // Every write of the branch is made at the same revision.
+    let mut events = Vec::new();
+    let req = pb::TxnRequest {
+      compare: vec![absent("spec")],
+      success: vec![set("spec", "v1", 0), set("current", "spec", 0), get("spec")],
+      failure: vec![get("spec")],
+    };
+    let res = apply_command(&mut sm, 1, 1, req.clone().into(), &mut events);
+    assert_eq!(res.succeeded, Some(true));
+    assert_eq!(res.responses.len(), 3);
+    assert_eq!(res.responses[2].value, Some(b"v1".to_vec()));
+    assert_eq!(events.len(), 2);
+    let current = namespace::get(&sm, namespace::DEFAULT, "current");
+    assert_eq!(current.map(|x| x.mod_revision), Some(1));
+
+    // The guard fails: only the failure branch is applied.
+    let res = apply(&mut sm, 2, req.into());
+    assert_eq!(res.succeeded, Some(false));
+    assert_eq!(res.responses.len(), 1);
+    assert_eq!(res.responses[0].revision, Some(1));
+
+    // A missing lease aborts the whole branch.
+    let req = pb::TxnRequest {
+      compare: vec![],
+      success: vec![
+        op(txn_op::Op::Delete(pb::DeleteRequest {
+          key: "spec".to_string(),
+          namespace: String::new(),
+        })),
+        set("current", "other", 9),
+      ],
+      failure: vec![],
+    };
+    let res = apply(&mut sm, 3, req.into());
+    assert_eq!(res.succeeded, None);
+    assert!(value(&sm, "spec").is_some());
+    assert_eq!(value(&sm, "current"), Some("spec"));
+  }
+
+  #[test]
+  fn test_revisions() {
+    let mut sm = pb::StateMachineData::default();
+    let set = |key: &str, value: &str| {
+      pb::Command::from(pb::SetRequest {
+        key: key.to_string(),
+        value: value.into(),
+        lease: 0,
+        namespace: String::new(),
+        content_type: String::new(),
+      })
+    };
+
+    let res = apply(&mut sm, 3, set("foo", "a"));
+    assert_eq!(
+      (res.create_revision, res.revision, res.version),
+      (Some(3), Some(3), Some(1))
+    );
+
+    apply(&mut sm, 4, set("bar", "b"));
+    let res = apply(&mut sm, 5, set("foo", "c"));
+    assert_eq!(
+      (res.create_revision, res.revision, res.version),
+      (Some(3), Some(5), Some(2))
+    );
+
+    // A key created again after a delete starts over.
+    let res = apply(
+      &mut sm,
+      6,
+      pb::DeleteRequest {
+        key: "foo".to_string(),
+        namespace: String::new(),
+      }
+      .into(),
+    );
+    assert_eq!(res.version, Some(2));
+    let res = apply(&mut sm, 7, set("foo", "d"));
+    assert_eq!(
+      (res.create_revision, res.revision, res.version),
+      (Some(7), Some(7), Some(1))
+    );
+  }
+
+  #[test]
+  fn test_namespaces() {
+    let mut sm = pb::StateMachineData::default();
+    let set = |namespace: &str, key: &str, value: &str, lease: u64| pb::SetRequest {
+      key: key.to_string(),
+      value: value.into(),
+      lease,
+      namespace: namespace.to_string(),
+      content_type: String::new(),
+    };
+    let quota = |namespace: &str, max_keys: u64, max_bytes: u64| {
+      pb::Command::from(pb::SetQuotaRequest {
+        namespace: namespace.to_string(),
+        quota: Some(pb::Quota {
+          max_keys,
+          max_bytes,
+        }),
+      })
+    };
+
+    // The same key in different namespaces are different keys.
+    apply(&mut sm, 1, set("", "foo", "a", 0).into());
+    apply(&mut sm, 2, set("user/other", "foo", "bb", 0).into());
+    assert_eq!(value(&sm, "foo"), Some("a"));
+    assert_eq!(sm.namespaces["user/other"].bytes, 5);
+
+    apply(&mut sm, 3, quota("user/other", 2, 10));
+    let res = apply(&mut sm, 4, set("user/other", "bar", "1234567", 0).into());
+    assert_eq!(res.succeeded, Some(false));
+    assert_eq!(res.rejection(), Rejection::QuotaExceeded);
+    assert!(namespace::get(&sm, "user/other", "bar").is_none());
+
+    // Overwriting a key only counts the difference.
+    let res = apply(&mut sm, 5, set("user/other", "foo", "1234567", 0).into());
+    assert_eq!(res.rejection(), Rejection::None);
+    assert_eq!(sm.namespaces["user/other"].bytes, 10);
+
+    // The writes of a transaction are checked together.
+    let op = |op| pb::TxnOp { op: Some(op) };
+    let req = pb::TxnRequest {
+      compare: vec![],
+      success: vec![
+        op(txn_op::Op::Delete(pb::DeleteRequest {
+          key: "foo".to_string(),
+          namespace: "user/other".to_string(),
+        })),
+        op(txn_op::Op::Set(set("user/other", "a", "1234", 0))),
+        op(txn_op::Op::Set(set("user/other", "b", "1234", 0))),
+      ],
+      failure: vec![],
+    };
+    let res = apply(&mut sm, 6, req.clone().into());
+    assert_eq!(res.succeeded, Some(true));
+    assert_eq!(sm.namespaces["user/other"].data.len(), 2);
+
+    let req = pb::TxnRequest {
+      success: vec![op(txn_op::Op::Set(set("user/other", "c", "1", 0)))],
+      ..req
+    };
+    let res = apply(&mut sm, 7, req.into());
+    assert_eq!(res.succeeded, None);
+    assert_eq!(res.rejection(), Rejection::QuotaExceeded);
+
+    // Revoking a lease deletes its keys in every namespace.
+    let lease = apply(&mut sm, 8, pb::GrantLeaseRequest { ttl: 10 }.into())
+      .lease
+      .unwrap();
+    apply(&mut sm, 9, set(namespace::SYSTEM, "x", "1", lease).into());
+    apply(
+      &mut sm,
+      10,
+      set(namespace::CONTROLLER, "x", "1", lease).into(),
+    );
+    apply(&mut sm, 11, pb::RevokeLeaseRequest { id: lease }.into());
+    assert!(!sm.namespaces.contains_key(namespace::SYSTEM));
+    assert!(!sm.namespaces.contains_key(namespace::CONTROLLER));
+  }
+}
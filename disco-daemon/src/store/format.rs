@@ -0,0 +1,159 @@
+//! Versioning of the `StateMachineData` layout stored in snapshots.
+//!
+//! Snapshots outlive the binary that wrote them: a node may restart with a newer version, or
+//! receive a snapshot from a leader that has not been upgraded yet. Every decoded state machine
+//! goes through [`migrate`] before it is used.
+//!
+//! Versions:
+//! - 0: user data in `legacy_data`, the revision that last modified each key in
+//!   `legacy_revisions`.
+//! - 1: user data and its revision metadata in `unscoped_data`, the keys attached to leases in
+//!   `Lease::unscoped_keys`.
+//! - 2: data split into `namespaces`, each with its usage and quota.
+
+use std::io;
+
+use crate::protobuf as pb;
+use crate::store::namespace;
+
+/// The layout written by this version.
+pub(crate) const CURRENT_FORMAT_VERSION: u32 = 2;
+
+/// Upgrades a decoded state machine to [`CURRENT_FORMAT_VERSION`].
+///
+/// Metadata that older versions did not record is approximated: the create revision of a key is
+/// taken to be its last modification, and its version starts at 1. Data written before
+/// namespaces existed moves to the default namespace.
+pub(crate) fn migrate(sm: &mut pb::StateMachineData) -> io::Result<()> {
+  if sm.format_version > CURRENT_FORMAT_VERSION {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      format!(
+        "unsupported state machine format version {}, the newest known is {}",
+        sm.format_version, CURRENT_FORMAT_VERSION
+      ),
+    ));
+  }
+
+  if sm.format_version == 0 {
+    let mut revisions = std::mem::take(&mut sm.legacy_revisions);
+    for (key, value) in std::mem::take(&mut sm.legacy_data) {
+      let revision = revisions.remove(&key).unwrap_or_default();
+      sm.unscoped_data.insert(
+        key,
+        pb::KeyValue {
+          value: value.into_bytes(),
+          create_revision: revision,
+          mod_revision: revision,
+          version: 1,
+          lease: 0,
+          content_type: String::new(),
+        },
+      );
+    }
+    sm.format_version = 1;
+  }
+
+  if sm.format_version == 1 {
+    let data = std::mem::take(&mut sm.unscoped_data);
+    if !data.is_empty() {
+      let bytes = data
+        .iter()
+        .map(|(key, kv)| namespace::size(key, &kv.value))
+        .sum();
+      sm.namespaces.insert(
+        namespace::DEFAULT.to_string(),
+        pb::Namespace {
+          data,
+          bytes,
+          quota: None,
+        },
+      );
+    }
+    for lease in sm.leases.values_mut() {
+      let keys = std::mem::take(&mut lease.unscoped_keys);
+      if !keys.is_empty() {
+        lease
+          .keys
+          .insert(namespace::DEFAULT.to_string(), pb::KeySet { keys });
+      }
+    }
+    sm.format_version = 2;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_migrate_from_version_0() {
+    let mut sm = pb::StateMachineData::default();
+    sm.legacy_data.insert("foo".to_string(), "bar".to_string());
+    sm.legacy_data.insert("baz".to_string(), "qux".to_string());
+    sm.legacy_revisions.insert("foo".to_string(), 7);
+
+    migrate(&mut sm).unwrap();
+
+    assert_eq!(sm.format_version, CURRENT_FORMAT_VERSION);
+    assert!(sm.legacy_data.is_empty());
+    assert!(sm.legacy_revisions.is_empty());
+    assert_eq!(
+      namespace::get(&sm, namespace::DEFAULT, "foo"),
+      Some(&pb::KeyValue {
+        value: b"bar".to_vec(),
+        create_revision: 7,
+        mod_revision: 7,
+        version: 1,
+        lease: 0,
+        content_type: String::new(),
+      })
+    );
+    let baz = namespace::get(&sm, namespace::DEFAULT, "baz");
+    assert_eq!(baz.map(|x| x.mod_revision), Some(0));
+    assert_eq!(sm.namespaces[namespace::DEFAULT].bytes, 12);
+  }
+
+  #[test]
+  fn test_migrate_from_version_1() {
+    let mut sm = pb::StateMachineData {
+      format_version: 1,
+      ..Default::default()
+    };
+    sm.unscoped_data.insert(
+      "foo".to_string(),
+      pb::KeyValue {
+        value: b"bar".to_vec(),
+        lease: 3,
+        ..Default::default()
+      },
+    );
+    let mut lease = pb::Lease {
+      id: 3,
+      ttl: 10,
+      ..Default::default()
+    };
+    lease.unscoped_keys.insert("foo".to_string(), ());
+    sm.leases.insert(3, lease);
+
+    migrate(&mut sm).unwrap();
+
+    assert!(sm.unscoped_data.is_empty());
+    assert_eq!(sm.namespaces[namespace::DEFAULT].data.len(), 1);
+    assert_eq!(sm.namespaces[namespace::DEFAULT].bytes, 6);
+    let lease = &sm.leases[&3];
+    assert!(lease.unscoped_keys.is_empty());
+    assert!(lease.keys[namespace::DEFAULT].keys.contains_key("foo"));
+  }
+
+  #[test]
+  fn test_reject_unknown_version() {
+    let mut sm = pb::StateMachineData {
+      format_version: CURRENT_FORMAT_VERSION + 1,
+      ..Default::default()
+    };
+    assert!(migrate(&mut sm).is_err());
+  }
+}
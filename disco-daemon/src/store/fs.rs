@@ -0,0 +1,79 @@
+//! Small filesystem helpers shared by the on-disk stores.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// Atomically replaces the file at `path` with `data`.
+///
+/// The data is written to a temporary file next to the target, flushed to disk and renamed over
+/// the target, so a crash leaves either the old or the new content but never a partial file.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+  let tmp = path.with_extension("tmp");
+  {
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+  }
+  fs::rename(&tmp, path)?;
+  match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => sync_dir(dir),
+    _ => sync_dir(Path::new(".")),
+  }
+}
+
+/// Flushes directory metadata so that created, renamed or removed entries survive a crash.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+  File::open(dir)?.sync_all()
+}
+
+/// The exclusive lock of a data directory, which keeps two processes from writing to the same
+/// stores. It is released when dropped, or when the process exits.
+#[derive(Debug)]
+pub struct DirLock {
+  _file: File,
+}
+
+impl DirLock {
+  /// Locks `dir`, creating it if it does not exist yet. Fails if the directory is already locked.
+  pub fn lock(dir: &Path) -> io::Result<Self> {
+    fs::create_dir_all(dir)?;
+    let file = OpenOptions::new()
+      .create(true)
+      .truncate(false)
+      .write(true)
+      .open(dir.join(LOCK_FILE))?;
+    match file.try_lock() {
+      Ok(()) => Ok(Self { _file: file }),
+      Err(TryLockError::WouldBlock) => Err(io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("{} is in use by another process", dir.display()),
+      )),
+      Err(TryLockError::Error(e)) => Err(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_dir_lock() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("data");
+
+    let lock = DirLock::lock(&path).unwrap();
+    let err = DirLock::lock(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    drop(lock);
+    DirLock::lock(&path).unwrap();
+  }
+}
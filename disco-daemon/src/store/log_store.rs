@@ -1,76 +1,350 @@
-//! Provide `LogStore`, which is a in-memory implementation of `RaftLogStore` for demonstration
-//! purpose only.
+//! Provide `LogStore`, a segment-file write-ahead log implementation of `RaftLogStorage`.
+//!
+//! The log is stored in a directory of append-only segment files named after the index of the
+//! first entry they contain. Every entry is written as a record:
+//!
+//! ```text
+//! | len: u32 LE | crc32: u32 LE | payload: protobuf encoded Entry |
+//! ```
+//!
+//! The vote, the committed log id and the last purged log id are kept in a small `meta` file
+//! that is replaced atomically. Only the location of each entry is kept in memory; entries are
+//! read back from the segment files on demand.
+//!
+//! On open, all segments are scanned. A torn or corrupted record at the tail of the last
+//! segment, as left behind by a crash in the middle of a write, is truncated away.
+//!
+//! The files are read and written, and flushed to disk, on the blocking threads of the runtime,
+//! one request at a time.
 
 use std::collections::BTreeMap;
-use std::fmt::Debug;
-use std::ops::RangeBounds;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::Bound;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
 
-use openraft::alias::LogIdOf;
-use openraft::alias::VoteOf;
 use openraft::entry::RaftEntry;
-use openraft::storage::IOFlushed;
-use openraft::LogState;
-use openraft::RaftTypeConfig;
-use openraft::StorageError;
-use tokio::sync::Mutex;
-
-/// RaftLogStore implementation with a in-memory storage
-#[derive(Clone, Debug, Default)]
-pub struct LogStore<C: RaftTypeConfig> {
-  inner: Arc<Mutex<LogStoreInner<C>>>,
+use prost::Message;
+
+use super::fs::sync_dir;
+use super::fs::write_atomic;
+use crate::protobuf as pb;
+use crate::raft_types::*;
+
+/// Size of the record header: payload length followed by the payload checksum.
+const RECORD_HEADER_SIZE: u64 = 8;
+
+/// A new segment is started once the active one grows beyond this size.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "seg";
+const META_FILE: &str = "meta";
+
+/// RaftLogStore implementation backed by segment files on disk.
+#[derive(Clone, Debug)]
+pub struct LogStore {
+  inner: Arc<Mutex<LogStoreInner>>,
+}
+
+impl LogStore {
+  /// Opens the log stored in `dir`, creating it if it does not exist yet.
+  pub fn open(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+    Self::open_with_segment_size(dir, DEFAULT_SEGMENT_SIZE)
+  }
+
+  /// Opens the log stored in `dir`, rolling over to a new segment file every `segment_size` bytes.
+  pub fn open_with_segment_size(
+    dir: impl AsRef<Path>,
+    segment_size: u64,
+  ) -> Result<Self, StorageError> {
+    let inner = LogStoreInner::open(dir.as_ref(), segment_size)?;
+    Ok(Self {
+      inner: Arc::new(Mutex::new(inner)),
+    })
+  }
+
+  /// Runs `f` on a blocking thread, with the log locked.
+  async fn run<T, F>(&self, f: F) -> Result<T, StorageError>
+  where
+    F: FnOnce(&mut LogStoreInner) -> Result<T, StorageError> + Send + 'static,
+    T: Send + 'static,
+  {
+    let inner = self.inner.clone();
+    tokio::task::spawn_blocking(move || f(&mut inner.lock().unwrap()))
+      .await
+      .map_err(|e| StorageError::write_logs(&e))?
+  }
+}
+
+/// Location of a single entry inside a segment file.
+#[derive(Debug, Clone, Copy)]
+struct EntryPos {
+  /// Key of the segment, i.e. the index of its first entry.
+  segment: u64,
+  /// Offset of the record header in the segment file.
+  offset: u64,
+  /// Length of the encoded entry.
+  len: u32,
+  log_id: LogId,
+}
+
+#[derive(Debug)]
+struct Segment {
+  path: PathBuf,
+  file: File,
+  len: u64,
+}
+
+impl Segment {
+  fn create(dir: &Path, first_index: u64) -> io::Result<Self> {
+    let path = segment_path(dir, first_index);
+    let file = OpenOptions::new()
+      .read(true)
+      .append(true)
+      .create(true)
+      .open(&path)?;
+    sync_dir(dir)?;
+    Ok(Self { path, file, len: 0 })
+  }
+
+  fn read_entry(&mut self, pos: &EntryPos) -> io::Result<pb::Entry> {
+    let mut buf = vec![0; pos.len as usize];
+    self
+      .file
+      .seek(SeekFrom::Start(pos.offset + RECORD_HEADER_SIZE))?;
+    self.file.read_exact(&mut buf)?;
+    decode_entry(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
 }
 
 #[derive(Debug)]
-pub struct LogStoreInner<C: RaftTypeConfig> {
-  /// The last purged log id.
-  last_purged_log_id: Option<LogIdOf<C>>,
+struct LogStoreInner {
+  dir: PathBuf,
+
+  segment_size: u64,
 
-  /// The Raft log.
-  log: BTreeMap<u64, C::Entry>,
+  /// The vote, committed log id and last purged log id.
+  meta: pb::LogMeta,
 
-  /// The commit log id.
-  committed: Option<LogIdOf<C>>,
+  /// Segment files by the index of their first entry.
+  segments: BTreeMap<u64, Segment>,
 
-  /// The current granted vote.
-  vote: Option<VoteOf<C>>,
+  /// Location of every entry in the log that has not been purged.
+  index: BTreeMap<u64, EntryPos>,
 }
 
-impl<C: RaftTypeConfig> Default for LogStoreInner<C> {
-  fn default() -> Self {
-    Self {
-      last_purged_log_id: None,
-      log: BTreeMap::new(),
-      committed: None,
-      vote: None,
-    }
+fn segment_path(dir: &Path, first_index: u64) -> PathBuf {
+  dir.join(format!("{:020}.{}", first_index, SEGMENT_EXTENSION))
+}
+
+fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Decodes a log entry, reading the set request of an entry written before commands as the
+/// command it stands for.
+fn decode_entry(buf: &[u8]) -> Result<pb::Entry, prost::DecodeError> {
+  let mut entry = pb::Entry::decode(buf)?;
+  if let Some(legacy) = entry.legacy_app_data.take() {
+    entry.app_data.get_or_insert_with(|| legacy.into());
   }
+  Ok(entry)
 }
 
-impl<C: RaftTypeConfig> LogStoreInner<C> {
-  async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug>(
-    &mut self,
-    range: RB,
-  ) -> Result<Vec<C::Entry>, StorageError<C>>
-  where
-    C::Entry: Clone,
-  {
-    let response = self
-      .log
-      .range(range.clone())
-      .map(|(_, val)| val.clone())
-      .collect::<Vec<_>>();
-    Ok(response)
+/// Result of reading one record from a segment during recovery.
+enum Record {
+  Entry(pb::Entry, u32),
+  /// Clean end of the segment.
+  End,
+  /// A partially written or corrupted record.
+  Torn(String),
+}
+
+fn read_record(reader: &mut impl Read, remaining: u64) -> io::Result<Record> {
+  if remaining == 0 {
+    return Ok(Record::End);
+  }
+  if remaining < RECORD_HEADER_SIZE {
+    return Ok(Record::Torn("incomplete record header".to_string()));
   }
 
-  async fn get_log_state(&mut self) -> Result<LogState<C>, StorageError<C>> {
-    let last = self.log.iter().next_back().map(|(_, ent)| ent.log_id());
+  let mut header = [0u8; RECORD_HEADER_SIZE as usize];
+  reader.read_exact(&mut header)?;
+  let len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+  let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
 
-    let last_purged = self.last_purged_log_id.clone();
+  if remaining - RECORD_HEADER_SIZE < len as u64 {
+    return Ok(Record::Torn(format!("incomplete record of {} bytes", len)));
+  }
+
+  let mut payload = vec![0; len as usize];
+  reader.read_exact(&mut payload)?;
+  if crc32fast::hash(&payload) != crc {
+    return Ok(Record::Torn("record checksum mismatch".to_string()));
+  }
+
+  match decode_entry(&payload) {
+    Ok(entry) => Ok(Record::Entry(entry, len)),
+    Err(e) => Ok(Record::Torn(format!("undecodable record: {}", e))),
+  }
+}
+
+impl LogStoreInner {
+  fn open(dir: &Path, segment_size: u64) -> Result<Self, StorageError> {
+    fs::create_dir_all(dir).map_err(|e| StorageError::read_logs(&e))?;
+
+    let meta = match fs::read(dir.join(META_FILE)) {
+      Ok(buf) => pb::LogMeta::decode(buf.as_slice()).map_err(|e| StorageError::read_vote(&e))?,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => pb::LogMeta::default(),
+      Err(e) => return Err(StorageError::read_vote(&e)),
+    };
+
+    let mut inner = Self {
+      dir: dir.to_path_buf(),
+      segment_size,
+      meta,
+      segments: BTreeMap::new(),
+      index: BTreeMap::new(),
+    };
+    inner.recover().map_err(|e| StorageError::read_logs(&e))?;
+    Ok(inner)
+  }
+
+  /// Scans all segment files, rebuilding the entry index and truncating a torn tail.
+  fn recover(&mut self) -> io::Result<()> {
+    let mut first_indexes = Vec::new();
+    for dir_entry in fs::read_dir(&self.dir)? {
+      let path = dir_entry?.path();
+      if path.extension().and_then(|x| x.to_str()) != Some(SEGMENT_EXTENSION) {
+        continue;
+      }
+      let first_index = path
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.parse::<u64>().ok())
+        .ok_or_else(|| invalid_data(format!("invalid segment file name: {}", path.display())))?;
+      first_indexes.push(first_index);
+    }
+    first_indexes.sort_unstable();
+
+    let purged_index = self.meta.last_purged_log_id.map(|x| x.index);
+    let last = first_indexes.len();
+
+    for (i, first_index) in first_indexes.into_iter().enumerate() {
+      let is_last = i + 1 == last;
+      let path = segment_path(&self.dir, first_index);
+      let mut file = OpenOptions::new().read(true).append(true).open(&path)?;
+      let file_len = file.metadata()?.len();
+
+      let mut reader = io::BufReader::new(&mut file);
+      let mut offset = 0;
+      let mut expected_index = first_index;
+      loop {
+        let (entry, len) = match read_record(&mut reader, file_len - offset)? {
+          Record::Entry(entry, len) => (entry, len),
+          Record::End => break,
+          Record::Torn(reason) if is_last => {
+            tracing::warn!(
+              "truncating torn tail of {} at offset {}: {}",
+              path.display(),
+              offset,
+              reason
+            );
+            break;
+          }
+          Record::Torn(reason) => {
+            return Err(invalid_data(format!(
+              "corrupted record in {} at offset {}: {}",
+              path.display(),
+              offset,
+              reason
+            )));
+          }
+        };
+
+        if entry.index != expected_index {
+          return Err(invalid_data(format!(
+            "unexpected entry index {} in {}, expected {}",
+            entry.index,
+            path.display(),
+            expected_index
+          )));
+        }
+
+        if Some(entry.index) > purged_index {
+          self.index.insert(entry.index, EntryPos {
+            segment: first_index,
+            offset,
+            len,
+            log_id: entry.log_id(),
+          });
+        }
+
+        offset += RECORD_HEADER_SIZE + len as u64;
+        expected_index += 1;
+      }
+      drop(reader);
+
+      if offset < file_len {
+        file.set_len(offset)?;
+        file.sync_all()?;
+      }
+
+      if offset == 0 {
+        // Nothing valid was ever written to this segment.
+        drop(file);
+        fs::remove_file(&path)?;
+        sync_dir(&self.dir)?;
+        continue;
+      }
+
+      self.segments.insert(first_index, Segment {
+        path,
+        file,
+        len: offset,
+      });
+    }
+
+    Ok(())
+  }
+
+  fn save_meta(&self) -> io::Result<()> {
+    write_atomic(&self.dir.join(META_FILE), &self.meta.encode_to_vec())
+  }
 
-    let last = match last {
-      None => last_purged.clone(),
-      Some(x) => Some(x),
+  fn try_get_log_entries(
+    &mut self,
+    range: (Bound<u64>, Bound<u64>),
+  ) -> Result<Vec<Entry>, StorageError> {
+    let mut entries = Vec::new();
+    for (_, pos) in self.index.range(range) {
+      let segment = self
+        .segments
+        .get_mut(&pos.segment)
+        .expect("indexed entry must belong to a segment");
+      let entry = segment
+        .read_entry(pos)
+        .map_err(|e| StorageError::read_logs(&e))?;
+      entries.push(entry);
+    }
+    Ok(entries)
+  }
+
+  fn get_log_state(&mut self) -> Result<LogState, StorageError> {
+    let last_purged: Option<LogId> = self.meta.last_purged_log_id.map(From::from);
+
+    let last = match self.index.last_key_value() {
+      None => last_purged,
+      Some((_, pos)) => Some(pos.log_id),
     };
 
     Ok(LogState {
@@ -79,66 +353,136 @@ impl<C: RaftTypeConfig> LogStoreInner<C> {
     })
   }
 
-  async fn save_committed(&mut self, committed: Option<LogIdOf<C>>) -> Result<(), StorageError<C>> {
-    self.committed = committed;
-    Ok(())
+  fn save_committed(&mut self, committed: Option<LogId>) -> Result<(), StorageError> {
+    self.meta.committed = committed.map(From::from);
+    self.save_meta().map_err(|e| StorageError::write(&e))
   }
 
-  async fn read_committed(&mut self) -> Result<Option<LogIdOf<C>>, StorageError<C>> {
-    Ok(self.committed.clone())
+  fn read_committed(&mut self) -> Result<Option<LogId>, StorageError> {
+    Ok(self.meta.committed.map(From::from))
   }
 
-  async fn save_vote(&mut self, vote: &VoteOf<C>) -> Result<(), StorageError<C>> {
-    self.vote = Some(vote.clone());
-    Ok(())
+  fn save_vote(&mut self, vote: &Vote) -> Result<(), StorageError> {
+    self.meta.vote = Some(*vote);
+    self.save_meta().map_err(|e| StorageError::write_vote(&e))
   }
 
-  async fn read_vote(&mut self) -> Result<Option<VoteOf<C>>, StorageError<C>> {
-    Ok(self.vote.clone())
+  fn read_vote(&mut self) -> Result<Option<Vote>, StorageError> {
+    Ok(self.meta.vote)
   }
 
-  async fn append<I>(&mut self, entries: I, callback: IOFlushed<C>) -> Result<(), StorageError<C>>
+  fn append(&mut self, entries: Vec<Entry>) -> Result<(), StorageError> {
+    self
+      .append_entries(entries)
+      .map_err(|e| StorageError::write_logs(&e))
+  }
+
+  /// Writes the entries to the active segment and flushes them to disk.
+  fn append_entries<I>(&mut self, entries: I) -> io::Result<()>
   where
-    I: IntoIterator<Item = C::Entry>,
+    I: IntoIterator<Item = Entry>,
   {
-    // Simple implementation that calls the flush-before-return `append_to_log`.
+    let mut buf = Vec::new();
+    let mut pending = Vec::new();
+
     for entry in entries {
-      self.log.insert(entry.index(), entry);
+      let active_full = match self.segments.last_key_value() {
+        Some((_, segment)) => segment.len + buf.len() as u64 >= self.segment_size,
+        None => true,
+      };
+      if active_full {
+        self.flush_active(&mut buf, &mut pending)?;
+        let segment = Segment::create(&self.dir, entry.index)?;
+        self.segments.insert(entry.index, segment);
+      }
+
+      let (segment_key, segment) = self.segments.last_key_value().unwrap();
+      let payload = entry.encode_to_vec();
+      pending.push(EntryPos {
+        segment: *segment_key,
+        offset: segment.len + buf.len() as u64,
+        len: payload.len() as u32,
+        log_id: entry.log_id(),
+      });
+      buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+      buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+      buf.extend_from_slice(&payload);
+    }
+
+    self.flush_active(&mut buf, &mut pending)
+  }
+
+  /// Writes buffered records to the active segment, fsyncs it and indexes the written entries.
+  fn flush_active(&mut self, buf: &mut Vec<u8>, pending: &mut Vec<EntryPos>) -> io::Result<()> {
+    if buf.is_empty() {
+      return Ok(());
     }
-    callback.io_completed(Ok(()));
 
+    let segment = self.segments.values_mut().next_back().unwrap();
+    segment.file.write_all(buf)?;
+    segment.file.sync_data()?;
+    segment.len += buf.len() as u64;
+    buf.clear();
+
+    for pos in pending.drain(..) {
+      self.index.insert(pos.log_id.index(), pos);
+    }
     Ok(())
   }
 
-  async fn truncate(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
-    let keys = self
-      .log
-      .range(log_id.index()..)
-      .map(|(k, _v)| *k)
-      .collect::<Vec<_>>();
-    for key in keys {
-      self.log.remove(&key);
+  fn truncate(&mut self, log_id: LogId) -> Result<(), StorageError> {
+    self
+      .truncate_from(log_id.index())
+      .map_err(|e| StorageError::write_logs(&e))
+  }
+
+  /// Removes all entries with an index greater than or equal to `index`.
+  fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+    let Some((_, first_removed)) = self.index.range(index..).next() else {
+      return Ok(());
+    };
+    let first_removed = *first_removed;
+
+    let removed_segments = self.segments.split_off(&(first_removed.segment + 1));
+    for (_, segment) in removed_segments {
+      fs::remove_file(&segment.path)?;
     }
 
+    if first_removed.offset == 0 {
+      let segment = self.segments.remove(&first_removed.segment).unwrap();
+      fs::remove_file(&segment.path)?;
+    } else {
+      let segment = self.segments.get_mut(&first_removed.segment).unwrap();
+      segment.file.set_len(first_removed.offset)?;
+      segment.file.sync_all()?;
+      segment.len = first_removed.offset;
+    }
+    sync_dir(&self.dir)?;
+
+    let removed = self.index.split_off(&index);
+    tracing::debug!("truncated {} log entries from index {}", removed.len(), index);
     Ok(())
   }
 
-  async fn purge(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
+  fn purge(&mut self, log_id: LogId) -> Result<(), StorageError> {
     {
-      let ld = &mut self.last_purged_log_id;
-      assert!(ld.as_ref() <= Some(&log_id));
-      *ld = Some(log_id.clone());
+      let last_purged: Option<LogId> = self.meta.last_purged_log_id.map(From::from);
+      assert!(last_purged <= Some(log_id));
+      self.meta.last_purged_log_id = Some(log_id.into());
+      self.save_meta().map_err(|e| StorageError::write_logs(&e))?;
     }
 
-    {
-      let keys = self
-        .log
-        .range(..=log_id.index())
-        .map(|(k, _v)| *k)
-        .collect::<Vec<_>>();
-      for key in keys {
-        self.log.remove(&key);
-      }
+    self.index = self.index.split_off(&(log_id.index() + 1));
+
+    // Every segment before the one holding the first remaining entry is fully purged.
+    let keep_from = match self.index.first_key_value() {
+      Some((_, pos)) => pos.segment,
+      None => u64::MAX,
+    };
+    let kept = self.segments.split_off(&keep_from);
+    let purged = std::mem::replace(&mut self.segments, kept);
+    for (_, segment) in purged {
+      fs::remove_file(&segment.path).map_err(|e| StorageError::write_logs(&e))?;
     }
 
     Ok(())
@@ -149,80 +493,64 @@ mod impl_log_store {
   use std::fmt::Debug;
   use std::ops::RangeBounds;
 
-  use openraft::alias::LogIdOf;
-  use openraft::alias::VoteOf;
-  use openraft::storage::IOFlushed;
   use openraft::storage::RaftLogStorage;
-  use openraft::LogState;
   use openraft::RaftLogReader;
-  use openraft::RaftTypeConfig;
-  use openraft::StorageError;
 
   use super::LogStore;
+  use crate::raft_types::*;
+  use crate::TypeConfig;
 
-  impl<C: RaftTypeConfig> RaftLogReader<C> for LogStore<C>
-  where
-    C::Entry: Clone,
-  {
+  impl RaftLogReader<TypeConfig> for LogStore {
     async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug>(
       &mut self,
       range: RB,
-    ) -> Result<Vec<C::Entry>, StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.try_get_log_entries(range).await
+    ) -> Result<Vec<Entry>, StorageError> {
+      let range = (range.start_bound().cloned(), range.end_bound().cloned());
+      self.run(move |x| x.try_get_log_entries(range)).await
     }
 
-    async fn read_vote(&mut self) -> Result<Option<VoteOf<C>>, StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.read_vote().await
+    async fn read_vote(&mut self) -> Result<Option<Vote>, StorageError> {
+      self.run(|x| x.read_vote()).await
     }
   }
 
-  impl<C: RaftTypeConfig> RaftLogStorage<C> for LogStore<C>
-  where
-    C::Entry: Clone,
-  {
+  impl RaftLogStorage<TypeConfig> for LogStore {
     type LogReader = Self;
 
-    async fn get_log_state(&mut self) -> Result<LogState<C>, StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.get_log_state().await
+    async fn get_log_state(&mut self) -> Result<LogState, StorageError> {
+      self.run(|x| x.get_log_state()).await
     }
 
-    async fn save_committed(
-      &mut self,
-      committed: Option<LogIdOf<C>>,
-    ) -> Result<(), StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.save_committed(committed).await
+    async fn save_committed(&mut self, committed: Option<LogId>) -> Result<(), StorageError> {
+      self.run(move |x| x.save_committed(committed)).await
     }
 
-    async fn read_committed(&mut self) -> Result<Option<LogIdOf<C>>, StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.read_committed().await
+    async fn read_committed(&mut self) -> Result<Option<LogId>, StorageError> {
+      self.run(|x| x.read_committed()).await
     }
 
-    async fn save_vote(&mut self, vote: &VoteOf<C>) -> Result<(), StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.save_vote(vote).await
+    async fn save_vote(&mut self, vote: &Vote) -> Result<(), StorageError> {
+      let vote = *vote;
+      self.run(move |x| x.save_vote(&vote)).await
     }
 
-    async fn append<I>(&mut self, entries: I, callback: IOFlushed<C>) -> Result<(), StorageError<C>>
+    async fn append<I>(&mut self, entries: I, callback: IOFlushed) -> Result<(), StorageError>
     where
-      I: IntoIterator<Item = C::Entry>,
+      I: IntoIterator<Item = Entry>,
     {
-      let mut inner = self.inner.lock().await;
-      inner.append(entries, callback).await
+      let entries = entries.into_iter().collect::<Vec<_>>();
+      self.run(move |x| x.append(entries)).await?;
+      callback.io_completed(Ok(()));
+
+      Ok(())
     }
 
-    async fn truncate(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.truncate(log_id).await
+    async fn truncate(&mut self, log_id: LogId) -> Result<(), StorageError> {
+      self.run(move |x| x.truncate(log_id)).await
     }
 
-    async fn purge(&mut self, log_id: LogIdOf<C>) -> Result<(), StorageError<C>> {
-      let mut inner = self.inner.lock().await;
-      inner.purge(log_id).await
+    async fn purge(&mut self, log_id: LogId) -> Result<(), StorageError> {
+      self.run(move |x| x.purge(log_id)).await
     }
 
     async fn get_log_reader(&mut self) -> Self::LogReader {
@@ -230,3 +558,174 @@ mod impl_log_store {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use openraft::storage::RaftLogStorage;
+  use openraft::RaftLogReader;
+
+  use super::*;
+
+  fn entry(index: u64) -> Entry {
+    pb::Entry {
+      term: 1,
+      index,
+      legacy_app_data: None,
+      app_data: Some(
+        pb::SetRequest {
+          key: format!("key-{}", index),
+          value: format!("value-{}", index).into_bytes(),
+          lease: 0,
+          namespace: String::new(),
+          content_type: String::new(),
+        }
+        .into(),
+      ),
+      membership: None,
+    }
+  }
+
+  async fn append(store: &LogStore, range: std::ops::RangeInclusive<u64>) {
+    let mut inner = store.inner.lock().unwrap();
+    inner.append_entries(range.map(entry)).unwrap();
+  }
+
+  fn last_segment(dir: &Path) -> PathBuf {
+    let mut segments = fs::read_dir(dir)
+      .unwrap()
+      .map(|x| x.unwrap().path())
+      .filter(|x| x.extension().and_then(|x| x.to_str()) == Some(SEGMENT_EXTENSION))
+      .collect::<Vec<_>>();
+    segments.sort();
+    segments.pop().unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_restart_recovers_log_and_vote() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+      let mut store = LogStore::open_with_segment_size(dir.path(), 256).unwrap();
+      append(&store, 1..=20).await;
+      let vote = pb::Vote {
+        leader_id: Some(pb::LeaderId {
+          term: 3,
+          node_id: 2,
+        }),
+        committed: true,
+      };
+      store.save_vote(&vote).await.unwrap();
+      store.save_committed(Some(LogId::new(1, 15))).await.unwrap();
+      // Dropped without any shutdown, as if the process was killed.
+    }
+
+    let mut store = LogStore::open_with_segment_size(dir.path(), 256).unwrap();
+    let state = store.get_log_state().await.unwrap();
+    assert_eq!(state.last_log_id, Some(LogId::new(1, 20)));
+    assert_eq!(store.read_committed().await.unwrap(), Some(LogId::new(1, 15)));
+    assert_eq!(store.read_vote().await.unwrap().unwrap().leader_id.unwrap().term, 3);
+
+    let entries = store.try_get_log_entries(1..=20).await.unwrap();
+    assert_eq!(entries, (1..=20).map(entry).collect::<Vec<_>>());
+  }
+
+  #[tokio::test]
+  async fn test_read_entries_written_before_commands() {
+    let dir = tempfile::tempdir().unwrap();
+    let set = pb::SetRequest {
+      key: "foo".to_string(),
+      value: b"bar".to_vec(),
+      ..Default::default()
+    };
+
+    {
+      let store = LogStore::open(dir.path()).unwrap();
+      let legacy = pb::Entry {
+        term: 1,
+        index: 1,
+        legacy_app_data: Some(set.clone()),
+        app_data: None,
+        membership: None,
+      };
+      let mut inner = store.inner.lock().unwrap();
+      inner.append_entries([legacy]).unwrap();
+    }
+
+    let mut store = LogStore::open(dir.path()).unwrap();
+    let entries = store.try_get_log_entries(1..=1).await.unwrap();
+    assert_eq!(entries[0].app_data, Some(set.into()));
+    assert_eq!(entries[0].legacy_app_data, None);
+  }
+
+  #[tokio::test]
+  async fn test_restart_truncates_torn_tail() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+      let store = LogStore::open(dir.path()).unwrap();
+      append(&store, 1..=5).await;
+    }
+
+    // Simulate a crash halfway through writing the sixth record.
+    let segment = last_segment(dir.path());
+    let valid_len = fs::metadata(&segment).unwrap().len();
+    {
+      let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+      file.write_all(&100u32.to_le_bytes()).unwrap();
+      file.write_all(&[0xab; 30]).unwrap();
+    }
+
+    let mut store = LogStore::open(dir.path()).unwrap();
+    let state = store.get_log_state().await.unwrap();
+    assert_eq!(state.last_log_id, Some(LogId::new(1, 5)));
+    assert_eq!(fs::metadata(&segment).unwrap().len(), valid_len);
+
+    // The log keeps working after recovery.
+    append(&store, 6..=7).await;
+    drop(store);
+
+    let mut store = LogStore::open(dir.path()).unwrap();
+    let entries = store.try_get_log_entries(1..).await.unwrap();
+    assert_eq!(entries, (1..=7).map(entry).collect::<Vec<_>>());
+  }
+
+  #[tokio::test]
+  async fn test_restart_drops_corrupted_tail_record() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+      let store = LogStore::open(dir.path()).unwrap();
+      append(&store, 1..=3).await;
+    }
+
+    // Flip the last byte of the final record so that its checksum no longer matches.
+    let segment = last_segment(dir.path());
+    let mut data = fs::read(&segment).unwrap();
+    *data.last_mut().unwrap() ^= 0xff;
+    fs::write(&segment, data).unwrap();
+
+    let mut store = LogStore::open(dir.path()).unwrap();
+    let state = store.get_log_state().await.unwrap();
+    assert_eq!(state.last_log_id, Some(LogId::new(1, 2)));
+  }
+
+  #[tokio::test]
+  async fn test_restart_after_truncate_and_purge() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+      let mut store = LogStore::open_with_segment_size(dir.path(), 128).unwrap();
+      append(&store, 1..=30).await;
+      store.truncate(LogId::new(1, 21)).await.unwrap();
+      store.purge(LogId::new(1, 10)).await.unwrap();
+    }
+
+    let mut store = LogStore::open_with_segment_size(dir.path(), 128).unwrap();
+    let state = store.get_log_state().await.unwrap();
+    assert_eq!(state.last_purged_log_id, Some(LogId::new(1, 10)));
+    assert_eq!(state.last_log_id, Some(LogId::new(1, 20)));
+
+    let entries = store.try_get_log_entries(0..100).await.unwrap();
+    assert_eq!(entries, (11..=20).map(entry).collect::<Vec<_>>());
+  }
+}
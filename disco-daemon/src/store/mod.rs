@@ -1,4 +1,7 @@
+use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 
@@ -11,92 +14,261 @@ use crate::protobuf::Response;
 use crate::raft_types::*;
 use crate::TypeConfig;
 
+mod command;
+mod format;
+mod fs;
 pub mod log_store;
-pub type LogStore = log_store::LogStore<TypeConfig>;
+pub mod namespace;
+mod range;
+pub mod snapshot_file;
+pub mod snapshot_store;
+pub mod watch;
+pub use fs::DirLock;
+pub use log_store::LogStore;
+pub use snapshot_file::SnapshotFile;
+pub use snapshot_store::SnapshotStore;
+pub use watch::KeyFilter;
+pub use watch::WatchError;
+pub use watch::WatchHub;
+pub use watch::Watcher;
 
 #[derive(Debug)]
 pub struct StoredSnapshot {
   pub meta: SnapshotMeta,
 
-  /// The data of the state machine at the time of this snapshot.
-  pub data: SnapshotData,
+  /// The file holding the data of the state machine at the time of this snapshot.
+  pub path: PathBuf,
 }
 
 /// Defines a state machine for the Raft cluster. This state machine represents a copy of the
 /// data for this node. Additionally, it is responsible for storing the last snapshot of the data.
-#[derive(Debug, Default)]
+///
+/// The state machine itself is kept in memory. Snapshots are persisted in a [`SnapshotStore`],
+/// and on startup the state machine is restored from the latest one, so that only the log entries
+/// applied after it need to be replayed.
+#[derive(Debug)]
 pub struct StateMachineStore {
   /// The Raft state machine.
   pub state_machine: Mutex<pb::StateMachineData>,
 
   snapshot_idx: Mutex<u64>,
 
-  /// The last received snapshot.
+  /// Where snapshots are persisted.
+  snapshot_store: SnapshotStore,
+
+  /// The last built or received snapshot.
   current_snapshot: Mutex<Option<StoredSnapshot>>,
+
+  /// Distributes applied changes to watchers.
+  watch_hub: WatchHub,
+}
+
+impl StateMachineStore {
+  /// Opens the state machine, restoring it from the latest snapshot stored in `snapshot_dir`.
+  pub fn open(snapshot_dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+    let snapshot_store = SnapshotStore::open(snapshot_dir)?;
+
+    let (state_machine, current_snapshot) = match snapshot_store.load_latest()? {
+      Some(loaded) => {
+        tracing::info!("restoring state machine from snapshot {}", loaded.snapshot_id);
+        let meta = snapshot_meta(&loaded.state_machine, loaded.snapshot_id);
+        let stored = StoredSnapshot {
+          meta,
+          path: loaded.path,
+        };
+        snapshot_store.purge_except(&stored.path)?;
+        (loaded.state_machine, Some(stored))
+      }
+      None => {
+        let state_machine = pb::StateMachineData {
+          format_version: format::CURRENT_FORMAT_VERSION,
+          ..Default::default()
+        };
+        (state_machine, None)
+      }
+    };
+
+    let last_applied = state_machine.last_applied.map(|x| x.index);
+    let snapshot_idx = current_snapshot
+      .as_ref()
+      .map(|x| parse_snapshot_idx(&x.meta.snapshot_id))
+      .unwrap_or_default();
+
+    Ok(Self {
+      state_machine: Mutex::new(state_machine),
+      snapshot_idx: Mutex::new(snapshot_idx),
+      snapshot_store,
+      current_snapshot: Mutex::new(current_snapshot),
+      watch_hub: WatchHub::new(last_applied.unwrap_or_default()),
+    })
+  }
+
+  /// Makes `stored` the current snapshot unless a newer one is already in place, then removes
+  /// every other snapshot file.
+  fn set_current_snapshot(&self, stored: StoredSnapshot) -> Result<(), StorageError> {
+    let mut current_snapshot = self.current_snapshot.lock().unwrap();
+
+    if let Some(current) = &*current_snapshot {
+      if current.meta.last_log_id > stored.meta.last_log_id {
+        tracing::debug!(
+          "discarding snapshot {}, {} is newer",
+          stored.meta.snapshot_id,
+          current.meta.snapshot_id
+        );
+        return self.snapshot_store.purge_except(&current.path);
+      }
+    }
+
+    self.snapshot_store.purge_except(&stored.path)?;
+    *current_snapshot = Some(stored);
+    Ok(())
+  }
+
+  /// Reads `key` of `namespace` and its revision metadata from the local state machine.
+  ///
+  /// `store_revision` in the response is the last log index applied locally, which tells how
+  /// recent the read is.
+  pub fn get(&self, namespace: &str, key: &str) -> Response {
+    let sm = self.state_machine.lock().unwrap();
+    let kv = namespace::get(&sm, namespace::resolve(namespace), key);
+    Response {
+      store_revision: sm.last_applied.map(|x| x.index).unwrap_or_default(),
+      ..command::response(kv)
+    }
+  }
+
+  /// Describes the usage and the quota of `namespace` in the local state machine.
+  pub fn namespace(&self, namespace: &str) -> pb::NamespaceResponse {
+    let namespace = namespace::resolve(namespace);
+    let sm = self.state_machine.lock().unwrap();
+    let ns = sm.namespaces.get(namespace);
+    pb::NamespaceResponse {
+      namespace: namespace.to_string(),
+      keys: ns.map(|x| x.data.len() as u64).unwrap_or_default(),
+      bytes: ns.map(|x| x.bytes).unwrap_or_default(),
+      quota: Some(ns.and_then(|x| x.quota).unwrap_or_default()),
+    }
+  }
+
+  /// Lists the keys selected by `req` from the local state machine.
+  pub fn range(&self, req: &pb::RangeRequest) -> pb::RangeResponse {
+    let sm = self.state_machine.lock().unwrap();
+    pb::RangeResponse {
+      store_revision: sm.last_applied.map(|x| x.index).unwrap_or_default(),
+      ..range::range(&sm, req)
+    }
+  }
+
+  /// Watches the changes made to the keys selected by `filter`, starting from `start_revision`
+  /// if it is set, or from the next change otherwise.
+  pub fn watch(
+    &self,
+    filter: KeyFilter,
+    start_revision: Option<u64>,
+  ) -> Result<Watcher, WatchError> {
+    self.watch_hub.watch(filter, start_revision)
+  }
+
+  /// Returns the ttl, in seconds, of every lease in the local state machine, by lease id.
+  pub fn leases(&self) -> BTreeMap<u64, u64> {
+    let sm = self.state_machine.lock().unwrap();
+    sm.leases
+      .iter()
+      .map(|(id, lease)| (*id, lease.ttl))
+      .collect()
+  }
+
+  /// Returns the ttl of lease `id`, in seconds, if it exists.
+  pub fn lease_ttl(&self, id: u64) -> Option<u64> {
+    let sm = self.state_machine.lock().unwrap();
+    sm.leases.get(&id).map(|lease| lease.ttl)
+  }
+
+  /// Creates a file to receive a snapshot streamed from the leader.
+  pub fn begin_receiving(&self) -> Result<SnapshotFile, StorageError> {
+    self.snapshot_store.create_temp()
+  }
+}
+
+/// Builds the snapshot meta describing a serialized state machine.
+fn snapshot_meta(state_machine: &pb::StateMachineData, snapshot_id: String) -> SnapshotMeta {
+  let last_membership_log_id = state_machine
+    .last_membership_log_id
+    .map(|log_id| log_id.into());
+  let membership = state_machine
+    .last_membership
+    .clone()
+    .unwrap_or_default()
+    .into();
+
+  SnapshotMeta {
+    last_log_id: state_machine.last_applied.map(From::from),
+    last_membership: StoredMembership::new(last_membership_log_id, membership),
+    snapshot_id,
+  }
+}
+
+/// Returns the counter ending a snapshot id, so that snapshots built after a restart do not
+/// reuse the id of one already on disk.
+fn parse_snapshot_idx(snapshot_id: &str) -> u64 {
+  snapshot_id
+    .rsplit('-')
+    .next()
+    .and_then(|x| x.parse().ok())
+    .unwrap_or_default()
 }
 
 impl RaftSnapshotBuilder<TypeConfig> for Arc<StateMachineStore> {
   #[tracing::instrument(level = "trace", skip(self))]
   async fn build_snapshot(&mut self) -> Result<Snapshot, StorageError> {
-    let data;
-    let last_applied: Option<LogId>;
-    let last_membership;
-
-    {
-      // Serialize the data of the state machine.
-      let state_machine = self.state_machine.lock().unwrap().clone();
-
-      last_applied = state_machine.last_applied.map(From::from);
-      let last_membership_log_id = state_machine
-        .last_membership_log_id
-        .map(|log_id| log_id.into());
-      let membership = state_machine
-        .last_membership
-        .clone()
-        .unwrap_or_default()
-        .into();
-      last_membership = StoredMembership::new(last_membership_log_id, membership);
-
-      data = prost::Message::encode_to_vec(&state_machine);
-    }
+    // The state machine is written to disk as it is encoded
+    let store = self.clone();
+    tokio::task::spawn_blocking(move || store.write_snapshot())
+      .await
+      .map_err(|e| StorageError::write_snapshot(None, &e))?
+  }
+}
 
+impl StateMachineStore {
+  /// Writes a snapshot of the state machine to a new file, and makes it the current snapshot.
+  fn write_snapshot(&self) -> Result<Snapshot, StorageError> {
     let snapshot_idx = {
       let mut l = self.snapshot_idx.lock().unwrap();
       *l += 1;
       *l
     };
 
-    let snapshot_id = if let Some(last) = &last_applied {
-      format!(
-        "{}-{}-{}",
-        last.committed_leader_id(),
-        last.index(),
-        snapshot_idx
-      )
-    } else {
-      format!("--{}", snapshot_idx)
-    };
+    // Serialize the data of the state machine straight into the file, without cloning it nor
+    // holding the encoded data in memory first.
+    let mut file = self.snapshot_store.create_temp()?;
+    let meta = {
+      let state_machine = self.state_machine.lock().unwrap();
 
-    let meta = SnapshotMeta {
-      last_log_id: last_applied,
-      last_membership,
-      snapshot_id,
-    };
+      let snapshot_id = if let Some(last) = &state_machine.last_applied {
+        format!("{}-{}-{}", last.term, last.index, snapshot_idx)
+      } else {
+        format!("--{}", snapshot_idx)
+      };
 
-    let stored = StoredSnapshot {
-      meta: meta.clone(),
-      data: data.clone(),
+      file
+        .encode(&*state_machine)
+        .map_err(|e| StorageError::write_snapshot(None, &e))?;
+      snapshot_meta(&state_machine, snapshot_id)
     };
 
-    // Emulation of storing snapshot locally
-    {
-      let mut current_snapshot = self.current_snapshot.lock().unwrap();
-      *current_snapshot = Some(stored);
-    }
+    let mut file = self.snapshot_store.persist(file, &meta.snapshot_id)?;
+    file
+      .rewind()
+      .map_err(|e| StorageError::read_snapshot(None, &e))?;
+
+    self.set_current_snapshot(StoredSnapshot {
+      meta: meta.clone(),
+      path: file.path().to_path_buf(),
+    })?;
 
     Ok(Snapshot {
       meta,
-      snapshot: data,
+      snapshot: file,
     })
   }
 }
@@ -133,25 +305,36 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
 
       sm.last_applied = Some(log_id.into());
 
-      let value = if let Some(req) = entry.app_data {
-        sm.data.insert(req.key, req.value.clone());
-        Some(req.value)
+      let response = if let Some(cmd) = entry.app_data {
+        let mut events = Vec::new();
+        let response = command::apply_command(
+          &mut sm,
+          log_id.index(),
+          *log_id.committed_leader_id(),
+          cmd,
+          &mut events,
+        );
+        self.watch_hub.publish(log_id.index(), events);
+        Response {
+          store_revision: log_id.index(),
+          ..response
+        }
       } else if let Some(mem) = entry.membership {
         sm.last_membership_log_id = Some(log_id.into());
         sm.last_membership = Some(mem);
-        None
+        Response::default()
       } else {
-        None
+        Response::default()
       };
 
-      res.push(Response { value });
+      res.push(response);
     }
     Ok(res)
   }
 
   #[tracing::instrument(level = "trace", skip(self))]
   async fn begin_receiving_snapshot(&mut self) -> Result<SnapshotData, StorageError> {
-    Ok(Default::default())
+    self.begin_receiving()
   }
 
   #[tracing::instrument(level = "trace", skip(self, snapshot))]
@@ -162,31 +345,41 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
   ) -> Result<(), StorageError> {
     tracing::info!("install snapshot");
 
-    let new_snapshot = StoredSnapshot {
-      meta: meta.clone(),
-      data: snapshot,
-    };
+    let mut snapshot = snapshot;
+    let mut d: pb::StateMachineData =
+      snapshot.decode().map_err(|e| StorageError::read_snapshot(None, &e))?;
 
-    // Update the state machine.
-    {
-      let d: pb::StateMachineData = prost::Message::decode(new_snapshot.data.as_ref())
-        .map_err(|e| StorageError::read_snapshot(None, &e))?;
+    // The leader may still be running a version that writes an older format.
+    format::migrate(&mut d).map_err(|e| StorageError::read_snapshot(None, &e))?;
 
+    // Persist the snapshot before exposing its data.
+    let path = self
+      .snapshot_store
+      .persist(snapshot, &meta.snapshot_id)?
+      .path()
+      .to_path_buf();
+
+    // Update the state machine, and let watchers know about the keys the snapshot changed.
+    {
       let mut state_machine = self.state_machine.lock().unwrap();
+      let revision = d.last_applied.map(|x| x.index).unwrap_or_default();
+      let events = watch::diff(&state_machine, &d, revision);
       *state_machine = d;
+      self.watch_hub.publish(revision, events);
     }
 
     // Update current snapshot.
-    let mut current_snapshot = self.current_snapshot.lock().unwrap();
-    *current_snapshot = Some(new_snapshot);
-    Ok(())
+    self.set_current_snapshot(StoredSnapshot {
+      meta: meta.clone(),
+      path,
+    })
   }
 
   #[tracing::instrument(level = "trace", skip(self))]
   async fn get_current_snapshot(&mut self) -> Result<Option<Snapshot>, StorageError> {
     match &*self.current_snapshot.lock().unwrap() {
       Some(snapshot) => {
-        let data = snapshot.data.clone();
+        let data = self.snapshot_store.open_snapshot(&snapshot.path)?;
         Ok(Some(Snapshot {
           meta: snapshot.meta.clone(),
           snapshot: data,
@@ -200,3 +393,226 @@ impl RaftStateMachine<TypeConfig> for Arc<StateMachineStore> {
     self.clone()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use super::*;
+
+  fn entry(index: u64, key: &str, value: &str) -> Entry {
+    command_entry(
+      index,
+      pb::SetRequest {
+        key: key.to_string(),
+        value: value.into(),
+        lease: 0,
+        namespace: String::new(),
+        content_type: String::new(),
+      },
+    )
+  }
+
+  fn value(sm: &StateMachineStore, key: &str) -> Option<Vec<u8>> {
+    sm.get("", key).value
+  }
+
+  fn command_entry(index: u64, command: impl Into<pb::Command>) -> Entry {
+    pb::Entry {
+      term: 1,
+      index,
+      legacy_app_data: None,
+      app_data: Some(command.into()),
+      membership: None,
+    }
+  }
+
+  fn snapshot_files(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+      .unwrap()
+      .map(|x| x.unwrap().path())
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn test_restart_restores_last_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let snapshot_id = {
+      let mut sm = Arc::new(StateMachineStore::open(dir.path()).unwrap());
+      sm.apply(vec![entry(1, "foo", "bar"), entry(2, "baz", "qux")])
+        .await
+        .unwrap();
+      let snapshot = sm.get_snapshot_builder().await.build_snapshot().await.unwrap();
+
+      // Applied after the snapshot; must be replayed from the log after a restart.
+      sm.apply(vec![entry(3, "foo", "zoo")]).await.unwrap();
+      snapshot.meta.snapshot_id
+    };
+
+    let mut sm = Arc::new(StateMachineStore::open(dir.path()).unwrap());
+    let (last_applied, _) = sm.applied_state().await.unwrap();
+    assert_eq!(last_applied, Some(LogId::new(1, 2)));
+    assert_eq!(value(&sm, "foo"), Some(b"bar".to_vec()));
+
+    let current = sm.get_current_snapshot().await.unwrap().unwrap();
+    assert_eq!(current.meta.snapshot_id, snapshot_id);
+    assert_eq!(current.meta.last_log_id, Some(LogId::new(1, 2)));
+
+    // The snapshot counter carries on from the snapshot on disk.
+    let snapshot = sm.get_snapshot_builder().await.build_snapshot().await.unwrap();
+    assert_eq!(snapshot.meta.snapshot_id, "1-2-2");
+    assert_ne!(snapshot.meta.snapshot_id, snapshot_id);
+  }
+
+  #[tokio::test]
+  async fn test_apply_delete() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sm = Arc::new(StateMachineStore::open(dir.path()).unwrap());
+
+    let delete = |index, key: &str| {
+      command_entry(
+        index,
+        pb::DeleteRequest {
+          key: key.to_string(),
+          namespace: String::new(),
+        },
+      )
+    };
+
+    let res = sm
+      .apply(vec![
+        entry(1, "foo", "bar"),
+        delete(2, "foo"),
+        delete(3, "foo"),
+      ])
+      .await
+      .unwrap();
+
+    assert_eq!(res[1].value, Some(b"bar".to_vec()));
+    assert_eq!(res[2].value, None);
+    assert!(sm.state_machine.lock().unwrap().namespaces.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_install_legacy_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sm = Arc::new(StateMachineStore::open(dir.path()).unwrap());
+
+    // A snapshot written before per-key metadata existed.
+    let mut legacy = pb::StateMachineData {
+      last_applied: Some(LogId::new(1, 2).into()),
+      ..Default::default()
+    };
+    legacy.legacy_data.insert("foo".to_string(), "bar".to_string());
+    legacy.legacy_revisions.insert("foo".to_string(), 2);
+    let meta = snapshot_meta(&legacy, "1-2-1".to_string());
+
+    let mut file = sm.begin_receiving_snapshot().await.unwrap();
+    file.write_all(&prost::Message::encode_to_vec(&legacy)).unwrap();
+    sm.install_snapshot(&meta, file).await.unwrap();
+
+    let res = sm.get("", "foo");
+    assert_eq!(res.value, Some(b"bar".to_vec()));
+    assert_eq!(res.revision, Some(2));
+    assert_eq!(res.store_revision, 2);
+
+    // New snapshots are written in the current format.
+    sm.apply(vec![entry(3, "foo", "baz")]).await.unwrap();
+    sm.get_snapshot_builder().await.build_snapshot().await.unwrap();
+    let loaded = sm.snapshot_store.load_latest().unwrap().unwrap();
+    assert_eq!(loaded.state_machine.format_version, format::CURRENT_FORMAT_VERSION);
+    let foo = namespace::get(&loaded.state_machine, namespace::DEFAULT, "foo");
+    assert_eq!(foo.map(|x| x.version), Some(2));
+  }
+
+  #[tokio::test]
+  async fn test_watch_across_snapshot_install() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sm = Arc::new(StateMachineStore::open(dir.path()).unwrap());
+    sm.apply(vec![entry(1, "foo", "a"), entry(2, "bar", "b")])
+      .await
+      .unwrap();
+
+    let mut watcher = sm
+      .watch(KeyFilter::new("", String::new(), true), Some(2))
+      .unwrap();
+
+    // The snapshot of a leader that deleted "bar" and wrote "baz" in the meantime.
+    let mut leader = pb::StateMachineData {
+      last_applied: Some(LogId::new(1, 5).into()),
+      format_version: format::CURRENT_FORMAT_VERSION,
+      ..Default::default()
+    };
+    leader.namespaces = sm.state_machine.lock().unwrap().namespaces.clone();
+    command::apply_command(
+      &mut leader,
+      3,
+      1,
+      pb::DeleteRequest {
+        key: "bar".to_string(),
+        namespace: String::new(),
+      }
+      .into(),
+      &mut Vec::new(),
+    );
+    command::apply_command(
+      &mut leader,
+      4,
+      1,
+      pb::SetRequest {
+        key: "baz".to_string(),
+        value: "c".into(),
+        lease: 0,
+        namespace: String::new(),
+        content_type: String::new(),
+      }
+      .into(),
+      &mut Vec::new(),
+    );
+    let meta = snapshot_meta(&leader, "1-5-1".to_string());
+    let mut file = sm.begin_receiving_snapshot().await.unwrap();
+    file
+      .write_all(&prost::Message::encode_to_vec(&leader))
+      .unwrap();
+    sm.install_snapshot(&meta, file).await.unwrap();
+
+    let changes = watcher.next().await.unwrap();
+    assert_eq!(changes.revision, 2);
+    assert_eq!(changes.events[0].key, "bar");
+
+    let changes = watcher.next().await.unwrap();
+    assert_eq!(changes.revision, 5);
+    let events = changes
+      .events
+      .iter()
+      .map(|x| (x.key.as_str(), x.r#type))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      events,
+      vec![
+        ("baz", pb::event::EventType::Put as i32),
+        ("bar", pb::event::EventType::Delete as i32),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_old_snapshots_are_removed() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sm = Arc::new(StateMachineStore::open(dir.path()).unwrap());
+
+    for index in 1..=3 {
+      sm.apply(vec![entry(index, "foo", "bar")]).await.unwrap();
+      sm.get_snapshot_builder().await.build_snapshot().await.unwrap();
+    }
+
+    let files = snapshot_files(dir.path());
+    assert_eq!(files.len(), 1);
+    assert!(files[0]
+      .file_name()
+      .unwrap()
+      .to_string_lossy()
+      .starts_with("1-3-"));
+  }
+}
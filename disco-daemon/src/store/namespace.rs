@@ -0,0 +1,200 @@
+//! Namespaces partition the keys of the state machine.
+//!
+//! Each namespace has its own keys, and a quota limiting how many keys and bytes it may hold. The
+//! namespaces are:
+//! - `system`: data of the cluster itself, such as locks and elections. Clients can read it, but
+//!   only the nodes write to it.
+//! - `controller`: state kept by the controller scripts.
+//! - `user/<name>`: data of applications. Requests that do not name a namespace use
+//!   `user/default`.
+//!
+//! Quotas are replicated through the Raft log like any other change, and checked when a write is
+//! applied, so every node refuses the same writes.
+
+use std::collections::BTreeMap;
+
+use crate::protobuf as pb;
+
+/// The namespace reserved for the data of the cluster itself.
+pub const SYSTEM: &str = "system";
+
+/// The namespace of the controller scripts.
+pub const CONTROLLER: &str = "controller";
+
+/// The prefix of the namespaces of applications.
+pub const USER_PREFIX: &str = "user/";
+
+/// The namespace of requests that do not name one.
+pub const DEFAULT: &str = "user/default";
+
+/// Returns the namespace a request refers to, with the default one standing in for an empty
+/// name.
+pub fn resolve(namespace: &str) -> &str {
+  if namespace.is_empty() {
+    DEFAULT
+  } else {
+    namespace
+  }
+}
+
+/// Checks that `namespace` names one of the known namespaces.
+pub fn validate(namespace: &str) -> Result<(), String> {
+  let namespace = resolve(namespace);
+  if namespace == SYSTEM || namespace == CONTROLLER {
+    return Ok(());
+  }
+
+  match namespace.strip_prefix(USER_PREFIX) {
+    Some(name)
+      if !name.is_empty()
+        && name
+          .chars()
+          .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') =>
+    {
+      Ok(())
+    }
+    _ => Err(format!(
+      "invalid namespace {:?}: expected {:?}, {:?} or {:?} followed by letters, digits, '-', '_' \
+       or '.'",
+      namespace, SYSTEM, CONTROLLER, USER_PREFIX
+    )),
+  }
+}
+
+/// Returns whether clients are refused writes to `namespace`.
+pub fn is_reserved(namespace: &str) -> bool {
+  resolve(namespace) == SYSTEM
+}
+
+/// Returns `key` of `namespace`, if it exists.
+pub(crate) fn get<'a>(
+  sm: &'a pb::StateMachineData,
+  namespace: &str,
+  key: &str,
+) -> Option<&'a pb::KeyValue> {
+  sm.namespaces.get(namespace)?.data.get(key)
+}
+
+/// The number of bytes a key and its value count for in the quota of their namespace.
+pub(crate) fn size(key: &str, value: &[u8]) -> u64 {
+  (key.len() + value.len()) as u64
+}
+
+/// Collects the writes of a command to check them against the quotas before any of them is
+/// applied.
+pub(crate) struct QuotaCheck<'a> {
+  sm: &'a pb::StateMachineData,
+
+  /// The size of each written key after the writes, or `None` if it is deleted.
+  sizes: BTreeMap<(String, String), Option<u64>>,
+}
+
+impl<'a> QuotaCheck<'a> {
+  pub(crate) fn new(sm: &'a pb::StateMachineData) -> Self {
+    Self {
+      sm,
+      sizes: BTreeMap::new(),
+    }
+  }
+
+  pub(crate) fn set(&mut self, namespace: &str, key: &str, value: &[u8]) {
+    self.sizes.insert(
+      (namespace.to_string(), key.to_string()),
+      Some(size(key, value)),
+    );
+  }
+
+  pub(crate) fn delete(&mut self, namespace: &str, key: &str) {
+    self
+      .sizes
+      .insert((namespace.to_string(), key.to_string()), None);
+  }
+
+  /// Returns whether the writes make a namespace exceed its quota.
+  ///
+  /// Only growing past a limit counts: a namespace already over a quota that was lowered can
+  /// still shrink, or be rewritten without growing.
+  pub(crate) fn exceeds_quota(&self) -> bool {
+    // The change in keys and bytes of each namespace.
+    let mut deltas: BTreeMap<&str, (i64, i64)> = BTreeMap::new();
+    for ((namespace, key), after) in &self.sizes {
+      let before = get(self.sm, namespace, key).map(|x| size(key, &x.value));
+      let delta = deltas.entry(namespace.as_str()).or_default();
+      delta.0 += after.is_some() as i64 - before.is_some() as i64;
+      delta.1 += after.unwrap_or_default() as i64 - before.unwrap_or_default() as i64;
+    }
+
+    deltas.into_iter().any(|(namespace, (keys, bytes))| {
+      let Some(ns) = self.sm.namespaces.get(namespace) else {
+        return false;
+      };
+      let quota = ns.quota.unwrap_or_default();
+      let exceeds = |limit: u64, current: u64, delta: i64| {
+        limit != 0 && delta > 0 && current as i64 + delta > limit as i64
+      };
+      exceeds(quota.max_keys, ns.data.len() as u64, keys)
+        || exceeds(quota.max_bytes, ns.bytes, bytes)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_validate() {
+    assert!(validate("").is_ok());
+    assert!(validate(SYSTEM).is_ok());
+    assert!(validate(CONTROLLER).is_ok());
+    assert!(validate("user/billing-v2").is_ok());
+
+    assert!(validate("user/").is_err());
+    assert!(validate("user/a/b").is_err());
+    assert!(validate("billing").is_err());
+
+    assert!(is_reserved(SYSTEM));
+    assert!(!is_reserved(""));
+  }
+
+  #[test]
+  fn test_quota_check() {
+    let mut sm = pb::StateMachineData::default();
+    let ns = sm.namespaces.entry(DEFAULT.to_string()).or_default();
+    ns.quota = Some(pb::Quota {
+      max_keys: 2,
+      max_bytes: 10,
+    });
+    ns.data.insert(
+      "a".to_string(),
+      pb::KeyValue {
+        value: b"1234".to_vec(),
+        ..Default::default()
+      },
+    );
+    ns.bytes = 5;
+
+    let check = |writes: &[(&str, Option<&str>)]| {
+      let mut check = QuotaCheck::new(&sm);
+      for (key, value) in writes {
+        match value {
+          Some(value) => check.set(DEFAULT, key, value.as_bytes()),
+          None => check.delete(DEFAULT, key),
+        }
+      }
+      check.exceeds_quota()
+    };
+
+    assert!(!check(&[("b", Some("123"))]));
+    assert!(check(&[("b", Some("12345"))]));
+    assert!(check(&[("b", Some("1")), ("c", Some("1"))]));
+
+    // Room made by a delete can be used by the same command.
+    assert!(!check(&[("a", None), ("b", Some("1")), ("c", Some("1"))]));
+
+    // Namespaces without a quota are not limited.
+    let mut check = QuotaCheck::new(&sm);
+    check.set(CONTROLLER, "big", &[0; 100]);
+    assert!(!check.exceeds_quota());
+  }
+}
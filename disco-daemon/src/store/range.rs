@@ -0,0 +1,171 @@
+//! Lists ranges of keys of a namespace from the state machine data.
+//!
+//! Keys are kept in a `BTreeMap`, so a range is a contiguous, ordered slice of the map. A page
+//! token is the last key of the previous page: the next page starts right after it.
+
+use std::ops::Bound;
+
+use crate::protobuf as pb;
+use crate::store::namespace;
+
+/// Lists the keys selected by `req`.
+pub(crate) fn range(sm: &pb::StateMachineData, req: &pb::RangeRequest) -> pb::RangeResponse {
+  let mut res = pb::RangeResponse {
+    count: keys(sm, req, None).count() as u64,
+    ..Default::default()
+  };
+  if req.count_only {
+    return res;
+  }
+
+  let page_token = Some(req.page_token.as_str()).filter(|x| !x.is_empty());
+  let limit = match req.limit {
+    0 => usize::MAX,
+    limit => limit as usize,
+  };
+
+  let mut keys = keys(sm, req, page_token).peekable();
+  while let Some((key, kv)) = keys.next() {
+    res.kvs.push(pb::KeyValuePair {
+      key: key.clone(),
+      kv: if req.keys_only {
+        None
+      } else {
+        Some(kv.clone())
+      },
+    });
+
+    if res.kvs.len() == limit {
+      if keys.peek().is_some() {
+        res.next_page_token = key.clone();
+      }
+      break;
+    }
+  }
+
+  res
+}
+
+/// Iterates over the keys selected by `req`, starting after `after` if it is set.
+fn keys<'a>(
+  sm: &'a pb::StateMachineData,
+  req: &'a pb::RangeRequest,
+  after: Option<&'a str>,
+) -> impl Iterator<Item = (&'a String, &'a pb::KeyValue)> {
+  // Every key with the prefix sorts after the prefix itself.
+  let start = req.start.as_str().max(req.prefix.as_str());
+  let (first, lower) = match after {
+    Some(after) if after >= start => (after, Bound::Excluded(after)),
+    _ => (start, Bound::Included(start)),
+  };
+
+  // `BTreeMap::range` panics on inverted bounds.
+  let (lower, upper) = if req.end.is_empty() {
+    (lower, Bound::Unbounded)
+  } else if first >= req.end.as_str() {
+    (Bound::Included(""), Bound::Excluded(""))
+  } else {
+    (lower, Bound::Excluded(req.end.as_str()))
+  };
+
+  let data = sm
+    .namespaces
+    .get(namespace::resolve(&req.namespace))
+    .map(|x| &x.data);
+  data.into_iter().flat_map(move |data| {
+    data
+      .range::<str, _>((lower, upper))
+      .take_while(|(key, _)| key.starts_with(&req.prefix))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn state_machine(keys: &[&str]) -> pb::StateMachineData {
+    let mut sm = pb::StateMachineData::default();
+    let ns = sm
+      .namespaces
+      .entry(namespace::DEFAULT.to_string())
+      .or_default();
+    for (i, key) in keys.iter().enumerate() {
+      ns.data.insert(
+        key.to_string(),
+        pb::KeyValue {
+          value: format!("v{}", i).into_bytes(),
+          ..Default::default()
+        },
+      );
+    }
+    sm
+  }
+
+  fn listed(res: &pb::RangeResponse) -> Vec<&str> {
+    res.kvs.iter().map(|x| x.key.as_str()).collect()
+  }
+
+  #[test]
+  fn test_range_with_prefix_and_bounds() {
+    let sm = state_machine(&["a", "app/1", "app/2", "app/3", "b"]);
+
+    let req = pb::RangeRequest {
+      prefix: "app/".to_string(),
+      ..Default::default()
+    };
+    let res = range(&sm, &req);
+    assert_eq!(listed(&res), vec!["app/1", "app/2", "app/3"]);
+    assert_eq!(res.count, 3);
+
+    let req = pb::RangeRequest {
+      start: "app/2".to_string(),
+      end: "b".to_string(),
+      keys_only: true,
+      ..Default::default()
+    };
+    let res = range(&sm, &req);
+    assert_eq!(listed(&res), vec!["app/2", "app/3"]);
+    assert!(res.kvs.iter().all(|x| x.kv.is_none()));
+
+    let req = pb::RangeRequest {
+      start: "b".to_string(),
+      end: "a".to_string(),
+      ..Default::default()
+    };
+    assert_eq!(range(&sm, &req).count, 0);
+
+    // Only the keys of the requested namespace are listed.
+    let req = pb::RangeRequest {
+      namespace: namespace::CONTROLLER.to_string(),
+      ..Default::default()
+    };
+    assert_eq!(range(&sm, &req).count, 0);
+  }
+
+  #[test]
+  fn test_range_pages() {
+    let sm = state_machine(&["k1", "k2", "k3", "k4", "k5"]);
+    let mut req = pb::RangeRequest {
+      prefix: "k".to_string(),
+      limit: 2,
+      ..Default::default()
+    };
+
+    let mut pages = Vec::new();
+    loop {
+      let res = range(&sm, &req);
+      assert_eq!(res.count, 5);
+      pages.push(listed(&res).join(","));
+      if res.next_page_token.is_empty() {
+        break;
+      }
+      req.page_token = res.next_page_token;
+    }
+    assert_eq!(pages, vec!["k1,k2", "k3,k4", "k5"]);
+
+    req.count_only = true;
+    let res = range(&sm, &req);
+    assert!(res.kvs.is_empty());
+    assert_eq!(res.count, 5);
+  }
+}
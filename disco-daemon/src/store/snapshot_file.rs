@@ -0,0 +1,370 @@
+//! Provide `SnapshotFile`, the file-backed snapshot data exchanged with openraft.
+//!
+//! Snapshots are never held in memory as a whole while they are transferred: the sender reads
+//! the file chunk by chunk and the receiver writes every chunk straight to disk.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use prost::bytes::buf::UninitSlice;
+use prost::bytes::Buf;
+use prost::bytes::BufMut;
+
+/// The size of the reads made while decoding a snapshot file.
+const DECODE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The size of the writes made while encoding a snapshot file.
+const ENCODE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Snapshot data stored in a file on disk.
+///
+/// A file created with [`SnapshotFile::create`] is temporary: it is removed when dropped, unless
+/// it has been persisted as a snapshot first.
+#[derive(Debug)]
+pub struct SnapshotFile {
+  path: PathBuf,
+  file: File,
+  temporary: bool,
+}
+
+impl SnapshotFile {
+  /// Creates an empty snapshot file at `path`, replacing any existing file.
+  pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+    let path = path.into();
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&path)?;
+    Ok(Self {
+      path,
+      file,
+      temporary: true,
+    })
+  }
+
+  /// Opens an existing snapshot file for reading.
+  pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+    let path = path.into();
+    let file = File::open(&path)?;
+    Ok(Self {
+      path,
+      file,
+      temporary: false,
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Reads up to `max_len` bytes from the current position. Returns an empty chunk at the end.
+  pub fn read_chunk(&mut self, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(max_len);
+    Read::by_ref(&mut self.file)
+      .take(max_len as u64)
+      .read_to_end(&mut chunk)?;
+    Ok(chunk)
+  }
+
+  /// Decodes the message stored in the file, starting from the beginning.
+  ///
+  /// The file is read through a small buffer while it is decoded, so the encoded message is
+  /// never held in memory as a whole next to the decoded one.
+  pub fn decode<M: prost::Message + Default>(&mut self) -> io::Result<M> {
+    self.rewind()?;
+    let len = self.file.metadata()?.len() as usize;
+    let mut buf = FileBuf::new(&mut self.file, len);
+    let res = M::decode(&mut buf);
+    if let Some(e) = buf.error {
+      return Err(e);
+    }
+    res.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+
+  /// Encodes `msg` into the file, from the current position.
+  ///
+  /// The encoded message is written out through a small buffer as it is encoded, so it is never
+  /// held in memory as a whole next to the message.
+  pub fn encode<M: prost::Message>(&mut self, msg: &M) -> io::Result<()> {
+    let mut buf = FileBufMut::new(&mut self.file);
+    msg
+      .encode(&mut buf)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    buf.finish()
+  }
+
+  /// Computes the CRC32 checksum of the file content and rewinds to the beginning.
+  pub fn checksum(&mut self) -> io::Result<u32> {
+    self.rewind()?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+      let n = self.file.read(&mut buf)?;
+      if n == 0 {
+        break;
+      }
+      hasher.update(&buf[..n]);
+    }
+    self.rewind()?;
+    Ok(hasher.finalize())
+  }
+
+  pub fn rewind(&mut self) -> io::Result<()> {
+    self.file.seek(SeekFrom::Start(0)).map(|_| ())
+  }
+
+  /// Flushes the content to disk and moves the file to `path`.
+  pub(crate) fn persist(mut self, path: &Path) -> io::Result<Self> {
+    self.file.sync_all()?;
+    fs::rename(&self.path, path)?;
+    self.path = path.to_path_buf();
+    self.temporary = false;
+    Ok(self)
+  }
+}
+
+impl Drop for SnapshotFile {
+  fn drop(&mut self) {
+    if self.temporary {
+      if let Err(e) = fs::remove_file(&self.path) {
+        tracing::warn!("failed to remove snapshot file {}: {}", self.path.display(), e);
+      }
+    }
+  }
+}
+
+impl Write for SnapshotFile {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.file.write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
+
+/// A [`Buf`] reading `remaining` bytes from a file as they are consumed.
+///
+/// [`Buf`] cannot fail, so after a read error the buffer yields zeros until its end and the
+/// error is kept in `error`, to be returned in place of the decoded message.
+struct FileBuf<'a> {
+  reader: BufReader<&'a mut File>,
+  remaining: usize,
+  error: Option<io::Error>,
+}
+
+static ZEROS: [u8; 64] = [0; 64];
+
+impl<'a> FileBuf<'a> {
+  fn new(file: &'a mut File, len: usize) -> Self {
+    let mut buf = Self {
+      reader: BufReader::with_capacity(DECODE_BUFFER_SIZE, file),
+      remaining: len,
+      error: None,
+    };
+    if len > 0 {
+      buf.fill();
+    }
+    buf
+  }
+
+  fn fill(&mut self) {
+    match self.reader.fill_buf() {
+      Ok([]) => self.error = Some(io::ErrorKind::UnexpectedEof.into()),
+      Ok(_) => {}
+      Err(e) => self.error = Some(e),
+    }
+  }
+}
+
+impl Buf for FileBuf<'_> {
+  fn remaining(&self) -> usize {
+    self.remaining
+  }
+
+  fn chunk(&self) -> &[u8] {
+    if self.error.is_some() {
+      return &ZEROS[..self.remaining.min(ZEROS.len())];
+    }
+    let buffered = self.reader.buffer();
+    &buffered[..buffered.len().min(self.remaining)]
+  }
+
+  fn advance(&mut self, mut cnt: usize) {
+    assert!(cnt <= self.remaining, "advance past the end of the snapshot file");
+    self.remaining -= cnt;
+
+    while self.error.is_none() {
+      let n = cnt.min(self.reader.buffer().len());
+      self.reader.consume(n);
+      cnt -= n;
+      if !self.reader.buffer().is_empty() || (cnt == 0 && self.remaining == 0) {
+        break;
+      }
+      self.fill();
+    }
+  }
+}
+
+/// A [`BufMut`] writing to a file each time it has buffered [`ENCODE_BUFFER_SIZE`] bytes.
+///
+/// [`BufMut`] cannot fail, so after a write error the bytes are dropped and the error is kept in
+/// `error`, to be returned by [`FileBufMut::finish`].
+struct FileBufMut<'a> {
+  file: &'a mut File,
+  buf: Vec<u8>,
+  error: Option<io::Error>,
+}
+
+impl<'a> FileBufMut<'a> {
+  fn new(file: &'a mut File) -> Self {
+    Self {
+      file,
+      buf: Vec::with_capacity(ENCODE_BUFFER_SIZE),
+      error: None,
+    }
+  }
+
+  fn write_buffered(&mut self) {
+    if self.error.is_none() {
+      if let Err(e) = self.file.write_all(&self.buf) {
+        self.error = Some(e);
+      }
+    }
+    self.buf.clear();
+  }
+
+  /// Writes out what is left in the buffer, or returns the first write error.
+  fn finish(mut self) -> io::Result<()> {
+    self.write_buffered();
+    match self.error {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+}
+
+// SAFETY: the spare capacity is handed out and advanced over by the implementation of `Vec`, and
+// the buffer is only written out and cleared in between.
+unsafe impl BufMut for FileBufMut<'_> {
+  fn remaining_mut(&self) -> usize {
+    self.buf.remaining_mut()
+  }
+
+  unsafe fn advance_mut(&mut self, cnt: usize) {
+    unsafe { self.buf.advance_mut(cnt) };
+    if self.buf.len() >= ENCODE_BUFFER_SIZE {
+      self.write_buffered();
+    }
+  }
+
+  fn chunk_mut(&mut self) -> &mut UninitSlice {
+    self.buf.chunk_mut()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_temporary_file_is_removed_unless_persisted() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let dropped = dir.path().join("dropped.tmp");
+    let mut file = SnapshotFile::create(&dropped).unwrap();
+    file.write_all(b"partial").unwrap();
+    drop(file);
+    assert!(!dropped.exists());
+
+    let persisted = dir.path().join("1-1-1.snap");
+    let mut file = SnapshotFile::create(dir.path().join("incoming.tmp")).unwrap();
+    file.write_all(b"complete").unwrap();
+    drop(file.persist(&persisted).unwrap());
+    assert_eq!(fs::read(&persisted).unwrap(), b"complete");
+  }
+
+  #[test]
+  fn test_read_in_chunks() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = (0..=255u8).cycle().take(10_000).collect::<Vec<_>>();
+
+    let mut file = SnapshotFile::create(dir.path().join("incoming.tmp")).unwrap();
+    file.write_all(&data).unwrap();
+    assert_eq!(file.checksum().unwrap(), crc32fast::hash(&data));
+
+    let mut read = Vec::new();
+    loop {
+      let chunk = file.read_chunk(4096).unwrap();
+      if chunk.is_empty() {
+        break;
+      }
+      assert!(chunk.len() <= 4096);
+      read.extend_from_slice(&chunk);
+    }
+    assert_eq!(read, data);
+  }
+
+  fn namespace() -> crate::protobuf::Namespace {
+    crate::protobuf::Namespace {
+      data: (0..10_000)
+        .map(|i| {
+          let kv = crate::protobuf::KeyValue {
+            value: vec![i as u8; 32],
+            mod_revision: i,
+            ..Default::default()
+          };
+          (format!("key-{}", i), kv)
+        })
+        .collect(),
+      bytes: 42,
+      quota: None,
+    }
+  }
+
+  #[test]
+  fn test_decode_larger_than_buffer() {
+    let dir = tempfile::tempdir().unwrap();
+    let message = namespace();
+    let data = prost::Message::encode_to_vec(&message);
+    assert!(data.len() > 4 * DECODE_BUFFER_SIZE);
+
+    let mut file = SnapshotFile::create(dir.path().join("incoming.tmp")).unwrap();
+    file.write_all(&data).unwrap();
+    let decoded: crate::protobuf::Namespace = file.decode().unwrap();
+    assert_eq!(decoded, message);
+
+    // A truncated file is an error, not a partial message.
+    let mut file = SnapshotFile::create(dir.path().join("truncated.tmp")).unwrap();
+    file.write_all(&data[..data.len() - 10]).unwrap();
+    let err = file.decode::<crate::protobuf::Namespace>().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[test]
+  fn test_encode_larger_than_buffer() {
+    let dir = tempfile::tempdir().unwrap();
+    let message = namespace();
+    let data = prost::Message::encode_to_vec(&message);
+    assert!(data.len() > 4 * ENCODE_BUFFER_SIZE);
+
+    let path = dir.path().join("incoming.tmp");
+    let mut file = SnapshotFile::create(&path).unwrap();
+    file.encode(&message).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), data);
+    let decoded: crate::protobuf::Namespace = file.decode().unwrap();
+    assert_eq!(decoded, message);
+  }
+}
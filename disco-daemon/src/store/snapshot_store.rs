@@ -0,0 +1,183 @@
+//! Provide `SnapshotStore`, which keeps state machine snapshots as files in a directory.
+//!
+//! Each snapshot is the protobuf encoded `StateMachineData`, stored in a file named after the
+//! snapshot id. Snapshots, whether built locally or received from the leader, are written to a
+//! temporary file first and renamed into place, so a crash never leaves a partially written
+//! snapshot behind. Only the latest snapshot is kept.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use super::format;
+use super::fs::sync_dir;
+use super::SnapshotFile;
+use crate::protobuf as pb;
+use crate::raft_types::*;
+
+const SNAPSHOT_EXTENSION: &str = "snap";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// A snapshot loaded from disk.
+#[derive(Debug)]
+pub struct LoadedSnapshot {
+  pub snapshot_id: String,
+  pub path: PathBuf,
+  pub state_machine: pb::StateMachineData,
+}
+
+#[derive(Debug)]
+pub struct SnapshotStore {
+  dir: PathBuf,
+
+  /// Used to name temporary files.
+  temp_idx: AtomicU64,
+}
+
+impl SnapshotStore {
+  /// Opens the snapshot directory, creating it if it does not exist yet.
+  ///
+  /// Temporary files left behind by a crash in the middle of writing a snapshot are removed.
+  pub fn open(dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+    let dir = dir.as_ref().to_path_buf();
+    let store = Self {
+      dir,
+      temp_idx: AtomicU64::new(0),
+    };
+    store
+      .remove_temp_files()
+      .map_err(|e| StorageError::read_snapshot(None, &e))?;
+    Ok(store)
+  }
+
+  /// Returns the path of the file holding the snapshot with the given id.
+  pub fn path(&self, snapshot_id: &str) -> PathBuf {
+    self
+      .dir
+      .join(format!("{}.{}", snapshot_id, SNAPSHOT_EXTENSION))
+  }
+
+  /// Creates a temporary file to write a new snapshot into.
+  ///
+  /// The file becomes a snapshot once it is passed to [`SnapshotStore::persist`].
+  pub fn create_temp(&self) -> Result<SnapshotFile, StorageError> {
+    let idx = self.temp_idx.fetch_add(1, Ordering::Relaxed);
+    let path = self.dir.join(format!("incoming-{}.{}", idx, TEMP_EXTENSION));
+    SnapshotFile::create(path).map_err(|e| StorageError::write_snapshot(None, &e))
+  }
+
+  /// Durably moves a fully written temporary file into place as the snapshot `snapshot_id`.
+  pub fn persist(
+    &self,
+    file: SnapshotFile,
+    snapshot_id: &str,
+  ) -> Result<SnapshotFile, StorageError> {
+    if snapshot_id.is_empty() || snapshot_id.contains(['/', '\\']) {
+      let e = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid snapshot id: {:?}", snapshot_id),
+      );
+      return Err(StorageError::write_snapshot(None, &e));
+    }
+
+    let persist = || -> io::Result<SnapshotFile> {
+      let file = file.persist(&self.path(snapshot_id))?;
+      sync_dir(&self.dir)?;
+      Ok(file)
+    };
+    persist().map_err(|e| StorageError::write_snapshot(None, &e))
+  }
+
+  /// Opens a snapshot file for reading.
+  pub fn open_snapshot(&self, path: &Path) -> Result<SnapshotFile, StorageError> {
+    SnapshotFile::open(path).map_err(|e| StorageError::read_snapshot(None, &e))
+  }
+
+  /// Loads the snapshot with the greatest last applied log id, if there is any.
+  ///
+  /// The returned state machine is migrated to the current format.
+  pub fn load_latest(&self) -> Result<Option<LoadedSnapshot>, StorageError> {
+    let mut latest: Option<LoadedSnapshot> = None;
+
+    for path in self.snapshot_files().map_err(|e| StorageError::read_snapshot(None, &e))? {
+      let Some(snapshot_id) = path.file_stem().and_then(|x| x.to_str()) else {
+        continue;
+      };
+
+      let mut file = self.open_snapshot(&path)?;
+      let sm: pb::StateMachineData = match file.decode() {
+        Ok(sm) => sm,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+          tracing::warn!("ignoring undecodable snapshot {}: {}", path.display(), e);
+          continue;
+        }
+        Err(e) => return Err(StorageError::read_snapshot(None, &e)),
+      };
+
+      let last_applied = |sm: &pb::StateMachineData| sm.last_applied.map(|x| (x.index, x.term));
+      let is_newer = match &latest {
+        Some(cur) => last_applied(&sm) > last_applied(&cur.state_machine),
+        None => true,
+      };
+      if is_newer {
+        latest = Some(LoadedSnapshot {
+          snapshot_id: snapshot_id.to_string(),
+          path: path.clone(),
+          state_machine: sm,
+        });
+      }
+    }
+
+    if let Some(latest) = &mut latest {
+      format::migrate(&mut latest.state_machine)
+        .map_err(|e| StorageError::read_snapshot(None, &e))?;
+    }
+
+    Ok(latest)
+  }
+
+  /// Removes every snapshot file except `keep`.
+  pub fn purge_except(&self, keep: &Path) -> Result<(), StorageError> {
+    let purge = || -> io::Result<()> {
+      let mut removed = false;
+      for path in self.snapshot_files()? {
+        if path != keep {
+          tracing::debug!("removing old snapshot file {}", path.display());
+          fs::remove_file(&path)?;
+          removed = true;
+        }
+      }
+      if removed {
+        sync_dir(&self.dir)?;
+      }
+      Ok(())
+    };
+    purge().map_err(|e| StorageError::write_snapshot(None, &e))
+  }
+
+  fn remove_temp_files(&self) -> io::Result<()> {
+    fs::create_dir_all(&self.dir)?;
+    for dir_entry in fs::read_dir(&self.dir)? {
+      let path = dir_entry?.path();
+      if path.extension().and_then(|x| x.to_str()) == Some(TEMP_EXTENSION) {
+        tracing::warn!("removing incomplete snapshot file {}", path.display());
+        fs::remove_file(&path)?;
+      }
+    }
+    Ok(())
+  }
+
+  fn snapshot_files(&self) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for dir_entry in fs::read_dir(&self.dir)? {
+      let path = dir_entry?.path();
+      if path.extension().and_then(|x| x.to_str()) == Some(SNAPSHOT_EXTENSION) {
+        files.push(path);
+      }
+    }
+    Ok(files)
+  }
+}
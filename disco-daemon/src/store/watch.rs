@@ -0,0 +1,348 @@
+//! Provide `WatchHub`, which distributes the changes applied to the state machine to watchers.
+//!
+//! Every log entry that changes keys is published as one `WatchResponse`, in log order. A bounded
+//! history of recent changes is kept so that a watcher can start from a past revision, e.g. to
+//! resume after reconnecting, as long as that revision has not been dropped from the history yet.
+//!
+//! Changes are only published once applied, and applied entries are never reverted, so a leader
+//! change does not affect watchers. When a snapshot replaces the state machine, the differences
+//! between the old and the new data are published as a single change at the revision of the
+//! snapshot, so that watchers still observe every key reaching its current state.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+use crate::protobuf as pb;
+use crate::protobuf::event::EventType;
+use crate::store::namespace;
+
+/// How many revisions with changes are kept to replay to new watchers.
+const HISTORY_SIZE: usize = 10_000;
+
+/// How many changes a watcher may fall behind before it is dropped.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Selects the keys a watcher is interested in.
+#[derive(Clone, Debug)]
+pub struct KeyFilter {
+  namespace: String,
+  key: String,
+  prefix: bool,
+}
+
+impl KeyFilter {
+  /// Matches `key` of `namespace` only, or every key of `namespace` starting with `key` if
+  /// `prefix` is set.
+  pub fn new(namespace: &str, key: String, prefix: bool) -> Self {
+    Self {
+      namespace: namespace::resolve(namespace).to_string(),
+      key,
+      prefix,
+    }
+  }
+
+  pub fn matches(&self, namespace: &str, key: &str) -> bool {
+    if namespace != self.namespace {
+      false
+    } else if self.prefix {
+      key.starts_with(&self.key)
+    } else {
+      key == self.key
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchError {
+  /// The start revision is no longer in the history; `first_revision` is the oldest one that is.
+  Compacted { first_revision: u64 },
+
+  /// The watcher did not keep up with the changes and missed some of them.
+  Lagged,
+
+  /// The state machine has been dropped.
+  Closed,
+}
+
+impl fmt::Display for WatchError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WatchError::Compacted { first_revision } => write!(
+        f,
+        "start revision has been compacted, the oldest available revision is {}",
+        first_revision
+      ),
+      WatchError::Lagged => write!(f, "watcher fell behind and missed changes"),
+      WatchError::Closed => write!(f, "state machine closed"),
+    }
+  }
+}
+
+impl std::error::Error for WatchError {}
+
+#[derive(Debug)]
+pub struct WatchHub {
+  inner: Mutex<WatchHubInner>,
+  tx: broadcast::Sender<Arc<pb::WatchResponse>>,
+}
+
+#[derive(Debug)]
+struct WatchHubInner {
+  history: VecDeque<Arc<pb::WatchResponse>>,
+
+  /// Every change at this revision or later is in `history`.
+  first_revision: u64,
+}
+
+impl WatchHub {
+  /// Creates a hub for a state machine whose last applied revision is `last_applied`.
+  ///
+  /// Changes up to and including `last_applied` can not be replayed.
+  pub fn new(last_applied: u64) -> Self {
+    let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+    Self {
+      inner: Mutex::new(WatchHubInner {
+        history: VecDeque::new(),
+        first_revision: last_applied + 1,
+      }),
+      tx,
+    }
+  }
+
+  /// Publishes the changes made at `revision`.
+  pub fn publish(&self, revision: u64, events: Vec<pb::Event>) {
+    if events.is_empty() {
+      return;
+    }
+
+    let changes = Arc::new(pb::WatchResponse { revision, events });
+
+    let mut inner = self.inner.lock().unwrap();
+    if inner.history.len() == HISTORY_SIZE {
+      if let Some(dropped) = inner.history.pop_front() {
+        inner.first_revision = dropped.revision + 1;
+      }
+    }
+    inner.history.push_back(changes.clone());
+
+    // Sent under the lock, so a new watcher can not miss it nor see it twice.
+    // An error only means that there is no watcher.
+    let _ = self.tx.send(changes);
+  }
+
+  /// Creates a watcher for the keys selected by `filter`.
+  ///
+  /// If `start_revision` is set, the changes made at that revision or later are replayed first.
+  pub fn watch(
+    &self,
+    filter: KeyFilter,
+    start_revision: Option<u64>,
+  ) -> Result<Watcher, WatchError> {
+    let inner = self.inner.lock().unwrap();
+
+    let mut pending = VecDeque::new();
+    if let Some(start) = start_revision {
+      if start < inner.first_revision {
+        return Err(WatchError::Compacted {
+          first_revision: inner.first_revision,
+        });
+      }
+      pending.extend(
+        inner
+          .history
+          .iter()
+          .filter(|x| x.revision >= start)
+          .cloned(),
+      );
+    }
+
+    Ok(Watcher {
+      filter,
+      pending,
+      rx: self.tx.subscribe(),
+    })
+  }
+}
+
+/// Receives the changes made to the keys selected by a [`KeyFilter`].
+#[derive(Debug)]
+pub struct Watcher {
+  filter: KeyFilter,
+  pending: VecDeque<Arc<pb::WatchResponse>>,
+  rx: broadcast::Receiver<Arc<pb::WatchResponse>>,
+}
+
+impl Watcher {
+  /// Waits for the next revision that changed any of the watched keys, and returns the changes
+  /// made to them.
+  pub async fn next(&mut self) -> Result<pb::WatchResponse, WatchError> {
+    loop {
+      let changes = match self.pending.pop_front() {
+        Some(changes) => changes,
+        None => match self.rx.recv().await {
+          Ok(changes) => changes,
+          Err(broadcast::error::RecvError::Lagged(_)) => return Err(WatchError::Lagged),
+          Err(broadcast::error::RecvError::Closed) => return Err(WatchError::Closed),
+        },
+      };
+
+      let events = changes
+        .events
+        .iter()
+        .filter(|x| self.filter.matches(&x.namespace, &x.key))
+        .cloned()
+        .collect::<Vec<_>>();
+
+      if !events.is_empty() {
+        return Ok(pb::WatchResponse {
+          revision: changes.revision,
+          events,
+        });
+      }
+    }
+  }
+}
+
+/// Builds the event for a key of `namespace` written with `kv`.
+pub(crate) fn put_event(namespace: &str, key: &str, kv: &pb::KeyValue) -> pb::Event {
+  pb::Event {
+    r#type: EventType::Put as i32,
+    key: key.to_string(),
+    kv: Some(kv.clone()),
+    namespace: namespace.to_string(),
+  }
+}
+
+/// Builds the event for a key of `namespace` deleted at `revision`.
+pub(crate) fn delete_event(namespace: &str, key: &str, revision: u64) -> pb::Event {
+  pb::Event {
+    r#type: EventType::Delete as i32,
+    key: key.to_string(),
+    kv: Some(pb::KeyValue {
+      mod_revision: revision,
+      ..Default::default()
+    }),
+    namespace: namespace.to_string(),
+  }
+}
+
+/// Computes the events that turn the data of `old` into the data of `new`, `revision` being the
+/// revision at which `new` is reached.
+pub(crate) fn diff(
+  old: &pb::StateMachineData,
+  new: &pb::StateMachineData,
+  revision: u64,
+) -> Vec<pb::Event> {
+  let mut events = Vec::new();
+  for (namespace, ns) in &new.namespaces {
+    for (key, kv) in &ns.data {
+      if namespace::get(old, namespace, key) != Some(kv) {
+        events.push(put_event(namespace, key, kv));
+      }
+    }
+  }
+  for (namespace, ns) in &old.namespaces {
+    for key in ns.data.keys() {
+      if namespace::get(new, namespace, key).is_none() {
+        events.push(delete_event(namespace, key, revision));
+      }
+    }
+  }
+  events
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn kv(value: &str, revision: u64) -> pb::KeyValue {
+    pb::KeyValue {
+      value: value.as_bytes().to_vec(),
+      create_revision: revision,
+      mod_revision: revision,
+      version: 1,
+      lease: 0,
+      content_type: String::new(),
+    }
+  }
+
+  #[tokio::test]
+  async fn test_watch_replays_history_then_streams() {
+    let hub = WatchHub::new(0);
+    let ns = namespace::DEFAULT;
+    hub.publish(1, vec![put_event(ns, "foo", &kv("a", 1))]);
+    hub.publish(2, vec![put_event(ns, "bar", &kv("b", 2))]);
+
+    let mut watcher = hub
+      .watch(KeyFilter::new("", "foo".to_string(), false), Some(1))
+      .unwrap();
+    let mut prefix_watcher = hub
+      .watch(KeyFilter::new(ns, "f".to_string(), true), None)
+      .unwrap();
+
+    // Keys of other namespaces are not watched.
+    hub.publish(3, vec![put_event(namespace::SYSTEM, "foo", &kv("x", 3))]);
+    hub.publish(4, vec![delete_event(ns, "foo", 4)]);
+    hub.publish(5, vec![put_event(ns, "fizz", &kv("c", 5))]);
+
+    assert_eq!(watcher.next().await.unwrap().revision, 1);
+    let changes = watcher.next().await.unwrap();
+    assert_eq!(changes.revision, 4);
+    assert_eq!(changes.events[0].r#type, EventType::Delete as i32);
+
+    assert_eq!(prefix_watcher.next().await.unwrap().revision, 4);
+    assert_eq!(prefix_watcher.next().await.unwrap().events[0].key, "fizz");
+  }
+
+  #[test]
+  fn test_watch_compacted_revision() {
+    let hub = WatchHub::new(5);
+    let filter = KeyFilter::new("", "foo".to_string(), false);
+
+    assert_eq!(
+      hub.watch(filter.clone(), Some(5)).unwrap_err(),
+      WatchError::Compacted { first_revision: 6 }
+    );
+    assert!(hub.watch(filter, Some(6)).is_ok());
+  }
+
+  #[test]
+  fn test_diff() {
+    let data = |kvs: Vec<(&str, pb::KeyValue)>| {
+      let mut sm = pb::StateMachineData::default();
+      let ns = sm
+        .namespaces
+        .entry(namespace::DEFAULT.to_string())
+        .or_default();
+      ns.data = kvs.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+      sm
+    };
+    let old = data(vec![
+      ("same", kv("a", 1)),
+      ("changed", kv("b", 2)),
+      ("removed", kv("c", 3)),
+    ]);
+    let new = data(vec![
+      ("same", kv("a", 1)),
+      ("changed", kv("d", 8)),
+      ("added", kv("e", 9)),
+    ]);
+
+    let events = diff(&old, &new, 10)
+      .into_iter()
+      .map(|x| (x.key, x.r#type, x.kv.unwrap().mod_revision))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      events,
+      vec![
+        ("added".to_string(), EventType::Put as i32, 9),
+        ("changed".to_string(), EventType::Put as i32, 8),
+        ("removed".to_string(), EventType::Delete as i32, 10),
+      ]
+    );
+  }
+}
@@ -2,22 +2,26 @@ use std::sync::Arc;
 
 use openraft::testing::log::StoreBuilder;
 use openraft::testing::log::Suite;
+use tempfile::TempDir;
 
 use crate::store::LogStore;
 use crate::store::StateMachineStore;
 use crate::raft_types::*;
 use crate::TypeConfig;
 
-struct MemKVStoreBuilder {}
+struct DiskKVStoreBuilder {}
 
-impl StoreBuilder<TypeConfig, LogStore, Arc<StateMachineStore>, ()> for MemKVStoreBuilder {
-    async fn build(&self) -> Result<((), LogStore, Arc<StateMachineStore>), StorageError> {
-        Ok(((), LogStore::default(), Arc::default()))
+impl StoreBuilder<TypeConfig, LogStore, Arc<StateMachineStore>, TempDir> for DiskKVStoreBuilder {
+    async fn build(&self) -> Result<(TempDir, LogStore, Arc<StateMachineStore>), StorageError> {
+        let dir = TempDir::new().map_err(|e| StorageError::write(&e))?;
+        let log_store = LogStore::open(dir.path().join("log"))?;
+        let state_machine_store = StateMachineStore::open(dir.path().join("snapshot"))?;
+        Ok((dir, log_store, Arc::new(state_machine_store)))
     }
 }
 
 #[tokio::test]
-pub async fn test_mem_store() -> Result<(), StorageError> {
-    Suite::test_all(MemKVStoreBuilder {}).await?;
+pub async fn test_disk_store() -> Result<(), StorageError> {
+    Suite::test_all(DiskKVStoreBuilder {}).await?;
     Ok(())
 }
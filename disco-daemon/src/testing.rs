@@ -0,0 +1,188 @@
+//! An in-process cluster for the tests that need running Raft nodes.
+//!
+//! Every node serves the Raft and the client API on a local port, so that replication and the
+//! requests forwarded to the leader go through gRPC as they do in a deployed cluster.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use openraft::Config;
+use openraft::ServerState;
+use openraft::SnapshotPolicy;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+
+use crate::grpc::app_service::AppServiceImpl;
+use crate::grpc::limits;
+use crate::grpc::limits::RequestLimits;
+use crate::grpc::raft_service::RaftServiceImpl;
+use crate::lease::LeaseManager;
+use crate::lock::Locks;
+use crate::membership::Drain;
+use crate::network::Network;
+use crate::protobuf;
+use crate::raft_types::*;
+use crate::store::LogStore;
+use crate::store::StateMachineStore;
+use crate::NodeId;
+
+/// How long the tests wait for the cluster to reach the state they expect.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct TestNode {
+  pub id: NodeId,
+  pub addr: String,
+  pub raft: Raft,
+  pub state_machine_store: Arc<StateMachineStore>,
+  pub drain: Arc<Drain>,
+  pub lease_manager: Arc<LeaseManager>,
+  pub locks: Arc<Locks>,
+
+  /// The client API of the node, to call the handlers directly.
+  pub app: AppServiceImpl,
+
+  server: JoinHandle<()>,
+  _dir: TempDir,
+}
+
+impl TestNode {
+  /// Starts node `id`, which is not part of a cluster yet.
+  pub async fn start(id: NodeId) -> TestNode {
+    let dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let log_store = LogStore::open(dir.path().join("log")).unwrap();
+    let state_machine_store =
+      Arc::new(StateMachineStore::open(dir.path().join("snapshot")).unwrap());
+
+    let limits = RequestLimits {
+      max_key_size: 4096,
+      max_value_size: 1024 * 1024,
+      max_entry_size: 3 * 512 * 1024,
+    };
+    let config = Config {
+      cluster_name: "test".to_string(),
+      election_timeout_min: 150,
+      election_timeout_max: 300,
+      heartbeat_interval: 50,
+      snapshot_policy: SnapshotPolicy::Never,
+      max_payload_entries: limits::MAX_PAYLOAD_ENTRIES,
+      ..Default::default()
+    }
+    .validate()
+    .unwrap();
+
+    let raft = Raft::new(
+      id,
+      Arc::new(config),
+      Network::new(id, None, limits.max_message_size()),
+      log_store,
+      state_machine_store.clone(),
+    )
+    .await
+    .unwrap();
+
+    let drain = Arc::new(Drain::new(raft.clone()));
+    let lease_manager = Arc::new(LeaseManager::new(
+      raft.clone(),
+      state_machine_store.clone(),
+      Duration::from_millis(100),
+    ));
+    let locks = Arc::new(Locks::new(raft.clone(), state_machine_store.clone()));
+    let app = || {
+      AppServiceImpl::new(
+        raft.clone(),
+        state_machine_store.clone(),
+        lease_manager.clone(),
+        locks.clone(),
+        limits,
+        None,
+        drain.clone(),
+      )
+    };
+
+    let raft_service = RaftServiceImpl::new(raft.clone(), state_machine_store.clone());
+    let router = Server::builder()
+      .add_service(
+        protobuf::raft_service_server::RaftServiceServer::new(raft_service)
+          .max_decoding_message_size(limits.max_message_size())
+          .max_encoding_message_size(limits.max_message_size()),
+      )
+      .add_service(
+        protobuf::app_service_server::AppServiceServer::new(app())
+          .max_decoding_message_size(limits.max_message_size())
+          .max_encoding_message_size(limits.max_message_size()),
+      );
+    let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+    let server = tokio::spawn(async move {
+      router.serve_with_incoming(incoming).await.unwrap();
+    });
+    let app = app();
+
+    TestNode {
+      id,
+      addr,
+      app,
+      raft,
+      state_machine_store,
+      drain,
+      lease_manager,
+      locks,
+      server,
+      _dir: dir,
+    }
+  }
+
+  pub fn node(&self) -> protobuf::Node {
+    protobuf::Node {
+      node_id: self.id,
+      rpc_addr: self.addr.clone(),
+    }
+  }
+
+  /// Waits until this node knows `leader_id` as the leader.
+  pub async fn wait_for_leader(&self, leader_id: NodeId) {
+    self
+      .raft
+      .wait(Some(TIMEOUT))
+      .current_leader(leader_id, "leader known")
+      .await
+      .unwrap();
+  }
+}
+
+impl Drop for TestNode {
+  fn drop(&mut self) {
+    self.server.abort();
+  }
+}
+
+/// Starts `n` nodes, with ids from 1, and makes them the voters of a cluster led by node 1.
+pub async fn start_cluster(n: u64) -> Vec<TestNode> {
+  let mut nodes = Vec::new();
+  for id in 1..=n {
+    nodes.push(TestNode::start(id).await);
+  }
+
+  let members = nodes
+    .iter()
+    .map(|x| (x.id, x.node()))
+    .collect::<BTreeMap<_, _>>();
+  nodes[0].raft.initialize(members).await.unwrap();
+  nodes[0]
+    .raft
+    .wait(Some(TIMEOUT))
+    .state(ServerState::Leader, "node 1 leads")
+    .await
+    .unwrap();
+
+  for node in &nodes {
+    node.wait_for_leader(1).await;
+  }
+  nodes
+}
@@ -0,0 +1,843 @@
+//! TLS for the traffic between nodes, and between clients and nodes.
+//!
+//! Nodes and clients trust a single CA. The certificate of each node names it `node-<id>`, as a
+//! DNS subject alternative name, which is checked both ways when nodes talk to each other: a node
+//! only sends RPCs to the node it meant to reach, and only accepts Raft RPCs sent on behalf of the
+//! node named by the certificate of the sender. Clients only need a certificate if the nodes
+//! require one.
+//!
+//! The files are read again periodically, so that rotated certificates are used for new
+//! connections without restarting.
+//!
+//! Addresses keep the `http://` scheme: TLS is set up by the connector under the channel.
+
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+use futures::Stream;
+use hyper_util::rt::TokioIo;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::client::verify_server_cert_signed_by_trust_anchor;
+use rustls::client::verify_server_name;
+use rustls::client::ResolvesClientCert;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::server::danger::ClientCertVerified;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::ClientHello;
+use rustls::server::ParsedCertificate;
+use rustls::server::ResolvesServerCert;
+use rustls::server::WebPkiClientVerifier;
+use rustls::sign::CertifiedKey;
+use rustls::DigitallySignedStruct;
+use rustls::DistinguishedName;
+use rustls::RootCertStore;
+use rustls::SignatureScheme;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::ReadBuf;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::TlsConnector;
+use tonic::codegen::Service;
+use tonic::transport::server::Connected;
+use tonic::transport::Channel;
+use tonic::transport::Endpoint;
+use tonic::transport::Uri;
+use tonic::Status;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use crate::settings::TlsSettings;
+use crate::NodeId;
+
+/// How long a peer has to complete the TLS handshake of a new connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the name the certificate of node `id` must have.
+pub fn node_name(id: NodeId) -> ServerName<'static> {
+  ServerName::try_from(format!("node-{}", id)).expect("node names are valid DNS names")
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+  /// A file could not be read.
+  Read(PathBuf, io::Error),
+
+  /// A file does not hold what was expected.
+  Invalid(PathBuf, String),
+}
+
+impl fmt::Display for TlsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TlsError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+      TlsError::Invalid(path, e) => write!(f, "invalid {}: {}", path.display(), e),
+    }
+  }
+}
+
+impl std::error::Error for TlsError {}
+
+fn invalid(path: &Path, e: impl fmt::Display) -> TlsError {
+  TlsError::Invalid(path.to_path_buf(), e.to_string())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+  std::fs::read(path).map_err(|e| TlsError::Read(path.to_path_buf(), e))
+}
+
+fn parse_certs(path: &Path, pem: &[u8]) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+  let certs = rustls_pemfile::certs(&mut &pem[..])
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|e| invalid(path, e))?;
+  if certs.is_empty() {
+    return Err(invalid(path, "no certificate found"));
+  }
+  Ok(certs)
+}
+
+/// The TLS files of a node or a client, as last loaded.
+#[derive(Debug)]
+pub struct TlsConfig {
+  /// The CA certificates that issue the certificates of the nodes and clients.
+  ca: PathBuf,
+
+  /// The certificate and key presented to peers. Only clients may have none.
+  identity: Option<(PathBuf, PathBuf)>,
+
+  /// Whether clients must present a certificate.
+  require_client_cert: bool,
+
+  provider: Arc<CryptoProvider>,
+  loaded: RwLock<Arc<Loaded>>,
+}
+
+#[derive(Debug)]
+struct Loaded {
+  /// The contents of the files, to tell whether they changed.
+  files: Vec<Vec<u8>>,
+
+  roots: Arc<RootCertStore>,
+  certified_key: Option<Arc<CertifiedKey>>,
+  client_verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl TlsConfig {
+  /// Loads the TLS files of a node.
+  pub fn node(settings: &TlsSettings) -> Result<Arc<Self>, TlsError> {
+    Self::new(
+      settings.ca.clone(),
+      Some((settings.cert.clone(), settings.key.clone())),
+      settings.require_client_cert,
+    )
+  }
+
+  /// Loads the TLS files of a client: the CA the certificates of the nodes are checked against,
+  /// and the certificate and key of the client if the nodes require one.
+  pub fn client(ca: PathBuf, identity: Option<(PathBuf, PathBuf)>) -> Result<Arc<Self>, TlsError> {
+    Self::new(ca, identity, false)
+  }
+
+  fn new(
+    ca: PathBuf,
+    identity: Option<(PathBuf, PathBuf)>,
+    require_client_cert: bool,
+  ) -> Result<Arc<Self>, TlsError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let loaded = load(&ca, identity.as_ref(), require_client_cert, &provider)?;
+    Ok(Arc::new(Self {
+      ca,
+      identity,
+      require_client_cert,
+      provider,
+      loaded: RwLock::new(Arc::new(loaded)),
+    }))
+  }
+
+  fn current(&self) -> Arc<Loaded> {
+    self.loaded.read().unwrap().clone()
+  }
+
+  /// Reads the files again, and uses them for new connections if they changed.
+  ///
+  /// Returns whether they changed. If they cannot be loaded, e.g. because a new certificate is
+  /// written but not its key yet, the current ones are kept.
+  pub fn reload(&self) -> Result<bool, TlsError> {
+    let loaded = load(
+      &self.ca,
+      self.identity.as_ref(),
+      self.require_client_cert,
+      &self.provider,
+    )?;
+    if loaded.files == self.current().files {
+      return Ok(false);
+    }
+    *self.loaded.write().unwrap() = Arc::new(loaded);
+    Ok(true)
+  }
+
+  /// Reloads the files every `interval`, for as long as the returned future runs. An interval of
+  /// zero disables the reloads, and the future returns at once.
+  pub async fn watch(self: Arc<Self>, interval: Duration) {
+    if interval.is_zero() {
+      return;
+    }
+
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, right after the files were loaded.
+    interval.tick().await;
+
+    loop {
+      interval.tick().await;
+      match self.reload() {
+        Ok(true) => info!("Reloaded TLS certificates"),
+        Ok(false) => {}
+        Err(e) => warn!(
+          "Failed to reload TLS certificates, keeping the current ones: {}",
+          e
+        ),
+      }
+    }
+  }
+
+  fn server_config(self: &Arc<Self>) -> rustls::ServerConfig {
+    let mut config = rustls::ServerConfig::builder_with_provider(self.provider.clone())
+      .with_safe_default_protocol_versions()
+      .expect("the default provider supports the default protocol versions")
+      .with_client_cert_verifier(Arc::new(Reloading(self.clone())))
+      .with_cert_resolver(Arc::new(Reloading(self.clone())));
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    config
+  }
+
+  /// Returns the configuration of connections to node `node_id`, or to any node if `None`.
+  fn client_config(self: &Arc<Self>, node_id: Option<NodeId>) -> rustls::ClientConfig {
+    let verifier = NodeVerifier {
+      tls: self.clone(),
+      node_id,
+    };
+    let mut config = rustls::ClientConfig::builder_with_provider(self.provider.clone())
+      .with_safe_default_protocol_versions()
+      .expect("the default provider supports the default protocol versions")
+      .dangerous()
+      .with_custom_certificate_verifier(Arc::new(verifier))
+      .with_client_cert_resolver(Arc::new(Reloading(self.clone())));
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    config
+  }
+}
+
+fn load(
+  ca: &Path,
+  identity: Option<&(PathBuf, PathBuf)>,
+  require_client_cert: bool,
+  provider: &Arc<CryptoProvider>,
+) -> Result<Loaded, TlsError> {
+  let ca_pem = read(ca)?;
+  let mut roots = RootCertStore::empty();
+  for cert in parse_certs(ca, &ca_pem)? {
+    roots.add(cert).map_err(|e| invalid(ca, e))?;
+  }
+  let roots = Arc::new(roots);
+  let mut files = vec![ca_pem];
+
+  let certified_key = match identity {
+    None => None,
+    Some((cert, key)) => {
+      let cert_pem = read(cert)?;
+      let key_pem = read(key)?;
+      let certs = parse_certs(cert, &cert_pem)?;
+      let private_key = rustls_pemfile::private_key(&mut &key_pem[..])
+        .map_err(|e| invalid(key, e))?
+        .ok_or_else(|| invalid(key, "no private key found"))?;
+      let signing_key = provider
+        .key_provider
+        .load_private_key(private_key)
+        .map_err(|e| invalid(key, e))?;
+      files.push(cert_pem);
+      files.push(key_pem);
+      Some(Arc::new(CertifiedKey::new(certs, signing_key)))
+    }
+  };
+
+  let mut client_verifier =
+    WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone());
+  if !require_client_cert {
+    client_verifier = client_verifier.allow_unauthenticated();
+  }
+  let client_verifier = client_verifier.build().map_err(|e| invalid(ca, e))?;
+
+  Ok(Loaded {
+    files,
+    roots,
+    certified_key,
+    client_verifier,
+  })
+}
+
+/// Hands the certificates last loaded to each new connection.
+#[derive(Debug)]
+struct Reloading(Arc<TlsConfig>);
+
+impl ResolvesServerCert for Reloading {
+  fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    self.0.current().certified_key.clone()
+  }
+}
+
+impl ResolvesClientCert for Reloading {
+  fn resolve(
+    &self,
+    _root_hint_subjects: &[&[u8]],
+    _sigschemes: &[SignatureScheme],
+  ) -> Option<Arc<CertifiedKey>> {
+    self.0.current().certified_key.clone()
+  }
+
+  fn has_certs(&self) -> bool {
+    self.0.identity.is_some()
+  }
+}
+
+impl ClientCertVerifier for Reloading {
+  fn offer_client_auth(&self) -> bool {
+    self.0.current().client_verifier.offer_client_auth()
+  }
+
+  fn client_auth_mandatory(&self) -> bool {
+    self.0.current().client_verifier.client_auth_mandatory()
+  }
+
+  fn root_hint_subjects(&self) -> &[DistinguishedName] {
+    // Hints are optional, and could not outlive a reload.
+    &[]
+  }
+
+  fn verify_client_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    now: UnixTime,
+  ) -> Result<ClientCertVerified, rustls::Error> {
+    self
+      .0
+      .current()
+      .client_verifier
+      .verify_client_cert(end_entity, intermediates, now)
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls12_signature(
+      message,
+      cert,
+      dss,
+      &self.0.provider.signature_verification_algorithms,
+    )
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls13_signature(
+      message,
+      cert,
+      dss,
+      &self.0.provider.signature_verification_algorithms,
+    )
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self
+      .0
+      .provider
+      .signature_verification_algorithms
+      .supported_schemes()
+  }
+}
+
+/// Checks that the certificate of a node is issued by the CA, and names the node that was meant
+/// to be reached rather than the address it was reached at.
+#[derive(Debug)]
+struct NodeVerifier {
+  tls: Arc<TlsConfig>,
+
+  /// The node to reach, or `None` to accept any node, e.g. when reaching a seed address.
+  node_id: Option<NodeId>,
+}
+
+impl ServerCertVerifier for NodeVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+    _server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    now: UnixTime,
+  ) -> Result<ServerCertVerified, rustls::Error> {
+    let cert = ParsedCertificate::try_from(end_entity)?;
+    verify_server_cert_signed_by_trust_anchor(
+      &cert,
+      &self.tls.current().roots,
+      intermediates,
+      now,
+      self.tls.provider.signature_verification_algorithms.all,
+    )?;
+    if let Some(node_id) = self.node_id {
+      verify_server_name(&cert, &node_name(node_id))?;
+    }
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls12_signature(
+      message,
+      cert,
+      dss,
+      &self.tls.provider.signature_verification_algorithms,
+    )
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> Result<HandshakeSignatureValid, rustls::Error> {
+    rustls::crypto::verify_tls13_signature(
+      message,
+      cert,
+      dss,
+      &self.tls.provider.signature_verification_algorithms,
+    )
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self
+      .tls
+      .provider
+      .signature_verification_algorithms
+      .supported_schemes()
+  }
+}
+
+/// Connects channels over TLS.
+#[derive(Clone)]
+struct Connector {
+  connector: TlsConnector,
+  node_id: Option<NodeId>,
+}
+
+impl Service<Uri> for Connector {
+  type Response = TokioIo<tokio_rustls::client::TlsStream<TcpStream>>;
+  type Error = io::Error;
+  type Future = Pin<Box<dyn Future<Output = Result<Self::Response, io::Error>> + Send>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, uri: Uri) -> Self::Future {
+    let connector = self.connector.clone();
+    let node_id = self.node_id;
+    Box::pin(async move {
+      let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing host"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+      let port = uri.port_u16().unwrap_or(80);
+
+      // The name is only sent to the node, the certificate is checked by `NodeVerifier`.
+      let name = match node_id {
+        Some(node_id) => node_name(node_id),
+        None => ServerName::try_from(host.clone())
+          .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+      };
+
+      let tcp = TcpStream::connect((host.as_str(), port)).await?;
+      tcp.set_nodelay(true)?;
+      let stream = connector.connect(name, tcp).await?;
+      Ok(TokioIo::new(stream))
+    })
+  }
+}
+
+/// Connects a channel to `endpoint`, over TLS if `tls` is set. The node reached must then be
+/// `node_id`, or any node if `None`.
+pub async fn connect(
+  endpoint: Endpoint,
+  tls: Option<&Arc<TlsConfig>>,
+  node_id: Option<NodeId>,
+) -> Result<Channel, tonic::transport::Error> {
+  match tls {
+    None => endpoint.connect().await,
+    Some(tls) => {
+      let connector = Connector {
+        connector: TlsConnector::from(Arc::new(tls.client_config(node_id))),
+        node_id,
+      };
+      endpoint.connect_with_connector(connector).await
+    }
+  }
+}
+
+/// Like [`connect`], but only connects when the channel is first used.
+pub fn connect_lazy(
+  endpoint: Endpoint,
+  tls: Option<&Arc<TlsConfig>>,
+  node_id: Option<NodeId>,
+) -> Channel {
+  match tls {
+    None => endpoint.connect_lazy(),
+    Some(tls) => {
+      let connector = Connector {
+        connector: TlsConnector::from(Arc::new(tls.client_config(node_id))),
+        node_id,
+      };
+      endpoint.connect_with_connector_lazy(connector)
+    }
+  }
+}
+
+/// What is known of the peer of a TLS connection. It is added to the extensions of each request
+/// received over the connection.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+  pub remote_addr: Option<SocketAddr>,
+
+  /// The certificates presented by the peer, its own first, if it presented any. They were
+  /// checked against the CA during the handshake.
+  pub certs: Option<Arc<Vec<CertificateDer<'static>>>>,
+}
+
+/// A connection accepted by a node, over TLS.
+pub struct TlsConnection(tokio_rustls::server::TlsStream<TcpStream>);
+
+impl Connected for TlsConnection {
+  type ConnectInfo = PeerInfo;
+
+  fn connect_info(&self) -> PeerInfo {
+    let (tcp, session) = self.0.get_ref();
+    PeerInfo {
+      remote_addr: tcp.peer_addr().ok(),
+      certs: session.peer_certificates().map(|x| Arc::new(x.to_vec())),
+    }
+  }
+}
+
+impl AsyncRead for TlsConnection {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_read(cx, buf)
+  }
+}
+
+impl AsyncWrite for TlsConnection {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.0).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.0).poll_shutdown(cx)
+  }
+}
+
+/// Accepts connections on `listener`, and yields them once their TLS handshake is done.
+///
+/// Handshakes run concurrently, so that a slow peer does not hold back the others, and failed
+/// ones are only logged.
+pub fn incoming(
+  listener: TcpListener,
+  tls: &Arc<TlsConfig>,
+) -> impl Stream<Item = Result<TlsConnection, io::Error>> {
+  let acceptor = TlsAcceptor::from(Arc::new(tls.server_config()));
+  let (tx, mut rx) = mpsc::channel(16);
+
+  tokio::spawn(async move {
+    while !tx.is_closed() {
+      let (tcp, addr) = match listener.accept().await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+          // e.g. too many open files: wait for connections to close.
+          warn!("Failed to accept connection: {}", e);
+          tokio::time::sleep(Duration::from_millis(100)).await;
+          continue;
+        }
+      };
+      if let Err(e) = tcp.set_nodelay(true) {
+        debug!("Failed to set TCP_NODELAY for {}: {}", addr, e);
+      }
+
+      let acceptor = acceptor.clone();
+      let tx = tx.clone();
+      tokio::spawn(async move {
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+          Ok(Ok(stream)) => {
+            let _ = tx.send(Ok(TlsConnection(stream))).await;
+          }
+          Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+          Err(_) => debug!("TLS handshake with {} timed out", addr),
+        }
+      });
+    }
+  });
+
+  futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+/// Checks that the peer of a request is node `node_id`, by the certificate it presented.
+///
+/// Requests received without TLS, which have no [`PeerInfo`], are not checked.
+pub fn check_peer(peer: Option<&PeerInfo>, node_id: NodeId) -> Result<(), Status> {
+  let Some(peer) = peer else {
+    return Ok(());
+  };
+
+  let cert = peer
+    .certs
+    .as_ref()
+    .and_then(|x| x.first())
+    .ok_or_else(|| Status::unauthenticated("A node certificate is required"))?;
+  let cert = ParsedCertificate::try_from(cert)
+    .map_err(|e| Status::unauthenticated(format!("Invalid certificate: {}", e)))?;
+  verify_server_name(&cert, &node_name(node_id)).map_err(|_| {
+    Status::permission_denied(format!(
+      "The certificate of the peer does not name node {}",
+      node_id
+    ))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use rcgen::BasicConstraints;
+  use rcgen::CertificateParams;
+  use rcgen::IsCa;
+  use rcgen::KeyPair;
+  use tempfile::TempDir;
+  use tokio::io::DuplexStream;
+
+  use super::*;
+
+  /// A CA, which issues the certificates of the tests into a temporary directory.
+  struct Ca {
+    dir: TempDir,
+    cert: rcgen::Certificate,
+    key: KeyPair,
+  }
+
+  impl Ca {
+    fn new() -> Ca {
+      let key = KeyPair::generate().unwrap();
+      let mut params = CertificateParams::new(Vec::new()).unwrap();
+      params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+      let cert = params.self_signed(&key).unwrap();
+
+      let dir = TempDir::new().unwrap();
+      std::fs::write(dir.path().join("ca.pem"), cert.pem()).unwrap();
+      Ca { dir, cert, key }
+    }
+
+    /// Returns the settings of a node whose files are named after `name`, and issues it a
+    /// certificate naming it `name`.
+    fn node(&self, name: &str) -> (TlsSettings, CertificateDer<'static>) {
+      let settings = TlsSettings {
+        ca: self.dir.path().join("ca.pem"),
+        cert: self.dir.path().join(format!("{}.pem", name)),
+        key: self.dir.path().join(format!("{}-key.pem", name)),
+        require_client_cert: true,
+        reload_interval: 60,
+      };
+      let cert = self.issue(&settings, name);
+      (settings, cert)
+    }
+
+    /// Issues a certificate naming `name`, and writes it and its key to the files of `settings`.
+    fn issue(&self, settings: &TlsSettings, name: &str) -> CertificateDer<'static> {
+      let key = KeyPair::generate().unwrap();
+      let cert = CertificateParams::new(vec![name.to_string()])
+        .unwrap()
+        .signed_by(&key, &self.cert, &self.key)
+        .unwrap();
+      std::fs::write(&settings.cert, cert.pem()).unwrap();
+      std::fs::write(&settings.key, key.serialize_pem()).unwrap();
+      cert.der().clone()
+    }
+  }
+
+  /// Connects `client` to `server`, which must be node `node_id`, and returns the outcome of the
+  /// handshake on each side.
+  async fn handshake(
+    client: &Arc<TlsConfig>,
+    server: &Arc<TlsConfig>,
+    node_id: NodeId,
+  ) -> (
+    io::Result<tokio_rustls::client::TlsStream<DuplexStream>>,
+    io::Result<tokio_rustls::server::TlsStream<DuplexStream>>,
+  ) {
+    let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+    let connector = TlsConnector::from(Arc::new(client.client_config(Some(node_id))));
+    let acceptor = TlsAcceptor::from(Arc::new(server.server_config()));
+    tokio::join!(
+      connector.connect(node_name(node_id), client_io),
+      acceptor.accept(server_io)
+    )
+  }
+
+  /// Returns the certificate `tls` presents to its peers.
+  fn presented(tls: &TlsConfig) -> CertificateDer<'static> {
+    tls.current().certified_key.as_ref().unwrap().cert[0].clone()
+  }
+
+  #[tokio::test]
+  async fn test_mutual_tls() {
+    let ca = Ca::new();
+    let (settings1, cert1) = ca.node("node-1");
+    let (settings2, cert2) = ca.node("node-2");
+    let node1 = TlsConfig::node(&settings1).unwrap();
+    let node2 = TlsConfig::node(&settings2).unwrap();
+
+    let (connected, accepted) = handshake(&node2, &node1, 1).await;
+    let (_, session) = connected.unwrap().into_inner();
+    assert_eq!(session.peer_certificates().unwrap()[0], cert1);
+
+    let (_, session) = accepted.unwrap().into_inner();
+    let peer = PeerInfo {
+      remote_addr: None,
+      certs: session.peer_certificates().map(|x| Arc::new(x.to_vec())),
+    };
+    assert_eq!(peer.certs.as_ref().unwrap()[0], cert2);
+    assert!(check_peer(Some(&peer), 2).is_ok());
+    let status = check_peer(Some(&peer), 1).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+  }
+
+  #[tokio::test]
+  async fn test_node_name_mismatch() {
+    let ca = Ca::new();
+    let (settings1, _) = ca.node("node-1");
+    let (settings2, _) = ca.node("node-2");
+    let node1 = TlsConfig::node(&settings1).unwrap();
+    let node2 = TlsConfig::node(&settings2).unwrap();
+
+    // Node 1 is reached where node 3 was expected
+    let (connected, _) = handshake(&node2, &node1, 3).await;
+    assert!(connected.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_client_certificate_required() {
+    let ca = Ca::new();
+    let (settings, _) = ca.node("node-1");
+    let node = TlsConfig::node(&settings).unwrap();
+    let client = TlsConfig::client(settings.ca.clone(), None).unwrap();
+
+    let (_, accepted) = handshake(&client, &node, 1).await;
+    assert!(accepted.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_reload() {
+    let ca = Ca::new();
+    let (settings1, _) = ca.node("node-1");
+    let (settings2, _) = ca.node("node-2");
+    let node1 = TlsConfig::node(&settings1).unwrap();
+    let node2 = TlsConfig::node(&settings2).unwrap();
+    assert!(!node1.reload().unwrap());
+
+    let rotated = ca.issue(&settings1, "node-1");
+    assert!(node1.reload().unwrap());
+    let (connected, _) = handshake(&node2, &node1, 1).await;
+    let (_, session) = connected.unwrap().into_inner();
+    assert_eq!(session.peer_certificates().unwrap()[0], rotated);
+
+    // A certificate whose key is not written yet is not picked up
+    ca.issue(&settings1, "node-1");
+    std::fs::write(&settings1.key, "").unwrap();
+    assert!(node1.reload().is_err());
+    let (connected, _) = handshake(&node2, &node1, 1).await;
+    let (_, session) = connected.unwrap().into_inner();
+    assert_eq!(session.peer_certificates().unwrap()[0], rotated);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_watch() {
+    let ca = Ca::new();
+    let (settings, _) = ca.node("node-1");
+    let node = TlsConfig::node(&settings).unwrap();
+
+    let watch = tokio::spawn(node.clone().watch(Duration::from_secs(60)));
+    let rotated = ca.issue(&settings, "node-1");
+    tokio::time::sleep(Duration::from_secs(61)).await;
+    assert_eq!(presented(&node), rotated);
+    watch.abort();
+
+    // An interval of 0 disables the reloads
+    ca.issue(&settings, "node-1");
+    tokio::time::timeout(Duration::from_secs(1), node.clone().watch(Duration::ZERO))
+      .await
+      .unwrap();
+    assert_eq!(presented(&node), rotated);
+  }
+
+  #[test]
+  fn test_check_peer_without_certificate() {
+    assert!(check_peer(None, 1).is_ok());
+
+    let peer = PeerInfo {
+      remote_addr: None,
+      certs: None,
+    };
+    let status = check_peer(Some(&peer), 1).unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+  }
+
+  #[test]
+  fn test_missing_files() {
+    let err = TlsConfig::client(PathBuf::from("/nonexistent/ca.pem"), None).unwrap_err();
+    assert!(matches!(err, TlsError::Read(..)));
+  }
+}
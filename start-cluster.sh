@@ -76,7 +76,7 @@ echo "Starting $NODE_COUNT uninitialized discod servers..."
 i=1
 while [ $i -le $NODE_COUNT ]; do
     port=$((BASE_PORT + i - 1))
-    $EXECUTABLE --id $i --addr $BASE_HOST:$port > n$i.log 2>&1 &
+    $EXECUTABLE --id $i --addr $BASE_HOST:$port --data-dir data/n$i > n$i.log 2>&1 &
     echo "Server $i started at http://$BASE_HOST:$port"
     i=$((i + 1))
 done
@@ -54,25 +54,25 @@ sleep 1
 
 echo "Start 5 uninitialized raft-key-value servers..."
 
-nohup ./target/debug/raft-key-value --id 1 --addr 127.0.0.1:5051 > n1.log &
+nohup ./target/debug/raft-key-value --id 1 --addr 127.0.0.1:5051 --data-dir data/n1 > n1.log &
 sleep 1
 echo "Server 1 started"
 
-nohup ./target/debug/raft-key-value --id 2 --addr 127.0.0.1:5052 > n2.log &
+nohup ./target/debug/raft-key-value --id 2 --addr 127.0.0.1:5052 --data-dir data/n2 > n2.log &
 sleep 1
 echo "Server 2 started"
 
-nohup ./target/debug/raft-key-value --id 3 --addr 127.0.0.1:5053 > n3.log &
+nohup ./target/debug/raft-key-value --id 3 --addr 127.0.0.1:5053 --data-dir data/n3 > n3.log &
 sleep 1
 echo "Server 3 started"
 sleep 1
 
-nohup ./target/debug/raft-key-value --id 4 --addr 127.0.0.1:5054 > n4.log &
+nohup ./target/debug/raft-key-value --id 4 --addr 127.0.0.1:5054 --data-dir data/n4 > n4.log &
 sleep 1
 echo "Server 4 started"
 sleep 1
 
-nohup ./target/debug/raft-key-value --id 5 --addr 127.0.0.1:5055 > n5.log &
+nohup ./target/debug/raft-key-value --id 5 --addr 127.0.0.1:5055 --data-dir data/n5 > n5.log &
 sleep 1
 echo "Server 5 started"
 sleep 1